@@ -0,0 +1,148 @@
+//! Where the plugin gets the current time from
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! All epochs this library hands out come from [Config::clock](crate::Config::clock),
+//! by default the system clock. Tests can set their own [Clock], to
+//! get deterministic epochs.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A source of the current time
+pub trait Clock: Send + Sync {
+    /// Current unix epoch, in seconds
+    fn now_epoch(&self) -> u64;
+
+    /// Current time since the unix epoch, for when the part below a
+    /// second matters. Defaults to [Clock::now_epoch] in whole
+    /// seconds.
+    fn now(&self) -> Duration {
+        Duration::from_secs(self.now_epoch())
+    }
+}
+
+/// The system clock, [SystemTime::now]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_epoch(&self) -> u64 {
+        self.now().as_secs()
+    }
+
+    fn now(&self) -> Duration {
+        // A clock before 1970 is broken enough, to just say 1970
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A [Clock] shareable between configs, as stored in
+/// [Config::clock](crate::Config::clock). Defaults to [SystemClock].
+///
+/// Two SharedClocks are equal if both are the system clock, or share
+/// the very same clock.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{clock::{Clock, SharedClock}, Config};
+/// struct FixedClock;
+/// impl Clock for FixedClock {
+///     fn now_epoch(&self) -> u64 {
+///         1650000000
+///     }
+/// }
+///
+/// let mut config = Config::new(String::from("fixed"));
+/// config.clock = SharedClock::new(FixedClock);
+/// assert_eq!(config.clock.now_epoch(), 1650000000);
+/// ```
+#[derive(Clone, Default)]
+pub struct SharedClock(Option<Arc<dyn Clock>>);
+
+impl SharedClock {
+    /// Share `clock`
+    pub fn new<C: Clock + 'static>(clock: C) -> Self {
+        Self(Some(Arc::new(clock)))
+    }
+
+    /// Current unix epoch, in seconds, see [Clock::now_epoch]
+    pub fn now_epoch(&self) -> u64 {
+        match &self.0 {
+            Some(clock) => clock.now_epoch(),
+            None => SystemClock.now_epoch(),
+        }
+    }
+
+    /// Current time since the unix epoch, see [Clock::now]
+    pub fn now(&self) -> Duration {
+        match &self.0 {
+            Some(clock) => clock.now(),
+            None => SystemClock.now(),
+        }
+    }
+
+    /// Address of the shared clock, to compare and hash by
+    fn address(&self) -> Option<*const ()> {
+        self.0.as_ref().map(|clock| Arc::as_ptr(clock) as *const ())
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.address() {
+            Some(address) => write!(f, "SharedClock({address:p})"),
+            None => write!(f, "SharedClock(SystemClock)"),
+        }
+    }
+}
+
+impl PartialEq for SharedClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+    }
+}
+
+impl Eq for SharedClock {}
+
+impl Hash for SharedClock {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedClock(u64);
+    impl Clock for FixedClock {
+        fn now_epoch(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_shared_clock() {
+        let system = SharedClock::default();
+        assert_eq!(system, SharedClock::default());
+        assert!(system.now_epoch() > 1650000000);
+
+        let fixed = SharedClock::new(FixedClock(42));
+        assert_eq!(fixed.now_epoch(), 42);
+        assert_eq!(fixed.now(), Duration::from_secs(42));
+        assert_eq!(fixed.clone(), fixed);
+        assert_ne!(fixed, SharedClock::new(FixedClock(42)));
+        assert_ne!(fixed, system);
+    }
+}
@@ -0,0 +1,211 @@
+//! The commands a munin plugin gets called with
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! Munin (and the helper arguments this library adds) tells the
+//! plugin what to do with its first argument. [parse_command] turns
+//! the commandline into a [MuninCommand], used by
+//! [MuninPlugin::start](super::MuninPlugin::start) and for everyone
+//! wanting to do their own dispatch.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use std::{ffi::OsString, path::PathBuf};
+
+/// What the plugin got asked to do
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum MuninCommand {
+    /// No argument, hand out data
+    Fetch,
+    /// `config`, print the graph config
+    Config,
+    /// `configfetch`, print config followed by data
+    ConfigFetch,
+    /// `autoconf`, tell if the plugin can configure itself
+    Autoconf,
+    /// `suggest`, list instances of a wildcard plugin
+    Suggest,
+    /// `capabilities`, list the munin capabilities the plugin supports
+    Capabilities,
+    /// `acquire`, start the daemon gathering data
+    Acquire,
+    /// `acquire-once`, gather data a single time to stdout
+    AcquireOnce,
+    /// `healthcheck`, check the acquire daemon
+    Healthcheck,
+    /// `verify`, check the directories and files the plugin uses
+    Verify,
+    /// `test`, print config and data, for a developer to look at
+    Test,
+    /// `replay CACHEFILE`, output a recorded cachefile
+    Replay(PathBuf),
+    /// Anything else, the arguments joined by a space
+    Unknown(String),
+}
+
+/// The answer to munins `autoconf`, see
+/// [MuninPlugin::autoconf_result](super::MuninPlugin::autoconf_result).
+///
+/// A plugin that can not configure itself should tell why, munin
+/// then shows `no (REASON)` to the admin.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::AutoconfResult;
+/// let result = AutoconfResult::no("network interface eth0 not found");
+/// assert!(!result.ok);
+/// assert_eq!(AutoconfResult::from(true), AutoconfResult::yes());
+/// ```
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct AutoconfResult {
+    /// Can the plugin configure itself?
+    pub ok: bool,
+    /// Why not, if it can not
+    pub reason: Option<String>,
+}
+
+impl AutoconfResult {
+    /// The plugin can configure itself
+    pub fn yes() -> Self {
+        Self {
+            ok: true,
+            reason: None,
+        }
+    }
+
+    /// The plugin can not configure itself, because of `reason`
+    pub fn no<S: Into<String>>(reason: S) -> Self {
+        Self {
+            ok: false,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+impl From<bool> for AutoconfResult {
+    fn from(ok: bool) -> Self {
+        Self { ok, reason: None }
+    }
+}
+
+/// Parse the commandline `args`, as from [std::env::args], including
+/// the program name in the first element.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::command::{parse_command, MuninCommand};
+/// let args = vec![String::from("load"), String::from("config")];
+/// assert_eq!(parse_command(&args), MuninCommand::Config);
+/// ```
+pub fn parse_command(args: &[String]) -> MuninCommand {
+    let args: Vec<&str> = args.iter().skip(1).map(String::as_str).collect();
+    match args.as_slice() {
+        [] => MuninCommand::Fetch,
+        ["config"] => MuninCommand::Config,
+        ["configfetch"] => MuninCommand::ConfigFetch,
+        ["autoconf"] => MuninCommand::Autoconf,
+        ["suggest"] => MuninCommand::Suggest,
+        ["capabilities"] => MuninCommand::Capabilities,
+        ["acquire"] => MuninCommand::Acquire,
+        ["acquire-once"] => MuninCommand::AcquireOnce,
+        ["healthcheck"] => MuninCommand::Healthcheck,
+        ["verify"] => MuninCommand::Verify,
+        ["test"] => MuninCommand::Test,
+        ["replay", cachefile] => MuninCommand::Replay(PathBuf::from(cachefile)),
+        other => MuninCommand::Unknown(other.join(" ")),
+    }
+}
+
+/// The note printed to stderr when the plugin gets run by hand, see
+/// [outside_munin].
+pub(crate) const USAGE_NOTE: &str = "\
+This is a munin plugin, usually run by munin-node. Going to hand out data now.
+Arguments it also understands: config, configfetch, autoconf, suggest,
+capabilities, acquire, acquire-once, healthcheck, verify, test, replay CACHEFILE.
+Use munin-run to run it the way munin does.";
+
+/// Does it look like a human ran the plugin, not munin? Munin always
+/// sets MUNIN_PLUGSTATE and the MUNIN_CAP_* capabilities, a human
+/// has stdout on a terminal (`tty`). Checks the environment in
+/// `vars`.
+pub(crate) fn outside_munin<I: IntoIterator<Item = (OsString, OsString)>>(
+    vars: I,
+    tty: bool,
+) -> bool {
+    tty && !vars.into_iter().any(|(name, _)| {
+        name == "MUNIN_PLUGSTATE" || name.to_string_lossy().starts_with("MUNIN_CAP_")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> MuninCommand {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_command(&args)
+    }
+
+    #[test]
+    fn test_outside_munin() {
+        let var = |name: &str| (OsString::from(name), OsString::from("1"));
+        let vars = || vec![var("HOME"), var("PATH")];
+        assert!(outside_munin(vars(), true));
+        // Output got redirected, or munin runs us
+        assert!(!outside_munin(vars(), false));
+        assert!(!outside_munin(vec![var("MUNIN_CAP_DIRTYCONFIG")], true));
+        assert!(!outside_munin(vec![var("MUNIN_PLUGSTATE")], true));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse(&["plugin"]), MuninCommand::Fetch);
+        assert_eq!(parse(&["plugin", "config"]), MuninCommand::Config);
+        assert_eq!(parse(&["plugin", "configfetch"]), MuninCommand::ConfigFetch);
+        assert_eq!(parse(&["plugin", "autoconf"]), MuninCommand::Autoconf);
+        assert_eq!(parse(&["plugin", "suggest"]), MuninCommand::Suggest);
+        assert_eq!(
+            parse(&["plugin", "capabilities"]),
+            MuninCommand::Capabilities
+        );
+        assert_eq!(parse(&["plugin", "acquire"]), MuninCommand::Acquire);
+        assert_eq!(
+            parse(&["plugin", "acquire-once"]),
+            MuninCommand::AcquireOnce
+        );
+        assert_eq!(parse(&["plugin", "healthcheck"]), MuninCommand::Healthcheck);
+        assert_eq!(parse(&["plugin", "verify"]), MuninCommand::Verify);
+        assert_eq!(parse(&["plugin", "test"]), MuninCommand::Test);
+        assert_eq!(
+            parse(&["plugin", "replay", "/tmp/cache"]),
+            MuninCommand::Replay(PathBuf::from("/tmp/cache"))
+        );
+    }
+
+    #[test]
+    fn test_parse_command_unknown() {
+        assert_eq!(
+            parse(&["plugin", "lala"]),
+            MuninCommand::Unknown(String::from("lala"))
+        );
+        // Arguments are case-sensitive
+        assert_eq!(
+            parse(&["plugin", "CONFIG"]),
+            MuninCommand::Unknown(String::from("CONFIG"))
+        );
+        // Replay needs exactly one file
+        assert_eq!(
+            parse(&["plugin", "replay"]),
+            MuninCommand::Unknown(String::from("replay"))
+        );
+        assert_eq!(
+            parse(&["plugin", "config", "extra"]),
+            MuninCommand::Unknown(String::from("config extra"))
+        );
+        // No program name at all is like no argument
+        assert_eq!(parse_command(&[]), MuninCommand::Fetch);
+    }
+}
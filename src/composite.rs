@@ -0,0 +1,189 @@
+//! Combine several plugins into one
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! A [CompositePlugin] is one munin (multigraph) plugin made of
+//! several small ones, each becoming a graph of its own. As
+//! [MuninPlugin] has generic functions, it can not be boxed itself,
+//! the parts are held as [DynPlugin], which every MuninPlugin is.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use crate::{Config, MuninPlugin};
+use anyhow::Result;
+use log::warn;
+use std::io::{BufWriter, Write};
+
+/// The parts of [MuninPlugin] a [CompositePlugin] needs, usable as
+/// trait object. Implemented for every [MuninPlugin].
+pub trait DynPlugin {
+    /// [MuninPlugin::config], writing to `handle`
+    fn dyn_config(&self, handle: &mut dyn Write) -> Result<()>;
+
+    /// [MuninPlugin::acquire], writing to `handle`
+    fn dyn_acquire(&mut self, handle: &mut dyn Write, config: &Config, epoch: u64) -> Result<()>;
+
+    /// [MuninPlugin::on_shutdown]
+    fn dyn_on_shutdown(&self, config: &Config) -> Result<()>;
+}
+
+impl<P: MuninPlugin> DynPlugin for P {
+    fn dyn_config(&self, handle: &mut dyn Write) -> Result<()> {
+        let mut handle = BufWriter::new(handle);
+        self.config(&mut handle)?;
+        handle.flush()?;
+        Ok(())
+    }
+
+    fn dyn_acquire(&mut self, handle: &mut dyn Write, config: &Config, epoch: u64) -> Result<()> {
+        let mut handle = BufWriter::new(handle);
+        self.acquire(&mut handle, config, epoch)?;
+        handle.flush()?;
+        Ok(())
+    }
+
+    fn dyn_on_shutdown(&self, config: &Config) -> Result<()> {
+        self.on_shutdown(config)
+    }
+}
+
+/// A multigraph plugin made of other plugins, one graph each.
+///
+/// [MuninPlugin::config] and [MuninPlugin::acquire] of the parts get
+/// called in the order they got added, each output preceded by a
+/// `multigraph NAME` line with the name given to
+/// [CompositePlugin::plugin]. Munin needs to support multigraph for
+/// this, which it tells with MUNIN_CAP_MULTIGRAPH.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{CompositePlugin, Config, MuninPlugin};
+/// # use anyhow::Result;
+/// # use std::io::{BufWriter, Write};
+/// struct Uptime;
+/// impl MuninPlugin for Uptime {
+///     fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+///         writeln!(handle, "graph_title Uptime")?;
+///         writeln!(handle, "uptime.label uptime")?;
+///         Ok(())
+///     }
+///     fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, _config: &Config, _epoch: u64) -> Result<()> {
+///         writeln!(handle, "uptime.value 42")?;
+///         Ok(())
+///     }
+/// }
+///
+/// let plugin = CompositePlugin::new().plugin("uptime", Uptime);
+/// assert_eq!(
+///     plugin.config_string().unwrap(),
+///     "multigraph uptime\ngraph_title Uptime\nuptime.label uptime\n"
+/// );
+/// ```
+#[derive(Default)]
+pub struct CompositePlugin {
+    parts: Vec<(String, Box<dyn DynPlugin>)>,
+}
+
+impl CompositePlugin {
+    /// Create a composite plugin without any parts
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `plugin` as graph `name`
+    pub fn plugin<N: Into<String>, P: DynPlugin + 'static>(mut self, name: N, plugin: P) -> Self {
+        self.parts.push((name.into(), Box::new(plugin)));
+        self
+    }
+
+    /// The names of the graphs, in the order added
+    pub fn names(&self) -> Vec<&str> {
+        self.parts.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+impl MuninPlugin for CompositePlugin {
+    fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        for (name, part) in &self.parts {
+            writeln!(handle, "multigraph {name}")?;
+            part.dyn_config(handle)?;
+        }
+        Ok(())
+    }
+
+    fn acquire<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<()> {
+        for (name, part) in &mut self.parts {
+            writeln!(handle, "multigraph {name}")?;
+            part.dyn_acquire(handle, config, epoch)?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Vec<&str> {
+        vec!["dirtyconfig", "multigraph"]
+    }
+
+    /// Shuts down all parts, even if one of them fails
+    fn on_shutdown(&self, config: &Config) -> Result<()> {
+        for (name, part) in &self.parts {
+            if let Err(e) = part.dyn_on_shutdown(config) {
+                warn!("Part {name} failed to shut down: {e:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes one field, named after the plugin
+    struct Trivial(&'static str);
+    impl MuninPlugin for Trivial {
+        fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+            writeln!(handle, "graph_title {}", self.0)?;
+            writeln!(handle, "{}.label {}", self.0, self.0)?;
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            _config: &Config,
+            epoch: u64,
+        ) -> Result<()> {
+            writeln!(handle, "{}.value {epoch}:1", self.0)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_composite() {
+        let mut plugin = CompositePlugin::new()
+            .plugin("load", Trivial("load"))
+            .plugin("memory", Trivial("memory"));
+        assert_eq!(plugin.names(), ["load", "memory"]);
+        assert_eq!(
+            plugin.config_string().unwrap(),
+            "multigraph load\ngraph_title load\nload.label load\n\
+             multigraph memory\ngraph_title memory\nmemory.label memory\n"
+        );
+
+        let config = Config::new_daemon(String::from("composite"));
+        let mut handle = BufWriter::new(Vec::new());
+        plugin.acquire(&mut handle, &config, 1650000000).unwrap();
+        assert_eq!(
+            handle.into_inner().unwrap(),
+            b"multigraph load\nload.value 1650000000:1\nmultigraph memory\nmemory.value 1650000000:1\n"
+        );
+        assert!(plugin.capabilities().contains(&"multigraph"));
+        plugin.on_shutdown(&config).unwrap();
+    }
+}
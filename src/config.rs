@@ -6,13 +6,94 @@
 // We do not want to write unsafe code
 #![forbid(unsafe_code)]
 
+use crate::clock::SharedClock;
+use crate::value::Aggregation;
+use anyhow::{anyhow, Result};
 use fastrand;
-use log::trace;
+use log::{trace, warn};
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    env,
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Write},
     iter::repeat_with,
     path::{Path, PathBuf},
+    time::Duration,
 };
+use tempfile::NamedTempFile;
+
+/// Parse a human readable buffer size, like `64KiB` or `1MB`, into
+/// bytes, for use with [Config::config_size] and [Config::fetch_size].
+///
+/// Understood are plain numbers (bytes) and the suffixes `B`, `KB`,
+/// `MB`, `GB` (powers of 1000) as well as `KiB`, `MiB`, `GiB` (powers
+/// of 1024) and their short forms `K`, `M`, `G`, case does not
+/// matter. Zero, negative numbers and unknown units give an error.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::config::{parse_size, Config};
+/// let mut config = Config::new(String::from("great-plugin"));
+/// config.fetch_size = parse_size("1MiB").unwrap();
+/// assert_eq!(config.fetch_size, 1048576);
+/// ```
+pub fn parse_size(size: &str) -> Result<usize> {
+    let size = size.trim();
+    // Split into the number and the unit following it
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: usize = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size {size}, expected a positive number"))?;
+    let factor: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "k" | "kib" => 1024,
+        "mb" => 1000 * 1000,
+        "m" | "mib" => 1024 * 1024,
+        "gb" => 1000 * 1000 * 1000,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Unknown unit {unit} in size {size}")),
+    };
+    match number.checked_mul(factor) {
+        Some(0) => Err(anyhow!("Size needs to be larger than 0")),
+        Some(bytes) => Ok(bytes),
+        None => Err(anyhow!("Size {size} too large")),
+    }
+}
+
+/// Where a streaming plugin puts the data gathered by
+/// [MuninPlugin::acquire](super::MuninPlugin::acquire).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Sink {
+    /// Append to the cachefile at [Config::plugin_cache], for
+    /// [MuninPlugin::fetch](super::MuninPlugin::fetch) to hand out.
+    File,
+    /// Send the data of every run as one datagram to the Unix socket
+    /// at the given path, no cachefile is written.
+    UnixDatagram(PathBuf),
+    /// Both, send to the socket at the given path and append to the
+    /// cachefile.
+    FileAndUnixDatagram(PathBuf),
+}
+
+/// How a fetch finds out if the acquire daemon of a streaming plugin
+/// runs, see [Config::lock_strategy].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum LockStrategy {
+    /// It runs if [Config::pidfile] is locked (by the daemon)
+    #[default]
+    Flock,
+    /// It runs if the process with the PID in [Config::pidfile]
+    /// exists, that is, `/proc/PID` is there. For filesystems where
+    /// flock is unreliable, like NFS.
+    PidCheck,
+    /// It runs if both of the above say so
+    Both,
+}
 
 /// Plugin configuration.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -22,6 +103,24 @@ pub struct Config {
     /// Default is "Simple munin plugin in Rust"
     pub plugin_name: String,
 
+    /// The name the plugin got called as, the basename of `argv[0]`,
+    /// set by [MuninPlugin::start](super::MuninPlugin::start). For a
+    /// wildcard plugin this is the name of the symlink, like
+    /// `if_eth0`, see [Config::wildcard_suffix]. Defaults to empty.
+    pub invoked_as: String,
+
+    /// The library directory of munin, from the MUNIN_LIBDIR
+    /// environment variable munin sets. Informational, for plugins
+    /// and the `verify` argument to report. Defaults to None if the
+    /// variable is not set.
+    pub munin_libdir: Option<PathBuf>,
+
+    /// The socket of rrdcached, if munin uses it, from the
+    /// MUNIN_RRDCACHED_SOCK environment variable. Informational, like
+    /// [Config::munin_libdir]. Defaults to None if the variable is not
+    /// set.
+    pub rrdcached_sock: Option<PathBuf>,
+
     /// Plugins state directory
     ///
     /// Fallback to /tmp if environment variable MUNIN_PLUGSTATE is
@@ -45,11 +144,29 @@ pub struct Config {
     /// this is true, otherwise false.
     pub dirtyconfig: bool,
 
+    /// Does munin support multigraph plugins?
+    ///
+    /// Checks MUNIN_CAP_MULTIGRAPH environment variable, if set to 1,
+    /// this is true, otherwise false. Multigraph plugins should fall
+    /// back to a single graph (or output nothing) if not.
+    pub multigraph: bool,
+
     /// Does this plugin need to run in background, continuously fetching data?
     ///
     /// Default to false
     pub daemonize: bool,
 
+    /// Run the acquire loop of a _streaming_ plugin in the foreground,
+    /// for a supervisor like systemd to manage, instead of detaching
+    /// into the background.
+    ///
+    /// [MuninPlugin::daemon](super::MuninPlugin::daemon) then still
+    /// locks [Config::pidfile] and writes its PID to it, and fetch no
+    /// longer spawns the acquire daemon - the supervisor runs it.
+    /// True if the MUNIN_PLUGIN_FOREGROUND environment variable is
+    /// set to 1, otherwise false.
+    pub foreground: bool,
+
     /// If plugin uses daemonize, whats the pidfile name?
     ///
     /// Defaults to [Config::plugin_statedir] plus "munin-plugin.pid", using
@@ -57,6 +174,37 @@ pub struct Config {
     /// [Config::plugin_statedir]/[Config::plugin_name].pid
     pub pidfile: PathBuf,
 
+    /// File recording when munin last fetched data.
+    ///
+    /// Every [MuninPlugin::fetch](super::MuninPlugin::fetch) of a
+    /// _streaming_ plugin stores the current epoch in here, see
+    /// [Config::last_fetch]. Defaults
+    /// to [Config::plugin_statedir] plus "munin-plugin.lastfetch",
+    /// using [Config::new] will set it to
+    /// [Config::plugin_statedir]/[Config::plugin_name].lastfetch
+    pub last_fetch_file: PathBuf,
+
+    /// File keeping state between runs of a _standard_ plugin, like
+    /// the counter reading of the last run, see [Config::save_state]
+    /// and [Config::load_state]. A [Counter](crate::value::Counter)
+    /// keeps its readings in a file beside it.
+    ///
+    /// Defaults to the MUNIN_STATEFILE environment variable munin
+    /// sets, without it to [Config::plugin_statedir] plus
+    /// "munin-plugin.state", using [Config::new] will set it to
+    /// [Config::plugin_statedir]/[Config::plugin_name].state
+    pub statefile: PathBuf,
+
+    /// Executable to run for spawning the acquire daemon.
+    ///
+    /// A streaming plugin, when called to fetch data, starts itself
+    /// in the background with the `acquire` argument. By default it
+    /// uses the running executable (see [std::env::current_exe]),
+    /// falling back to the name it got called as (`argv[0]`), should
+    /// that not be available. Set this to override it, see
+    /// [Config::spawn_command].
+    pub acquire_command: Option<PathBuf>,
+
     /// Size of buffer for BufWriter for [MuninPlugin::config](super::MuninPlugin::config).
     ///
     /// Defaults to 8192, but if the plugin outputs huge munin
@@ -69,6 +217,252 @@ pub struct Config {
     /// Defaults to 8192, but if the plugin outputs large datasets, it
     /// is useful to increase this.
     pub fetch_size: usize,
+
+    /// Where a streaming plugin stores its data.
+    ///
+    /// Defaults to [Sink::File], appending to [Config::plugin_cache].
+    /// If a co-located collector should be fed directly, without the
+    /// round-trip through the cachefile, use [Sink::UnixDatagram] (or
+    /// [Sink::FileAndUnixDatagram] to have both).
+    pub acquire_sink: Sink,
+
+    /// How to check if the acquire daemon runs, before spawning it
+    /// (and for `healthcheck`). Defaults to [LockStrategy::Flock].
+    pub lock_strategy: LockStrategy,
+
+    /// Keep the daemon running if [MuninPlugin::acquire](super::MuninPlugin::acquire) panics?
+    ///
+    /// Usually a panic in acquire takes down the whole daemon, and
+    /// until munin comes around to fetch data (and restarts it) no
+    /// data is gathered. If true, the daemon catches the panic, logs
+    /// it and only skips that run. Defaults to false.
+    ///
+    /// Note that this claims your plugin to be
+    /// [unwind safe](std::panic::UnwindSafe): if acquire panics
+    /// halfway through changing the plugins state, the next run sees
+    /// that half-done state. Also, anything acquire wrote before
+    /// panicking still ends up in the cachefile.
+    pub catch_acquire_panics: bool,
+
+    /// How long a fetch of a _standard_ plugin waits for
+    /// [MuninPlugin::acquire](super::MuninPlugin::acquire), before
+    /// giving up with unknown values for all fields and a
+    /// [PluginError::Timeout](crate::PluginError::Timeout). So a hung
+    /// read does not hang munin. Only honoured by a plugin wrapped in
+    /// a [TimeoutPlugin](crate::TimeoutPlugin), walking away from a
+    /// hung acquire needs it on a thread of its own. Defaults to
+    /// None, waiting for as long as it takes.
+    pub fetch_timeout: Option<Duration>,
+
+    /// How long the daemon of a _streaming_ plugin waits for a run
+    /// of [MuninPlugin::acquire](super::MuninPlugin::acquire), before
+    /// storing unknown values for its epoch and going on with the
+    /// next. Runs while the hung one is still busy get skipped, so
+    /// one blocking read does not stall the loop. Like
+    /// [Config::fetch_timeout], only honoured by a plugin wrapped in
+    /// a [TimeoutPlugin](crate::TimeoutPlugin). Defaults to None,
+    /// waiting for as long as it takes.
+    pub acquire_timeout: Option<Duration>,
+
+    /// Tolerated difference, in seconds, between two successive
+    /// epochs handed to [MuninPlugin::acquire](super::MuninPlugin::acquire)
+    /// in a streaming plugin.
+    ///
+    /// Whenever the epoch moves backwards, or forward by more than
+    /// this on top of [Config::acquire_interval], the daemon considers
+    /// this a clock jump (NTP step, VM suspend, ...) and logs a
+    /// warning. Defaults to 5.
+    pub clock_jump_threshold: u64,
+
+    /// Write a marker into the cachefile when a clock jump got detected?
+    ///
+    /// If true, a comment line `# clock jump from OLD to NEW` is
+    /// written to the cachefile before the data of the tick, so the
+    /// jump can be spotted when looking at the cache. Munin ignores
+    /// comment lines. Defaults to false.
+    pub clock_jump_marker: bool,
+
+    /// Write a `# last-updated EPOCH` comment after every run?
+    ///
+    /// If true, the daemon appends this line after the data of each
+    /// run, so the last such line in the cachefile tells how fresh it
+    /// is, without having to look at the EPOCH of every value. Fetch
+    /// hands on only the newest one, after all the data, instead of
+    /// one per run. Munin ignores comment lines. Defaults to false.
+    pub freshness_comment: bool,
+
+    /// Maximum age, in seconds, of the newest data in the cachefile
+    /// for the `healthcheck` argument to consider a streaming plugin
+    /// healthy. Right after a fetch emptied it, the time of that
+    /// fetch counts. Defaults to 10.
+    pub healthcheck_max_age: u64,
+
+    /// Write a sequence number for every run into the cachefile?
+    ///
+    /// If true, the daemon writes a comment line `# sequence N epoch
+    /// EPOCH` before the data of every run, with N counting up by
+    /// one each time. Gaps in the sequence or epochs make dropped
+    /// runs visible when inspecting the cachefile. Munin ignores
+    /// comment lines. Defaults to false.
+    pub debug_sequence: bool,
+
+    /// Warn about stray writes to stdout during acquire?
+    ///
+    /// A `println!` in acquire goes to /dev/null in the detached
+    /// daemon, but breaks the output munin parses in foreground runs,
+    /// `test` and `acquire-once`. If true, those compare the size of
+    /// stdout before and after acquire, warning if it grew. This only
+    /// works with stdout redirected to a file, terminals and pipes
+    /// have no size to compare. True if the MUNIN_PLUGIN_DEBUG
+    /// environment variable is set, otherwise false.
+    pub debug_stdout: bool,
+
+    /// How often the daemon of a _streaming_ plugin calls
+    /// [MuninPlugin::acquire](super::MuninPlugin::acquire). Defaults
+    /// to once a second. Plugins whose values change slowly can run
+    /// less often, say every 10 seconds. Sub-second intervals work
+    /// too, the values of all runs within a second then get folded
+    /// into one, see [Config::subsecond_aggregation]. Needs to be
+    /// larger than zero.
+    ///
+    /// Remember to set munins `update_rate` to match.
+    pub acquire_interval: Duration,
+
+    /// How the daemon folds the values of the runs within one second
+    /// into one for munin, with an [Config::acquire_interval] below
+    /// a second. Munin keeps only one value per field and epoch, so
+    /// the runs in between would get lost. Defaults to
+    /// [Aggregation::Mean], use [Aggregation::Last] for counters.
+    pub subsecond_aggregation: Aggregation,
+
+    /// Start the acquire loop of a _streaming_ plugin on a whole
+    /// second of [Config::clock]?
+    ///
+    /// If true, the daemon sleeps until the next full second before
+    /// its first run, so plugins started at different times all
+    /// sample at the top of the second, giving comparable series.
+    /// Only the sync loop of
+    /// [MuninPlugin::daemon](super::MuninPlugin::daemon) aligns.
+    /// Defaults to false.
+    pub align_to_second: bool,
+
+    /// How often the daemon of a _streaming_ plugin stores the data
+    /// it gathered (see [Config::acquire_sink]).
+    ///
+    /// Defaults to zero, storing the data of every run right away.
+    /// Longer intervals collect the runs in memory in between, saving
+    /// IO, but the data is lost should the daemon crash, and a fetch
+    /// only sees what got stored.
+    pub flush_interval: Duration,
+
+    /// How long a fetch that just spawned the acquire daemon waits,
+    /// before handing out data, so the daemon has a chance to gather
+    /// some. Defaults to 1 second, zero does not wait at all.
+    pub initial_spawn_delay: Duration,
+
+    /// Should the daemon of a _streaming_ plugin stop cleanly on
+    /// `SIGTERM` and `SIGINT`, finishing the current run and removing
+    /// its [Config::pidfile]? Defaults to true.
+    pub catch_signals: bool,
+
+    /// The `update_rate` (in seconds) of a _streaming_ plugin, how
+    /// often it delivers values. See
+    /// [write_streaming_graph_config](crate::streaming::write_streaming_graph_config).
+    /// Defaults to None, [MuninPlugin::start](super::MuninPlugin::start)
+    /// warns if it is unset for a daemonizing plugin.
+    pub update_rate: Option<u32>,
+
+    /// The value of `graph_data_size` of a _streaming_ plugin, like
+    /// `custom 1d`, how much data munin keeps at that resolution. See
+    /// [write_streaming_graph_config](crate::streaming::write_streaming_graph_config).
+    /// Defaults to None, [MuninPlugin::start](super::MuninPlugin::start)
+    /// warns if it is unset for a daemonizing plugin.
+    pub graph_data_size: Option<String>,
+
+    /// Derive the vertical axis of the graphs from the values munin
+    /// gets with dirtyconfig?
+    ///
+    /// If true, and dirtyconfig is enabled, the data gets fetched
+    /// before the config is written, and every graph gets
+    /// `--lower-limit` and `--upper-limit` set to the smallest and
+    /// largest value it got, appended to its `graph_args`. Meant for
+    /// values whose range is not known in advance, for all others
+    /// set fixed limits. Defaults to false.
+    pub observed_range: bool,
+
+    /// Directory the daemon of a _streaming_ plugin changes into
+    /// after detaching, relative paths in the config resolve from
+    /// here. Needs to exist. Defaults to _/tmp_.
+    pub working_directory: PathBuf,
+
+    /// Should the daemon chown its [Config::pidfile]? Defaults to
+    /// true. As only root may chown, plugins run as normal user (say
+    /// while testing locally, or in a container) should set this to
+    /// false.
+    pub chown_pidfile: bool,
+
+    /// Where epochs come from, the ones handed to
+    /// [MuninPlugin::acquire](super::MuninPlugin::acquire) and those
+    /// recorded for fetches. Defaults to the system clock, tests can
+    /// set their own [Clock](crate::clock::Clock).
+    pub clock: SharedClock,
+
+    /// Upper limit of the size, in bytes, of [Config::plugin_cache].
+    ///
+    /// If munin stops fetching, the cachefile of a _streaming_ plugin
+    /// would grow forever. With a limit set, the daemon checks the
+    /// size before appending, and once it exceeds the limit, drops
+    /// the oldest lines, keeping the newest half of the limit (at a
+    /// line boundary). Defaults to None, no limit.
+    pub max_cache_bytes: Option<u64>,
+
+    /// Gzip-compress [Config::plugin_cache].
+    ///
+    /// A fast _streaming_ plugin with many fields writes a lot of data
+    /// between two fetches. With this set, the daemon appends each
+    /// run as its own gzip member, and
+    /// [MuninPlugin::fetch](super::MuninPlugin::fetch) decompresses
+    /// while copying, so munin still gets plain text. With
+    /// [Config::max_cache_bytes] the limit applies to the compressed
+    /// size. Defaults to false.
+    pub compress_cache: bool,
+
+    /// Keep the cachefile [MuninPlugin::fetch](super::MuninPlugin::fetch)
+    /// handed to munin, as [Config::plugin_cache] with the extension
+    /// `last`, replacing the one from the fetch before. To look at what
+    /// munin got, when debugging missing data. Defaults to false, the
+    /// fetched data gets deleted.
+    pub keep_fetched_cache: bool,
+
+    /// Empty [Config::plugin_cache] (and [Config::extra_caches]) when
+    /// the acquire daemon starts?
+    ///
+    /// After a crash of the daemon the cachefile may still hold data
+    /// of long ago, which munin would take on the next fetch. If true,
+    /// only data gathered since the start gets handed out. Defaults to
+    /// false.
+    pub reset_cache_on_start: bool,
+
+    /// Directory for the temporary file
+    /// [MuninPlugin::fetch](super::MuninPlugin::fetch) moves the
+    /// cachefile to, before handing it out.
+    ///
+    /// Defaults to None, the directory of [Config::plugin_cache]. Set
+    /// it if that is read-only or slow. On a different filesystem,
+    /// the cachefile gets copied (and removed) instead of renamed.
+    pub fetch_tempdir: Option<PathBuf>,
+
+    /// More cachefiles, for the subgraphs of a _streaming_ multigraph
+    /// plugin, keyed by the name of the multigraph.
+    ///
+    /// Data [MuninPlugin::acquire](super::MuninPlugin::acquire) writes
+    /// after a `multigraph NAME` line, with NAME listed here, goes to
+    /// the cachefile for NAME instead of [Config::plugin_cache].
+    /// [MuninPlugin::fetch](super::MuninPlugin::fetch) then hands out
+    /// the main cachefile first, followed by each of these (in order
+    /// of their names) below a single `multigraph NAME` line. Defaults
+    /// to none.
+    pub extra_caches: BTreeMap<String, PathBuf>,
 }
 
 impl Config {
@@ -78,6 +472,220 @@ impl Config {
         PathBuf::from(env::var("MUNIN_PLUGSTATE").unwrap_or_else(|_| String::from("/tmp")))
     }
 
+    /// The statefile munin wants us to use, or one in the statedir
+    /// named after `name`, see [Config::statefile].
+    fn get_statefile(name: &str) -> PathBuf {
+        Config::env_var("MUNIN_STATEFILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Config::get_statedir().join(format!("{name}.state")))
+    }
+
+    /// Check the MUNIN_CAP_DIRTYCONFIG environment variable, true if
+    /// it is set to 1.
+    fn env_dirtyconfig() -> bool {
+        Config::env_capability("MUNIN_CAP_DIRTYCONFIG")
+    }
+
+    /// Check a munin capability environment variable, like
+    /// MUNIN_CAP_MULTIGRAPH (or a switch like MUNIN_PLUGIN_FOREGROUND),
+    /// true if it is set to 1.
+    fn env_capability(name: &str) -> bool {
+        match env::var(name) {
+            Ok(val) => val.eq(&"1"),
+            Err(_) => false,
+        }
+    }
+
+    /// Read the plugin setting `name`, as set with `env.NAME` in
+    /// munins plugin-conf.d, see [crate::pluginconf].
+    ///
+    /// munin-node hands those to the plugin as environment variable
+    /// `NAME`, so `name` may be given with or without the `env.`
+    /// prefix. Unset and empty variables are None.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::Config;
+    /// std::env::set_var("interface", "eth0");
+    /// assert_eq!(Config::env_var("env.interface"), Some(String::from("eth0")));
+    /// assert_eq!(Config::env_var("interface"), Some(String::from("eth0")));
+    /// ```
+    pub fn env_var(name: &str) -> Option<String> {
+        let name = name.strip_prefix("env.").unwrap_or(name);
+        env::var(name).ok().filter(|val| !val.is_empty())
+    }
+
+    /// Like [Config::env_var], returning `default` if the setting is
+    /// not there.
+    pub fn env_var_or(name: &str, default: &str) -> String {
+        Config::env_var(name).unwrap_or_else(|| default.to_string())
+    }
+
+    /// Like [Config::env_var], parsed into a `T`. Errors if the
+    /// setting is there, but does not parse.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::Config;
+    /// std::env::set_var("warning", "10");
+    /// assert_eq!(Config::env_parse::<u32>("env.warning").unwrap(), Some(10));
+    /// assert_eq!(Config::env_parse::<u32>("env.critical").unwrap(), None);
+    /// ```
+    pub fn env_parse<T>(name: &str) -> Result<Option<T>>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        Config::env_var(name)
+            .map(|val| {
+                val.trim()
+                    .parse()
+                    .map_err(|e| anyhow!("Can not parse {name} value {val}: {e}"))
+            })
+            .transpose()
+    }
+
+    /// Decide if data should be sent right after the config. That is
+    /// [Config::dirtyconfig], wherever its value came from.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::config::Config;
+    /// let mut config = Config::new(String::from("great-plugin"));
+    /// config.dirtyconfig = false;
+    /// assert!(!config.dirtyconfig_enabled());
+    /// ```
+    pub fn dirtyconfig_enabled(&self) -> bool {
+        self.dirtyconfig
+    }
+
+    /// Return the epoch of the last time munin fetched data, if known.
+    ///
+    /// Lets a plugin adapt, for example sample less often when munin
+    /// fetches rarely. The value is read from
+    /// [Config::last_fetch_file] on every call.
+    pub fn last_fetch(&self) -> Option<u64> {
+        fs::read_to_string(&self.last_fetch_file)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Store `epoch` as time of the last fetch in
+    /// [Config::last_fetch_file].
+    ///
+    /// The file is written to a temporary file first and then renamed
+    /// into place, so readers never see a partial value.
+    pub fn record_fetch(&self, epoch: u64) -> Result<()> {
+        let dir = self
+            .last_fetch_file
+            .parent()
+            .unwrap_or_else(|| Path::new("."));
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        writeln!(tmp, "{epoch}")?;
+        tmp.persist(&self.last_fetch_file)?;
+        Ok(())
+    }
+
+    /// Store `state` as JSON in [Config::statefile], for the next run
+    /// to pick up with [Config::load_state].
+    ///
+    /// Like [Config::record_fetch], the file is written to a temporary
+    /// file first and then renamed into place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::Config;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let mut config = Config::new(String::from("counter"));
+    /// # config.statefile = dir.path().join("counter.state");
+    /// assert_eq!(config.load_state::<u64>(), None);
+    /// config.save_state(&4711u64).unwrap();
+    /// assert_eq!(config.load_state::<u64>(), Some(4711));
+    /// ```
+    pub fn save_state<T: Serialize>(&self, state: &T) -> Result<()> {
+        save_json(&self.statefile, state)
+    }
+
+    /// Read back what [Config::save_state] stored in
+    /// [Config::statefile].
+    ///
+    /// None if there is no state yet, like on the very first run. A
+    /// statefile that can not be read or parsed (say, after the
+    /// plugin changed its state type) is None as well, with a
+    /// warning, so the plugin starts over.
+    pub fn load_state<T: DeserializeOwned>(&self) -> Option<T> {
+        load_json(&self.statefile)
+    }
+
+    /// Size [Config::config_size] and [Config::fetch_size] for a
+    /// plugin with `fields` fields, written in lines of about
+    /// `avg_line` bytes: their product, rounded up to a power of two.
+    ///
+    /// A _streaming_ plugin hands out many values per field on every
+    /// fetch, count each of them as a field.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::Config;
+    /// let config = Config::new(String::from("big")).with_estimated_fields(500, 40);
+    /// assert_eq!(config.config_size, 32768);
+    /// ```
+    pub fn with_estimated_fields(mut self, fields: usize, avg_line: usize) -> Self {
+        let size = fields
+            .saturating_mul(avg_line)
+            .max(1)
+            .checked_next_power_of_two()
+            .unwrap_or(usize::MAX);
+        self.config_size = size;
+        self.fetch_size = size;
+        self
+    }
+
+    /// Set [Config::acquire_command] to the currently running
+    /// executable, as found by [std::env::current_exe].
+    pub fn use_current_exe(&mut self) -> Result<()> {
+        self.acquire_command = Some(env::current_exe()?);
+        Ok(())
+    }
+
+    /// The instance of a wildcard plugin, the part of
+    /// [Config::invoked_as] after `prefix`. None if it does not start
+    /// with `prefix`, or nothing comes after it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::Config;
+    /// let mut config = Config::new(String::from("if_"));
+    /// config.invoked_as = String::from("if_eth0");
+    /// assert_eq!(config.wildcard_suffix("if_"), Some(String::from("eth0")));
+    /// ```
+    pub fn wildcard_suffix(&self, prefix: &str) -> Option<String> {
+        self.invoked_as
+            .strip_prefix(prefix)
+            .filter(|suffix| !suffix.is_empty())
+            .map(String::from)
+    }
+
+    /// Return the command to spawn the acquire daemon with,
+    /// [Config::acquire_command] if set, otherwise the canonicalized
+    /// path of the running executable. Only if that can not be
+    /// found, `arg0`, the name the plugin got called as - which munin
+    /// may resolve differently via PATH.
+    pub fn spawn_command(&self, arg0: &str) -> PathBuf {
+        self.acquire_command
+            .clone()
+            .or_else(|| env::current_exe().and_then(fs::canonicalize).ok())
+            .unwrap_or_else(|| PathBuf::from(arg0))
+    }
+
     /// Create a new Config with defined plugin_name, also setting
     /// [Config::pidfile] and [Config::plugin_cache] to a sensible
     /// value using the [Config::plugin_name].
@@ -109,14 +717,61 @@ impl Config {
         Config::realnew(plugin_name, true)
     }
 
+    /// Start a [ConfigBuilder], to set up a Config step by step.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::config::Config;
+    /// let config = Config::builder()
+    ///     .name(String::from("great-plugin"))
+    ///     .daemonize(true)
+    ///     .build();
+    /// assert!(config.daemonize);
+    /// ```
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Set up [env_logger] as logger, for plugins that do not bring
+    /// their own. Only available with the `logging` feature.
+    ///
+    /// Logs go to stderr, never to stdout, which munin parses. The
+    /// level (or a full env_logger filter, like
+    /// `munin_plugin=trace`) comes from the MUNIN_PLUGIN_LOG
+    /// environment variable, defaulting to warn. Does nothing, if a
+    /// logger is installed already.
+    #[cfg(feature = "logging")]
+    pub fn init_logging() {
+        let env = env_logger::Env::new().filter_or("MUNIN_PLUGIN_LOG", "warn");
+        // An error only means there is a logger already
+        let _ = env_logger::Builder::from_env(env)
+            .target(env_logger::Target::Stderr)
+            .try_init();
+    }
+
+    /// Check that [Config::working_directory] is an existing
+    /// directory, the daemon can change into.
+    pub fn check_working_directory(&self) -> Result<()> {
+        if !self.working_directory.is_dir() {
+            return Err(anyhow!(
+                "Working directory {} does not exist or is no directory",
+                self.working_directory.display()
+            ));
+        }
+        Ok(())
+    }
+
     /// Actually do the work of creating the config element
     fn realnew(plugin_name: String, daemonize: bool) -> Self {
         trace!("Creating new config for plugin {plugin_name}, daemon: {daemonize}");
         let pd = plugin_name.clone();
         Self {
             plugin_name,
-            daemonize: daemonize,
+            daemonize,
             pidfile: Config::get_statedir().join(format!("{}.pid", pd)),
+            last_fetch_file: Config::get_statedir().join(format!("{}.lastfetch", pd)),
+            statefile: Config::get_statefile(&pd),
             plugin_cache: Config::get_statedir().join(format!("munin.{}.value", pd)),
             ..Default::default()
         }
@@ -135,20 +790,379 @@ impl Default for Config {
         let cachename = Path::new(&statedir).join(format!("munin.{}.value", insert));
         Self {
             plugin_name: String::from("Simple munin plugin in Rust"),
+            invoked_as: String::new(),
+            munin_libdir: Config::env_var("MUNIN_LIBDIR").map(PathBuf::from),
+            rrdcached_sock: Config::env_var("MUNIN_RRDCACHED_SOCK").map(PathBuf::from),
             plugin_statedir: statedir.clone(),
             plugin_cache: cachename,
-            dirtyconfig: match env::var("MUNIN_CAP_DIRTYCONFIG") {
-                Ok(val) => val.eq(&"1"),
-                Err(_) => false,
-            },
+            dirtyconfig: Config::env_dirtyconfig(),
+            multigraph: Config::env_capability("MUNIN_CAP_MULTIGRAPH"),
             daemonize: false,
+            foreground: Config::env_capability("MUNIN_PLUGIN_FOREGROUND"),
             pidfile: statedir.join("munin-plugin.pid"),
+            last_fetch_file: statedir.join("munin-plugin.lastfetch"),
+            statefile: Config::get_statefile("munin-plugin"),
+            acquire_command: None,
             config_size: 8192,
             fetch_size: 8192,
+            acquire_sink: Sink::File,
+            lock_strategy: LockStrategy::Flock,
+            catch_acquire_panics: false,
+            fetch_timeout: None,
+            acquire_timeout: None,
+            clock_jump_threshold: 5,
+            clock_jump_marker: false,
+            freshness_comment: false,
+            healthcheck_max_age: 10,
+            debug_sequence: false,
+            debug_stdout: Config::env_var("MUNIN_PLUGIN_DEBUG").is_some(),
+            acquire_interval: Duration::from_secs(1),
+            subsecond_aggregation: Aggregation::Mean,
+            align_to_second: false,
+            flush_interval: Duration::ZERO,
+            initial_spawn_delay: Duration::from_secs(1),
+            catch_signals: true,
+            update_rate: None,
+            graph_data_size: None,
+            observed_range: false,
+            working_directory: PathBuf::from("/tmp"),
+            chown_pidfile: true,
+            clock: SharedClock::default(),
+            max_cache_bytes: None,
+            compress_cache: false,
+            keep_fetched_cache: false,
+            reset_cache_on_start: false,
+            fetch_tempdir: None,
+            extra_caches: BTreeMap::new(),
         }
     }
 }
 
+/// Builder for a [Config], see [Config::builder].
+///
+/// Like [Config::new], the [Config::pidfile] and [Config::plugin_cache]
+/// get derived from the plugin name, but only if they are not set
+/// explicitly - no matter in which order the setters got called.
+/// Everything not set keeps the value [Config::new] would use.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ConfigBuilder {
+    name: Option<String>,
+    daemonize: bool,
+    fetch_size: Option<usize>,
+    config_size: Option<usize>,
+    pidfile: Option<PathBuf>,
+    plugin_cache: Option<PathBuf>,
+    dirtyconfig_auto: Option<bool>,
+}
+
+impl ConfigBuilder {
+    /// Set the [Config::plugin_name]
+    pub fn name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Set [Config::daemonize], for _streaming_ plugins
+    pub fn daemonize(mut self, daemonize: bool) -> Self {
+        self.daemonize = daemonize;
+        self
+    }
+
+    /// Set [Config::fetch_size]
+    pub fn fetch_size(mut self, size: usize) -> Self {
+        self.fetch_size = Some(size);
+        self
+    }
+
+    /// Set [Config::config_size]
+    pub fn config_size(mut self, size: usize) -> Self {
+        self.config_size = Some(size);
+        self
+    }
+
+    /// Set [Config::fetch_size] from a human readable size like `8k`
+    /// or `1MiB`, see [parse_size]
+    pub fn fetch_size_str(self, size: &str) -> Result<Self> {
+        Ok(self.fetch_size(parse_size(size)?))
+    }
+
+    /// Set [Config::config_size] from a human readable size like `8k`
+    /// or `1MiB`, see [parse_size]
+    pub fn config_size_str(self, size: &str) -> Result<Self> {
+        Ok(self.config_size(parse_size(size)?))
+    }
+
+    /// Set [Config::pidfile], instead of deriving it from the name
+    pub fn pidfile(mut self, pidfile: PathBuf) -> Self {
+        self.pidfile = Some(pidfile);
+        self
+    }
+
+    /// Set [Config::plugin_cache], instead of deriving it from the name
+    pub fn plugin_cache(mut self, plugin_cache: PathBuf) -> Self {
+        self.plugin_cache = Some(plugin_cache);
+        self
+    }
+
+    /// Should MUNIN_CAP_DIRTYCONFIG decide about
+    /// [Config::dirtyconfig]? True (the default) fills it from the
+    /// environment, false makes it start out false, for wrappers that
+    /// set the variable but want the classic two calls.
+    pub fn dirtyconfig_auto(mut self, auto: bool) -> Self {
+        self.dirtyconfig_auto = Some(auto);
+        self
+    }
+
+    /// Create the [Config]
+    pub fn build(self) -> Config {
+        let mut config = match self.name {
+            Some(name) => Config::realnew(name, self.daemonize),
+            None => Config {
+                daemonize: self.daemonize,
+                ..Default::default()
+            },
+        };
+        if let Some(size) = self.fetch_size {
+            config.fetch_size = size;
+        }
+        if let Some(size) = self.config_size {
+            config.config_size = size;
+        }
+        if let Some(pidfile) = self.pidfile {
+            config.pidfile = pidfile;
+        }
+        if let Some(plugin_cache) = self.plugin_cache {
+            config.plugin_cache = plugin_cache;
+        }
+        if self.dirtyconfig_auto == Some(false) {
+            config.dirtyconfig = false;
+        }
+        config
+    }
+}
+
+/// Store `state` as JSON in the file at `path`, written to a
+/// temporary file first and then renamed into place, see
+/// [Config::save_state].
+pub(crate) fn save_json<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut tmp, state)?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+/// Read back what [save_json] stored at `path`, None if there is
+/// nothing or it can not be used, see [Config::load_state].
+pub(crate) fn load_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let state = match fs::read(path) {
+        Ok(state) => state,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Can not read statefile {}: {e}", path.display());
+            return None;
+        }
+    };
+    serde_json::from_slice(&state)
+        .map_err(|e| warn!("Ignoring statefile {}: {e}", path.display()))
+        .ok()
+}
+
+/// The settings of a [Config] kept in a TOML file, see
+/// [Config::from_toml_file]. Durations are in (fractional) seconds.
+#[cfg(feature = "config-file")]
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    plugin_name: Option<String>,
+    daemonize: Option<bool>,
+    plugin_statedir: Option<PathBuf>,
+    plugin_cache: Option<PathBuf>,
+    pidfile: Option<PathBuf>,
+    last_fetch_file: Option<PathBuf>,
+    statefile: Option<PathBuf>,
+    working_directory: Option<PathBuf>,
+    acquire_command: Option<PathBuf>,
+    dirtyconfig: Option<bool>,
+    config_size: Option<usize>,
+    fetch_size: Option<usize>,
+    acquire_interval: Option<f64>,
+    flush_interval: Option<f64>,
+    initial_spawn_delay: Option<f64>,
+    update_rate: Option<u32>,
+    graph_data_size: Option<String>,
+    max_cache_bytes: Option<u64>,
+    compress_cache: Option<bool>,
+    keep_fetched_cache: Option<bool>,
+    healthcheck_max_age: Option<u64>,
+    catch_acquire_panics: Option<bool>,
+    fetch_timeout: Option<f64>,
+    acquire_timeout: Option<f64>,
+    align_to_second: Option<bool>,
+    // Tables come last in TOML
+    extra_caches: Option<BTreeMap<String, PathBuf>>,
+}
+
+#[cfg(feature = "config-file")]
+impl Config {
+    /// Read a [Config] from the TOML file at `path`. Only available
+    /// with the `config-file` feature.
+    ///
+    /// The file can set the plugin name, paths, buffer sizes,
+    /// intervals (in seconds) and the switches of the cachefile,
+    /// named like the fields of [Config]. Without explicit paths,
+    /// they get derived from the plugin name, as with [Config::new].
+    /// Everything not in the file keeps its default. What munin
+    /// passes in the environment still wins: MUNIN_PLUGSTATE over
+    /// `plugin_statedir`, MUNIN_CAP_DIRTYCONFIG over `dirtyconfig`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::Config;
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// # let path = dir.path().join("plugin.toml");
+    /// std::fs::write(&path, "plugin_name = \"traffic\"\ndaemonize = true\nacquire_interval = 0.5\n").unwrap();
+    /// let config = Config::from_toml_file(&path).unwrap();
+    /// assert_eq!(config.plugin_name, "traffic");
+    /// assert!(config.pidfile.ends_with("traffic.pid"));
+    /// assert_eq!(config.acquire_interval.as_millis(), 500);
+    /// ```
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Can not read config file {}: {e}", path.display()))?;
+        let file: ConfigFile = toml::from_str(&content)
+            .map_err(|e| anyhow!("Can not parse config file {}: {e}", path.display()))?;
+        let daemonize = file.daemonize.unwrap_or(false);
+        let mut config = match file.plugin_name {
+            Some(name) => Config::realnew(name, daemonize),
+            None => Config {
+                daemonize,
+                ..Default::default()
+            },
+        };
+        let seconds = |name: &str, value: f64| {
+            Duration::try_from_secs_f64(value)
+                .map_err(|e| anyhow!("Can not use {name} value {value}: {e}"))
+        };
+
+        if let Some(statedir) = file.plugin_statedir {
+            config.plugin_statedir = statedir;
+        }
+        if let Some(plugin_cache) = file.plugin_cache {
+            config.plugin_cache = plugin_cache;
+        }
+        if let Some(pidfile) = file.pidfile {
+            config.pidfile = pidfile;
+        }
+        if let Some(last_fetch_file) = file.last_fetch_file {
+            config.last_fetch_file = last_fetch_file;
+        }
+        if let Some(statefile) = file.statefile {
+            config.statefile = statefile;
+        }
+        if let Some(working_directory) = file.working_directory {
+            config.working_directory = working_directory;
+        }
+        config.acquire_command = file.acquire_command.or(config.acquire_command);
+        if let Some(dirtyconfig) = file.dirtyconfig {
+            config.dirtyconfig = dirtyconfig;
+        }
+        if let Some(size) = file.config_size {
+            config.config_size = size;
+        }
+        if let Some(size) = file.fetch_size {
+            config.fetch_size = size;
+        }
+        if let Some(interval) = file.acquire_interval {
+            config.acquire_interval = seconds("acquire_interval", interval)?;
+        }
+        if let Some(interval) = file.flush_interval {
+            config.flush_interval = seconds("flush_interval", interval)?;
+        }
+        if let Some(delay) = file.initial_spawn_delay {
+            config.initial_spawn_delay = seconds("initial_spawn_delay", delay)?;
+        }
+        config.update_rate = file.update_rate.or(config.update_rate);
+        config.graph_data_size = file.graph_data_size.or(config.graph_data_size);
+        config.max_cache_bytes = file.max_cache_bytes.or(config.max_cache_bytes);
+        if let Some(compress) = file.compress_cache {
+            config.compress_cache = compress;
+        }
+        if let Some(keep) = file.keep_fetched_cache {
+            config.keep_fetched_cache = keep;
+        }
+        if let Some(age) = file.healthcheck_max_age {
+            config.healthcheck_max_age = age;
+        }
+        if let Some(catch) = file.catch_acquire_panics {
+            config.catch_acquire_panics = catch;
+        }
+        if let Some(timeout) = file.fetch_timeout {
+            config.fetch_timeout = Some(seconds("fetch_timeout", timeout)?);
+        }
+        if let Some(timeout) = file.acquire_timeout {
+            config.acquire_timeout = Some(seconds("acquire_timeout", timeout)?);
+        }
+        if let Some(align) = file.align_to_second {
+            config.align_to_second = align;
+        }
+        if let Some(extra_caches) = file.extra_caches {
+            config.extra_caches = extra_caches;
+        }
+
+        // The environment munin sets wins
+        if env::var_os("MUNIN_PLUGSTATE").is_some() {
+            config.plugin_statedir = Config::get_statedir();
+        }
+        if env::var_os("MUNIN_CAP_DIRTYCONFIG").is_some() {
+            config.dirtyconfig = Config::env_dirtyconfig();
+        }
+        Ok(config)
+    }
+
+    /// Write the settings [Config::from_toml_file] reads to the TOML
+    /// file at `path`, replacing it. Only available with the
+    /// `config-file` feature.
+    pub fn to_toml_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file = ConfigFile {
+            plugin_name: Some(self.plugin_name.clone()),
+            daemonize: Some(self.daemonize),
+            plugin_statedir: Some(self.plugin_statedir.clone()),
+            plugin_cache: Some(self.plugin_cache.clone()),
+            pidfile: Some(self.pidfile.clone()),
+            last_fetch_file: Some(self.last_fetch_file.clone()),
+            statefile: Some(self.statefile.clone()),
+            working_directory: Some(self.working_directory.clone()),
+            acquire_command: self.acquire_command.clone(),
+            dirtyconfig: Some(self.dirtyconfig),
+            config_size: Some(self.config_size),
+            fetch_size: Some(self.fetch_size),
+            acquire_interval: Some(self.acquire_interval.as_secs_f64()),
+            flush_interval: Some(self.flush_interval.as_secs_f64()),
+            initial_spawn_delay: Some(self.initial_spawn_delay.as_secs_f64()),
+            update_rate: self.update_rate,
+            graph_data_size: self.graph_data_size.clone(),
+            max_cache_bytes: self.max_cache_bytes,
+            compress_cache: Some(self.compress_cache),
+            keep_fetched_cache: Some(self.keep_fetched_cache),
+            healthcheck_max_age: Some(self.healthcheck_max_age),
+            catch_acquire_panics: Some(self.catch_acquire_panics),
+            fetch_timeout: self.fetch_timeout.map(|timeout| timeout.as_secs_f64()),
+            acquire_timeout: self.acquire_timeout.map(|timeout| timeout.as_secs_f64()),
+            align_to_second: Some(self.align_to_second),
+            extra_caches: Some(self.extra_caches.clone()),
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        tmp.write_all(toml::to_string(&file)?.as_bytes())?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,6 +1193,8 @@ mod tests {
         config2.pidfile = PathBuf::new();
         config2.pidfile.push(&config2.plugin_statedir);
         config2.pidfile.push(String::from("Lala.pid"));
+        config2.last_fetch_file = config2.plugin_statedir.join("Lala.lastfetch");
+        config2.statefile = config2.plugin_statedir.join("Lala.state");
 
         let config3 = Config::new(String::from("Lala"));
         // At this point, the plugin_cache should be different
@@ -187,6 +1203,285 @@ mod tests {
         assert_eq!(config2, config3);
     }
 
+    #[test]
+    fn test_acquire_interval() {
+        let config = Config::new_daemon(String::from("slow"));
+        assert_eq!(config.acquire_interval, Duration::from_secs(1));
+        let config = Config {
+            acquire_interval: Duration::from_secs(2),
+            ..Config::new_daemon(String::from("slow"))
+        };
+        assert_eq!(config.acquire_interval, Duration::from_secs(2));
+        assert_eq!(config.clone(), config);
+    }
+
+    #[test]
+    fn test_working_directory() {
+        let config = Config::new_daemon(String::from("workdir"));
+        assert_eq!(config.working_directory, PathBuf::from("/tmp"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            working_directory: dir.path().to_path_buf(),
+            ..Config::new_daemon(String::from("workdir"))
+        };
+        assert_eq!(config.working_directory, dir.path());
+        assert!(config.check_working_directory().is_ok());
+
+        // Files are no directories
+        let file = NamedTempFile::new_in(dir.path()).unwrap();
+        config.working_directory = file.path().to_path_buf();
+        assert!(config.check_working_directory().is_err());
+        config.working_directory = dir.path().join("missing");
+        assert!(config.check_working_directory().is_err());
+    }
+
+    #[test]
+    fn test_env_capability() {
+        // Own variables, so other tests creating configs are not
+        // affected by changing the environment
+        env::set_var("MUNIN_CAP_TEST_ENABLED", "1");
+        env::set_var("MUNIN_CAP_TEST_DISABLED", "0");
+        assert!(Config::env_capability("MUNIN_CAP_TEST_ENABLED"));
+        assert!(!Config::env_capability("MUNIN_CAP_TEST_DISABLED"));
+        assert!(!Config::env_capability("MUNIN_CAP_TEST_MISSING"));
+        assert_eq!(
+            Config::new(String::from("multi")).multigraph,
+            Config::env_capability("MUNIN_CAP_MULTIGRAPH")
+        );
+    }
+
+    #[test]
+    fn test_env_var() {
+        env::set_var("munin_plugin_test_host", "localhost");
+        env::set_var("munin_plugin_test_empty", "");
+        env::set_var("munin_plugin_test_port", "4949");
+        env::set_var("munin_plugin_test_broken", "lala");
+
+        assert_eq!(
+            Config::env_var("munin_plugin_test_host"),
+            Some(String::from("localhost"))
+        );
+        assert_eq!(
+            Config::env_var("env.munin_plugin_test_host"),
+            Some(String::from("localhost"))
+        );
+        assert_eq!(Config::env_var("munin_plugin_test_empty"), None);
+        assert_eq!(Config::env_var("munin_plugin_test_missing"), None);
+
+        assert_eq!(
+            Config::env_var_or("env.munin_plugin_test_host", "remote"),
+            "localhost"
+        );
+        assert_eq!(
+            Config::env_var_or("env.munin_plugin_test_missing", "remote"),
+            "remote"
+        );
+
+        assert_eq!(
+            Config::env_parse::<u16>("env.munin_plugin_test_port").unwrap(),
+            Some(4949)
+        );
+        assert_eq!(
+            Config::env_parse::<u16>("env.munin_plugin_test_missing").unwrap(),
+            None
+        );
+        let err = Config::env_parse::<u16>("env.munin_plugin_test_broken")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Can not parse env.munin_plugin_test_broken value lala"));
+    }
+
+    #[test]
+    fn test_munin_environment() {
+        env::set_var("MUNIN_LIBDIR", "/usr/share/munin");
+        env::set_var("MUNIN_RRDCACHED_SOCK", "/run/munin/rrdcached.sock");
+        let config = Config::new(String::from("informed"));
+        env::remove_var("MUNIN_LIBDIR");
+        env::remove_var("MUNIN_RRDCACHED_SOCK");
+        assert_eq!(config.munin_libdir, Some(PathBuf::from("/usr/share/munin")));
+        assert_eq!(
+            config.rrdcached_sock,
+            Some(PathBuf::from("/run/munin/rrdcached.sock"))
+        );
+
+        let config = Config::new(String::from("uninformed"));
+        assert_eq!(config.munin_libdir, None);
+        assert_eq!(config.rrdcached_sock, None);
+    }
+
+    #[test]
+    fn test_dirtyconfig_enabled() {
+        let mut config = Config::new(String::from("dirty"));
+        config.dirtyconfig = true;
+        assert!(config.dirtyconfig_enabled());
+        config.dirtyconfig = false;
+        assert!(!config.dirtyconfig_enabled());
+
+        // Without auto the environment does not count
+        let config = Config::builder().dirtyconfig_auto(false).build();
+        assert!(!config.dirtyconfig_enabled());
+        let config = Config::builder().dirtyconfig_auto(true).build();
+        assert_eq!(config.dirtyconfig, Config::env_dirtyconfig());
+    }
+
+    #[test]
+    fn test_wildcard_suffix() {
+        let mut config = Config::new(String::from("if_"));
+        assert_eq!(config.wildcard_suffix("if_"), None);
+        config.invoked_as = String::from("if_eth0");
+        assert_eq!(config.wildcard_suffix("if_"), Some(String::from("eth0")));
+        assert_eq!(config.wildcard_suffix("if_err_"), None);
+        config.invoked_as = String::from("if_");
+        assert_eq!(config.wildcard_suffix("if_"), None);
+    }
+
+    #[test]
+    fn test_with_estimated_fields() {
+        let config = Config::new(String::from("estimate")).with_estimated_fields(500, 40);
+        assert!(config.config_size > 8192);
+        assert_eq!(config.config_size, 32768);
+        assert_eq!(config.fetch_size, 32768);
+        // Already a power of two stays
+        let config = config.with_estimated_fields(256, 64);
+        assert_eq!(config.fetch_size, 16384);
+        let config = config.with_estimated_fields(0, 40);
+        assert_eq!(config.fetch_size, 1);
+        let config = config.with_estimated_fields(usize::MAX, 2);
+        assert_eq!(config.fetch_size, usize::MAX);
+    }
+
+    #[test]
+    fn test_spawn_command() {
+        let mut config = Config::new_daemon(String::from("spawner"));
+        assert_eq!(
+            config.spawn_command("spawner"),
+            fs::canonicalize(env::current_exe().unwrap()).unwrap()
+        );
+        config.acquire_command = Some(PathBuf::from("/usr/lib/munin/spawner"));
+        assert_eq!(
+            config.spawn_command("spawner"),
+            PathBuf::from("/usr/lib/munin/spawner")
+        );
+        config.use_current_exe().unwrap();
+        assert_eq!(config.spawn_command("spawner"), env::current_exe().unwrap());
+    }
+
+    #[test]
+    fn test_last_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new(String::from("fetcher"));
+        config.last_fetch_file = dir.path().join("fetcher.lastfetch");
+        assert_eq!(config.last_fetch(), None);
+        config.record_fetch(1650000000).unwrap();
+        assert_eq!(config.last_fetch(), Some(1650000000));
+        config.record_fetch(1650000300).unwrap();
+        assert_eq!(config.last_fetch(), Some(1650000300));
+    }
+
+    #[test]
+    fn test_state() {
+        #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+        struct Reading {
+            value: u64,
+            epoch: u64,
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new(String::from("stateful"));
+        assert_eq!(config.statefile.file_name().unwrap(), "stateful.state");
+        config.statefile = dir.path().join("stateful.state");
+        assert_eq!(config.load_state::<Reading>(), None);
+
+        let reading = Reading {
+            value: 4711,
+            epoch: 1650000000,
+        };
+        config.save_state(&reading).unwrap();
+        assert_eq!(config.load_state(), Some(reading));
+
+        // Garbage is no state
+        fs::write(&config.statefile, "not json").unwrap();
+        assert_eq!(config.load_state::<Reading>(), None);
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn test_init_logging() {
+        // Installs the logger of the whole process, so give it one
+        // of its own
+        if env::var_os("MUNIN_PLUGIN_TEST_LOGGING").is_some() {
+            Config::init_logging();
+            // Second time there is a logger already
+            Config::init_logging();
+            assert!(log::log_enabled!(log::Level::Warn));
+            assert!(!log::log_enabled!(log::Level::Info));
+            return;
+        }
+        let output = std::process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "config::tests::test_init_logging"])
+            .env("MUNIN_PLUGIN_TEST_LOGGING", "1")
+            .env_remove("MUNIN_PLUGIN_LOG")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+    }
+
+    #[cfg(feature = "config-file")]
+    #[test]
+    fn test_toml_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plugin.toml");
+        let mut config = Config::new_daemon(String::from("tomled"));
+        config.fetch_size = 65536;
+        config.acquire_interval = Duration::from_millis(250);
+        config.update_rate = Some(1);
+        config.graph_data_size = Some(String::from("custom 1d"));
+        config.compress_cache = true;
+        config.fetch_timeout = Some(Duration::from_millis(1500));
+        config.acquire_timeout = Some(Duration::from_millis(800));
+        config
+            .extra_caches
+            .insert(String::from("disk"), dir.path().join("disk.value"));
+        config.to_toml_file(&path).unwrap();
+
+        let mut read = Config::from_toml_file(&path).unwrap();
+        // Not kept in the file
+        read.clock = config.clock.clone();
+        assert_eq!(read, config);
+
+        // The environment wins
+        env::set_var("MUNIN_CAP_DIRTYCONFIG", "1");
+        assert!(Config::from_toml_file(&path).unwrap().dirtyconfig);
+        env::remove_var("MUNIN_CAP_DIRTYCONFIG");
+
+        fs::write(&path, "plugin_name = \"tomled\"\nfetch_sise = 1\n").unwrap();
+        assert!(Config::from_toml_file(&path).is_err());
+        fs::write(&path, "acquire_interval = -1\n").unwrap();
+        assert!(Config::from_toml_file(&path).is_err());
+        assert!(Config::from_toml_file(dir.path().join("missing.toml")).is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("8192").unwrap(), 8192);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+        assert_eq!(parse_size("64KiB").unwrap(), 65536);
+        assert_eq!(parse_size("64kb").unwrap(), 64000);
+        assert_eq!(parse_size("1 MiB").unwrap(), 1048576);
+        assert_eq!(parse_size("8k").unwrap(), 8192);
+        assert_eq!(parse_size("1M").unwrap(), 1048576);
+        assert_eq!(parse_size("2GB").unwrap(), 2000000000);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("0KiB").is_err());
+        assert!(parse_size("-5KiB").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+        assert!(parse_size("KiB").is_err());
+        assert!(parse_size("").is_err());
+    }
+
     #[test]
     fn test_new_daemon() {
         let config = Config::new_daemon(String::from("great-plugin"));
@@ -197,4 +1492,51 @@ mod tests {
         );
         assert!(config.daemonize);
     }
+
+    #[test]
+    fn test_builder() {
+        // Same as new, if nothing else is set
+        let config = Config::builder().name(String::from("great-plugin")).build();
+        assert_eq!(config, Config::new(String::from("great-plugin")));
+
+        let config = Config::builder()
+            .plugin_cache(PathBuf::from("/tmp/custom.value"))
+            .name(String::from("great-plugin"))
+            .daemonize(true)
+            .fetch_size(16384)
+            .config_size(1024)
+            .build();
+        // Setting the name later does not clobber the custom cache
+        assert_eq!(config.plugin_cache, PathBuf::from("/tmp/custom.value"));
+        assert_eq!(
+            config.pidfile,
+            Config::get_statedir().join("great-plugin.pid")
+        );
+        assert!(config.daemonize);
+        assert_eq!(config.fetch_size, 16384);
+        assert_eq!(config.config_size, 1024);
+
+        let config = Config::builder()
+            .pidfile(PathBuf::from("/tmp/custom.pid"))
+            .name(String::from("first"))
+            .name(String::from("second"))
+            .build();
+        assert_eq!(config.plugin_name, "second");
+        assert_eq!(config.pidfile, PathBuf::from("/tmp/custom.pid"));
+
+        assert_eq!(
+            config.plugin_cache,
+            Config::get_statedir().join("munin.second.value")
+        );
+
+        let config = Config::builder()
+            .fetch_size_str("1M")
+            .unwrap()
+            .config_size_str("8k")
+            .unwrap()
+            .build();
+        assert_eq!(config.fetch_size, 1048576);
+        assert_eq!(config.config_size, 8192);
+        assert!(Config::builder().fetch_size_str("8 parsecs").is_err());
+    }
 }
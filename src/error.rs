@@ -0,0 +1,114 @@
+//! The error type of the plugin interface
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! [MuninPlugin::start](crate::MuninPlugin::start),
+//! [MuninPlugin::daemon](crate::MuninPlugin::daemon) and
+//! [MuninPlugin::fetch](crate::MuninPlugin::fetch) return a
+//! [PluginError], so callers can match on what failed, instead of
+//! looking at the text of an [anyhow::Error]. Everything else in this
+//! library (and what plugins implement) stays on [anyhow::Result],
+//! anyhow errors convert into [PluginError::Other].
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use std::{error::Error, fmt, io, time::Duration};
+
+/// What went wrong in a plugin
+#[derive(Debug)]
+pub enum PluginError {
+    /// Reading or writing a file (cachefile, pidfile, ...) or the
+    /// munin handle failed
+    Io(io::Error),
+    /// Detaching the acquire daemon failed
+    Daemonize(String),
+    /// The lock on the cachefile could not be taken
+    Lock(io::Error),
+    /// The plugin got called with arguments it does not know
+    InvalidArgument(String),
+    /// [MuninPlugin::acquire](crate::MuninPlugin::acquire) did not
+    /// finish in the given time, see [TimeoutPlugin](crate::TimeoutPlugin)
+    Timeout(Duration),
+    /// Anything else, like an error of [MuninPlugin::acquire](crate::MuninPlugin::acquire)
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::Io(e) => write!(f, "I/O error: {e}"),
+            PluginError::Daemonize(e) => write!(f, "Could not daemonize: {e}"),
+            PluginError::Lock(e) => write!(f, "Could not lock the cachefile: {e}"),
+            PluginError::InvalidArgument(args) => write!(f, "Unsupported arguments: {args}"),
+            PluginError::Timeout(timeout) => write!(f, "acquire took longer than {timeout:?}"),
+            PluginError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for PluginError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PluginError::Io(e) | PluginError::Lock(e) => Some(e),
+            PluginError::Other(e) => Some(e.as_ref()),
+            PluginError::Daemonize(_)
+            | PluginError::InvalidArgument(_)
+            | PluginError::Timeout(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for PluginError {
+    fn from(e: io::Error) -> Self {
+        PluginError::Io(e)
+    }
+}
+
+impl From<tempfile::PersistError> for PluginError {
+    fn from(e: tempfile::PersistError) -> Self {
+        PluginError::Io(e.error)
+    }
+}
+
+impl From<anyhow::Error> for PluginError {
+    /// Get back a [PluginError] (or [io::Error]) that went through
+    /// anyhow, everything else is [PluginError::Other].
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<PluginError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => PluginError::Io(e),
+            Err(e) => PluginError::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_from_anyhow() {
+        let e = anyhow::Error::from(PluginError::InvalidArgument(String::from("a b")));
+        let e = PluginError::from(e);
+        assert!(matches!(e, PluginError::InvalidArgument(ref args) if args == "a b"));
+        assert_eq!(e.to_string(), "Unsupported arguments: a b");
+
+        let e = anyhow::Error::from(PluginError::Timeout(Duration::from_secs(2)));
+        let e = PluginError::from(e);
+        assert!(matches!(e, PluginError::Timeout(_)));
+        assert_eq!(e.to_string(), "acquire took longer than 2s");
+
+        let e = anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(PluginError::from(e), PluginError::Io(_)));
+
+        let e = PluginError::from(anyhow!("acquire broke"));
+        assert!(matches!(e, PluginError::Other(_)));
+        assert_eq!(e.to_string(), "acquire broke");
+        assert!(e.source().is_some());
+    }
+}
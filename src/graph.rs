@@ -0,0 +1,1672 @@
+//! Describe a munin graph and write its config
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! Instead of writing every line of [MuninPlugin::config](super::MuninPlugin::config)
+//! by hand, build a [Graph] with its [Field]s and let
+//! [Graph::write_config] output it. Attribute names come from the
+//! builder methods, so they can no longer be misspelled.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use crate::value::{write_value, MuninValue, ObservedRange};
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    io::{BufWriter, Write},
+};
+
+/// Turn `raw` into a valid munin field name, matching
+/// `[A-Za-z_][A-Za-z0-9_]*`.
+///
+/// Every character not allowed gets replaced by `_`, and a name
+/// starting with a digit gets an `_` in front. Meant for field names
+/// derived from interface names, mount points and alike.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::sanitize_fieldname;
+/// assert_eq!(sanitize_fieldname("/dev/sda"), "_dev_sda");
+/// assert_eq!(sanitize_fieldname("2ndnic"), "_2ndnic");
+/// ```
+pub fn sanitize_fieldname(raw: &str) -> String {
+    let mut name: String = raw
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// How munin treats the values of a [Field], its `type`.
+///
+/// Displays as the (case-sensitive) munin keyword.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::FieldType;
+/// assert_eq!(FieldType::Derive.to_string(), "DERIVE");
+/// assert_eq!(FieldType::default(), FieldType::Gauge);
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum FieldType {
+    /// Value is stored as is, the munin default
+    #[default]
+    Gauge,
+    /// Ever increasing counter, munin graphs the rate and handles
+    /// overflows
+    Counter,
+    /// Like counter, but without overflow handling, for counters
+    /// that can be reset
+    Derive,
+    /// Counter that gets reset on every read
+    Absolute,
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keyword = match self {
+            FieldType::Gauge => "GAUGE",
+            FieldType::Counter => "COUNTER",
+            FieldType::Derive => "DERIVE",
+            FieldType::Absolute => "ABSOLUTE",
+        };
+        write!(f, "{keyword}")
+    }
+}
+
+/// A `warning` or `critical` threshold of a [Field], when munin
+/// should say something about a value.
+///
+/// Displays in munins range syntax, and converts into the [String]
+/// [Field::warning] and [Field::critical] take. Munin has no inverted
+/// ranges, a value inside of a range can not be warned about.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::{Field, Threshold};
+/// assert_eq!(Threshold::Above(10.0).to_string(), ":10");
+/// assert_eq!(Threshold::Outside(0.5, 10.0).to_string(), "0.5:10");
+/// let field = Field::new("load").warning(Threshold::Above(10.0));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    /// Values above the limit, `:max`
+    Above(f64),
+    /// Values below the limit, `min:`
+    Below(f64),
+    /// Values outside of the range (min, max), `min:max`
+    Outside(f64, f64),
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Threshold::Above(max) => write!(f, ":{max}"),
+            Threshold::Below(min) => write!(f, "{min}:"),
+            Threshold::Outside(min, max) => write!(f, "{min}:{max}"),
+        }
+    }
+}
+
+impl From<Threshold> for String {
+    fn from(threshold: Threshold) -> Self {
+        threshold.to_string()
+    }
+}
+
+/// Check if `range` is a range munin understands for `warning` and
+/// `critical`: `max`, `min:`, `:max` or `min:max`, with min not
+/// above max.
+fn valid_range(range: &str) -> bool {
+    let limit = |limit: &str| limit.is_empty() || limit.parse::<f64>().is_ok_and(f64::is_finite);
+    match range.split_once(':') {
+        None => !range.is_empty() && limit(range),
+        Some((min, max)) => {
+            limit(min)
+                && limit(max)
+                && (min.is_empty()
+                    || max.is_empty()
+                    || min.parse::<f64>().ok() <= max.parse::<f64>().ok())
+        }
+    }
+}
+
+/// A `graph_category` of a [Graph], munins well-known categories,
+/// so they can not be misspelled, plus [Category::Custom] for
+/// everything else.
+///
+/// Displays as the munin keyword, and converts into the [String]
+/// [Graph::category] and [Graph::categories] take.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::{Category, Graph};
+/// assert_eq!(Category::Network.to_string(), "network");
+/// assert_eq!(Category::OneSec.to_string(), "1sec");
+/// let graph = Graph::new("Load average").category(Category::System);
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Category {
+    /// Plugins with a 1 second resolution, `1sec`
+    OneSec,
+    /// `antivirus`
+    Antivirus,
+    /// Application servers, `appserver`
+    Appserver,
+    /// Authentication servers and services, `auth`
+    Auth,
+    /// `backup`
+    Backup,
+    /// Messaging servers, `chat`
+    Chat,
+    /// `cloud`
+    Cloud,
+    /// Content management systems, `cms`
+    Cms,
+    /// `cpu`
+    Cpu,
+    /// Databases, `db`
+    Db,
+    /// Development tools, `devel`
+    Devel,
+    /// Disks and other block devices, `disk`
+    Disk,
+    /// `dns`
+    Dns,
+    /// `filetransfer`
+    Filetransfer,
+    /// `forum`
+    Forum,
+    /// Filesystems, `fs`
+    Fs,
+    /// Firewalls, `fw`
+    Fw,
+    /// Game servers, `games`
+    Games,
+    /// High throughput computing, `htc`
+    Htc,
+    /// `loadbalancer`
+    Loadbalancer,
+    /// `mail`
+    Mail,
+    /// `mailinglist`
+    Mailinglist,
+    /// `memory`
+    Memory,
+    /// Munin itself, `munin`
+    Munin,
+    /// `network`
+    Network,
+    /// Whatever fits nowhere else, `other`
+    Other,
+    /// `printing`
+    Printing,
+    /// `processes`
+    Processes,
+    /// `radio`
+    Radio,
+    /// Storage area networks, `san`
+    San,
+    /// `search`
+    Search,
+    /// `security`
+    Security,
+    /// Temperature, fans, voltages and alike, `sensors`
+    Sensors,
+    /// `spamfilter`
+    Spamfilter,
+    /// `streaming`
+    Streaming,
+    /// General system state, like load, `system`
+    System,
+    /// `time`
+    Time,
+    /// `tv`
+    Tv,
+    /// `virtualization`
+    Virtualization,
+    /// `voip`
+    Voip,
+    /// `webserver`
+    Webserver,
+    /// `wiki`
+    Wiki,
+    /// `wireless`
+    Wireless,
+    /// Any other category, written as given
+    Custom(String),
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keyword = match self {
+            Category::OneSec => "1sec",
+            Category::Antivirus => "antivirus",
+            Category::Appserver => "appserver",
+            Category::Auth => "auth",
+            Category::Backup => "backup",
+            Category::Chat => "chat",
+            Category::Cloud => "cloud",
+            Category::Cms => "cms",
+            Category::Cpu => "cpu",
+            Category::Db => "db",
+            Category::Devel => "devel",
+            Category::Disk => "disk",
+            Category::Dns => "dns",
+            Category::Filetransfer => "filetransfer",
+            Category::Forum => "forum",
+            Category::Fs => "fs",
+            Category::Fw => "fw",
+            Category::Games => "games",
+            Category::Htc => "htc",
+            Category::Loadbalancer => "loadbalancer",
+            Category::Mail => "mail",
+            Category::Mailinglist => "mailinglist",
+            Category::Memory => "memory",
+            Category::Munin => "munin",
+            Category::Network => "network",
+            Category::Other => "other",
+            Category::Printing => "printing",
+            Category::Processes => "processes",
+            Category::Radio => "radio",
+            Category::San => "san",
+            Category::Search => "search",
+            Category::Security => "security",
+            Category::Sensors => "sensors",
+            Category::Spamfilter => "spamfilter",
+            Category::Streaming => "streaming",
+            Category::System => "system",
+            Category::Time => "time",
+            Category::Tv => "tv",
+            Category::Virtualization => "virtualization",
+            Category::Voip => "voip",
+            Category::Webserver => "webserver",
+            Category::Wiki => "wiki",
+            Category::Wireless => "wireless",
+            Category::Custom(category) => category,
+        };
+        write!(f, "{keyword}")
+    }
+}
+
+impl From<Category> for String {
+    fn from(category: Category) -> Self {
+        category.to_string()
+    }
+}
+
+/// The rrdtool options of a graph, the value of `graph_args`.
+///
+/// Only options that got set are written out, in a fixed order.
+/// Converts into a String, so it can be handed to [Graph::args].
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::GraphArgs;
+/// let args = GraphArgs::new().base(1000).lower_limit(0.0);
+/// assert_eq!(args.to_string(), "--base 1000 --lower-limit 0");
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GraphArgs {
+    base: Option<u32>,
+    lower_limit: Option<f64>,
+    upper_limit: Option<f64>,
+    logarithmic: bool,
+    rigid: bool,
+}
+
+impl GraphArgs {
+    /// No options set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `--base`, 1000 for most values, 1024 for sizes in memory
+    /// (so 1k is 1024 bytes). Traffic in bits or bytes per second
+    /// uses 1000.
+    pub fn base(mut self, base: u32) -> Self {
+        self.base = Some(base);
+        self
+    }
+
+    /// Set `--lower-limit`, where the vertical axis starts, unless a
+    /// value is lower (see [GraphArgs::rigid])
+    pub fn lower_limit(mut self, limit: f64) -> Self {
+        self.lower_limit = Some(limit);
+        self
+    }
+
+    /// Set `--upper-limit`, where the vertical axis ends, unless a
+    /// value is higher (see [GraphArgs::rigid])
+    pub fn upper_limit(mut self, limit: f64) -> Self {
+        self.upper_limit = Some(limit);
+        self
+    }
+
+    /// Set `--logarithmic`, for a logarithmic vertical axis
+    pub fn logarithmic(mut self, logarithmic: bool) -> Self {
+        self.logarithmic = logarithmic;
+        self
+    }
+
+    /// Set `--rigid`, to not extend the axis past the limits
+    pub fn rigid(mut self, rigid: bool) -> Self {
+        self.rigid = rigid;
+        self
+    }
+
+    /// Fix the vertical axis to the range from `lower` to `upper`,
+    /// setting both limits and [GraphArgs::rigid] at once. Meant for
+    /// bounded values like percentages, where a limit without
+    /// `--rigid` (or the other way round) is a common mistake.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::GraphArgs;
+    /// let args = GraphArgs::new().fixed_range(0.0, 100.0);
+    /// assert_eq!(args.to_string(), "--lower-limit 0 --upper-limit 100 --rigid");
+    /// ```
+    pub fn fixed_range(self, lower: f64, upper: f64) -> Self {
+        self.lower_limit(lower).upper_limit(upper).rigid(true)
+    }
+
+    /// Set the limits to the smallest and largest value in `range`,
+    /// for graphs whose range is not known in advance. Limits nothing
+    /// got observed for stay as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::GraphArgs;
+    /// # use munin_plugin::value::ObservedRange;
+    /// let mut range = ObservedRange::new();
+    /// range.observe(3);
+    /// range.observe(17);
+    /// let args = GraphArgs::new().observed(&range);
+    /// assert_eq!(args.to_string(), "--lower-limit 3 --upper-limit 17");
+    /// ```
+    pub fn observed(mut self, range: &ObservedRange) -> Self {
+        self.lower_limit = range.min().or(self.lower_limit);
+        self.upper_limit = range.max().or(self.upper_limit);
+        self
+    }
+}
+
+impl fmt::Display for GraphArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args = Vec::new();
+        if let Some(base) = self.base {
+            args.push(format!("--base {base}"));
+        }
+        if let Some(limit) = self.lower_limit {
+            args.push(format!("--lower-limit {limit}"));
+        }
+        if let Some(limit) = self.upper_limit {
+            args.push(format!("--upper-limit {limit}"));
+        }
+        if self.rigid {
+            args.push(String::from("--rigid"));
+        }
+        if self.logarithmic {
+            args.push(String::from("--logarithmic"));
+        }
+        write!(f, "{}", args.join(" "))
+    }
+}
+
+impl From<GraphArgs> for String {
+    fn from(args: GraphArgs) -> Self {
+        args.to_string()
+    }
+}
+
+/// The range [Field::clamp] keeps values in
+#[derive(Clone, Copy, Debug)]
+struct Clamp {
+    min: f64,
+    max: f64,
+}
+
+// Compared bitwise, so a Field can stay Eq and Hash
+impl PartialEq for Clamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.min.to_bits() == other.min.to_bits() && self.max.to_bits() == other.max.to_bits()
+    }
+}
+
+impl Eq for Clamp {}
+
+impl Hash for Clamp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.min.to_bits().hash(state);
+        self.max.to_bits().hash(state);
+    }
+}
+
+/// One field (data source) of a [Graph].
+///
+/// Only attributes that got set are written out.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::Field;
+/// let field = Field::new("load").label("load").warning("10").critical("120");
+/// ```
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Field {
+    name: String,
+    label: Option<String>,
+    type_: Option<FieldType>,
+    draw: Option<String>,
+    min: Option<String>,
+    max: Option<String>,
+    cdef: Option<String>,
+    warning: Option<String>,
+    critical: Option<String>,
+    info: Option<String>,
+    clamp: Option<Clamp>,
+}
+
+impl Field {
+    /// Create a field called `name`, the name used for its values.
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Name of the field
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set `label`, the name shown in the graph legend
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Set `type`, how munin treats the values
+    pub fn type_(mut self, type_: FieldType) -> Self {
+        self.type_ = Some(type_);
+        self
+    }
+
+    /// The type of the field, [FieldType::Gauge] if not set
+    pub fn field_type(&self) -> FieldType {
+        self.type_.unwrap_or_default()
+    }
+
+    /// Set `draw`, how the field is drawn, like `LINE1` or `AREASTACK`
+    pub fn draw<S: Into<String>>(mut self, draw: S) -> Self {
+        self.draw = Some(draw.into());
+        self
+    }
+
+    /// Set `min`, values below are unknown
+    pub fn min<S: Into<String>>(mut self, min: S) -> Self {
+        self.min = Some(min.into());
+        self
+    }
+
+    /// Set `max`, values above are unknown
+    pub fn max<S: Into<String>>(mut self, max: S) -> Self {
+        self.max = Some(max.into());
+        self
+    }
+
+    /// Set `cdef`, a RPN expression to calculate the value drawn
+    pub fn cdef<S: Into<String>>(mut self, cdef: S) -> Self {
+        self.cdef = Some(cdef.into());
+        self
+    }
+
+    /// Set a `cdef` drawing the value multiplied by `factor`, like 8
+    /// to store bytes and draw bits. The stored data stays as it is,
+    /// only the graph shows it converted. Needs the name of the
+    /// field, so set it after [Field::new], replacing any
+    /// [Field::cdef].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::Field;
+    /// # use std::io::BufWriter;
+    /// let field = Field::new("rx").label("received").cdef_scale(8.0);
+    /// let mut handle = BufWriter::new(Vec::new());
+    /// field.write_config(&mut handle).unwrap();
+    /// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    /// assert_eq!(output, "rx.label received\nrx.cdef rx,8,*\n");
+    /// ```
+    pub fn cdef_scale(self, factor: f64) -> Self {
+        let cdef = format!("{},{factor},*", self.name);
+        self.cdef(cdef)
+    }
+
+    /// Set `warning`, the range (like `10` or `5:10`) outside of
+    /// which munin warns, or a [Threshold]
+    pub fn warning<S: Into<String>>(mut self, warning: S) -> Self {
+        self.warning = Some(warning.into());
+        self
+    }
+
+    /// Set `critical`, the range outside of which munin alerts, or a
+    /// [Threshold]
+    pub fn critical<S: Into<String>>(mut self, critical: S) -> Self {
+        self.critical = Some(critical.into());
+        self
+    }
+
+    /// Set `info`, a longer description of the field
+    pub fn info<S: Into<String>>(mut self, info: S) -> Self {
+        self.info = Some(info.into());
+        self
+    }
+
+    /// Keep the values written with [Field::write_value] between
+    /// `min` and `max`, warning whenever one got clamped.
+    ///
+    /// Meant for sensors that now and then report impossible values,
+    /// which would blow up the scale of the graph. Unlike
+    /// [Field::min] and [Field::max], this is not written to the
+    /// config, the values are changed before munin sees them.
+    ///
+    /// Should `min` be larger than `max`, the two get swapped, with a
+    /// warning.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::Field;
+    /// # use std::io::BufWriter;
+    /// let field = Field::new("temp").label("temperature").clamp(-40.0, 125.0);
+    /// let mut handle = BufWriter::new(Vec::new());
+    /// field.write_value(&mut handle, -200.0, None).unwrap();
+    /// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    /// assert_eq!(output, "temp.value -40\n");
+    /// ```
+    pub fn clamp(mut self, min: f64, max: f64) -> Self {
+        let (min, max) = if min > max {
+            warn!(
+                "Field {} clamped to {min} up to {max}, swapping the two",
+                self.name
+            );
+            (max, min)
+        } else {
+            (min, max)
+        };
+        self.clamp = Some(Clamp { min, max });
+        self
+    }
+
+    /// Write a value of this field, see [write_value], clamped to
+    /// the range of [Field::clamp] if set.
+    pub fn write_value<W: Write, V: Into<MuninValue>>(
+        &self,
+        handle: &mut BufWriter<W>,
+        value: V,
+        epoch: Option<u64>,
+    ) -> Result<()> {
+        let value = value.into();
+        let value = match self.clamped(value) {
+            Some(clamped) => {
+                warn!(
+                    "Value {value} of field {} out of range, using {clamped}",
+                    self.name
+                );
+                clamped
+            }
+            None => value,
+        };
+        write_value(handle, &self.name, value, epoch)
+    }
+
+    /// `value` moved into the range of [Field::clamp], None if it
+    /// needs no change. Unknown values stay unknown.
+    fn clamped(&self, value: MuninValue) -> Option<MuninValue> {
+        let clamp = self.clamp?;
+        let (number, precision) = match value {
+            MuninValue::Integer(value) => (value as f64, None),
+            MuninValue::Unsigned(value) => (value as f64, None),
+            MuninValue::Float(value) if value.is_finite() => (value, None),
+            MuninValue::Decimal(value, precision) if value.is_finite() => (value, Some(precision)),
+            MuninValue::Float(_) | MuninValue::Decimal(..) | MuninValue::Unknown => return None,
+        };
+        let limit = if number < clamp.min {
+            clamp.min
+        } else if number > clamp.max {
+            clamp.max
+        } else {
+            return None;
+        };
+        // Keep the decimals, if the value had fixed ones
+        Some(match precision {
+            Some(precision) => MuninValue::Decimal(limit, precision),
+            None => MuninValue::Float(limit),
+        })
+    }
+
+    /// Write the config lines of this field. Without a
+    /// [Field::label], the name is used as label, munin does not
+    /// draw a field it has no attribute for.
+    pub fn write_config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        let label = Some(self.label.clone().unwrap_or_else(|| self.name.clone()));
+        let type_ = self.type_.map(|type_| type_.to_string());
+        let attributes = [
+            ("label", &label),
+            ("type", &type_),
+            ("draw", &self.draw),
+            ("min", &self.min),
+            ("max", &self.max),
+            ("cdef", &self.cdef),
+            ("warning", &self.warning),
+            ("critical", &self.critical),
+            ("info", &self.info),
+        ];
+        for (attribute, value) in attributes {
+            if let Some(value) = value {
+                writeln!(handle, "{}.{} {}", self.name, attribute, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A munin graph, its global attributes and the [Field]s in it.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::{Field, Graph};
+/// # use std::io::BufWriter;
+/// let graph = Graph::new("Load average")
+///     .args("--base 1000 -l 0")
+///     .vlabel("load")
+///     .category("system")
+///     .field(Field::new("load").label("load").warning("10"));
+///
+/// let mut handle = BufWriter::new(Vec::new());
+/// graph.write_config(&mut handle).unwrap();
+/// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+/// assert!(output.starts_with("graph_title Load average\n"));
+/// assert!(output.ends_with("load.warning 10\n"));
+/// ```
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Graph {
+    title: String,
+    args: Option<String>,
+    vlabel: Option<String>,
+    scale: Option<bool>,
+    categories: Vec<String>,
+    info: Option<String>,
+    data_size: Option<String>,
+    order: Option<String>,
+    fields: Vec<Field>,
+    strict: bool,
+}
+
+impl Graph {
+    /// Create a graph with the given `graph_title`
+    pub fn new<T: Into<String>>(title: T) -> Self {
+        Self {
+            title: title.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Create a graph with the defaults most system metrics share:
+    /// no scaling of values, `--base 1000` and category
+    /// [Category::System]. Every one of them can be changed after,
+    /// with the usual methods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::{Category, Field, Graph, GraphArgs};
+    /// let graph = Graph::system_preset("Load average").field(Field::new("load"));
+    /// assert_eq!(
+    ///     graph,
+    ///     Graph::new("Load average")
+    ///         .args(GraphArgs::new().base(1000))
+    ///         .scale(false)
+    ///         .category(Category::System)
+    ///         .field(Field::new("load"))
+    /// );
+    /// ```
+    pub fn system_preset<T: Into<String>>(title: T) -> Self {
+        Self::new(title)
+            .args(GraphArgs::new().base(1000))
+            .scale(false)
+            .category(Category::System)
+    }
+
+    /// Set `graph_args`, arguments for rrdtool graph, preferably
+    /// built with [GraphArgs]
+    pub fn args<S: Into<String>>(mut self, args: S) -> Self {
+        self.args = Some(args.into());
+        self
+    }
+
+    /// Set `graph_vlabel`, the label of the vertical axis
+    pub fn vlabel<S: Into<String>>(mut self, vlabel: S) -> Self {
+        self.vlabel = Some(vlabel.into());
+        self
+    }
+
+    /// Set `graph_scale`, if values get scaled with units like k or M
+    pub fn scale(mut self, scale: bool) -> Self {
+        self.scale = Some(scale);
+        self
+    }
+
+    /// Set `graph_category`, the category the graph is listed in,
+    /// preferably a [Category]
+    pub fn category<S: Into<String>>(mut self, category: S) -> Self {
+        self.categories = vec![category.into()];
+        self
+    }
+
+    /// Set a number of categories the graph is listed in, for munin
+    /// versions that support more than one. Each gets its own
+    /// `graph_category` line, older munin versions only keep the
+    /// last, so list the main category last.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::{Category, Graph};
+    /// let graph = Graph::new("Mail queue").categories([Category::Processes, Category::Mail]);
+    /// ```
+    pub fn categories<I, S>(mut self, categories: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.categories = categories.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set `graph_info`, a longer description of the graph
+    pub fn info<S: Into<String>>(mut self, info: S) -> Self {
+        self.info = Some(info.into());
+        self
+    }
+
+    /// Set `graph_data_size`, how much data munin keeps, preferably
+    /// a [DataSize](crate::streaming::DataSize)
+    pub fn data_size<S: Into<String>>(mut self, data_size: S) -> Self {
+        self.data_size = Some(data_size.into());
+        self
+    }
+
+    /// Set `graph_order`, the order fields are drawn (and stacked) in.
+    /// Without it, a graph with more than one field gets a
+    /// `graph_order` listing them in the order they got added.
+    pub fn order<S: Into<String>>(mut self, order: S) -> Self {
+        self.order = Some(order.into());
+        self
+    }
+
+    /// Make [Graph::write_config] fail when [Graph::check] finds a
+    /// problem, instead of only warning about it. Meant for tests and
+    /// CI, so an incomplete config does not get deployed.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Add a field, fields are written in the order added
+    pub fn field(mut self, field: Field) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    /// The fields of the graph
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// The first of the `values` naming a field the graph does not have
+    fn unknown_field<'v>(&self, values: &[(&'v str, MuninValue)]) -> Option<&'v str> {
+        values
+            .iter()
+            .map(|(field, _)| *field)
+            .find(|field| !self.fields.iter().any(|f| f.name() == *field))
+    }
+
+    /// Write `values`, see [Field::write_value], in the order the
+    /// fields got added. That is the order [Graph::write_config]
+    /// declares them in, whatever order `values` has, so config and
+    /// values always match. Fails, without writing anything, if the
+    /// graph lacks one of the fields.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::{Field, Graph};
+    /// # use std::io::BufWriter;
+    /// let graph = Graph::new("Traffic").field(Field::new("rx")).field(Field::new("tx"));
+    /// let mut handle = BufWriter::new(Vec::new());
+    /// graph.write_values(&mut handle, &[("tx", 2.into()), ("rx", 1.into())], None).unwrap();
+    /// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    /// assert_eq!(output, "rx.value 1\ntx.value 2\n");
+    /// ```
+    pub fn write_values<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        values: &[(&str, MuninValue)],
+        epoch: Option<u64>,
+    ) -> Result<()> {
+        if let Some(field) = self.unknown_field(values) {
+            return Err(anyhow!("No field {field} in graph {}", self.title));
+        }
+        for field in &self.fields {
+            for (_, value) in values.iter().filter(|(name, _)| *name == field.name()) {
+                field.write_value(handle, *value, epoch)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything that looks like a mistake in the graph, an empty
+    /// list if nothing does. [Graph::write_config] warns about each,
+    /// or fails in [Graph::strict] mode.
+    ///
+    /// Checked are that the graph has a title and at least one
+    /// field, a graph without is almost always a bug. Then the
+    /// alerting thresholds: a field with a
+    /// `critical` but no `warning` (munin will only ever alert, not
+    /// warn first), and thresholds that are no valid range. As the
+    /// thresholds are set on the [Field], they can not refer to a
+    /// field that does not exist. Also checked are the categories,
+    /// a [Category::Custom] must be one lowercase word, and none may
+    /// be listed twice.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::{Field, Graph};
+    /// let graph = Graph::new("Load").field(Field::new("load").critical("120"));
+    /// assert_eq!(graph.check(), ["Field load has a critical, but no warning threshold"]);
+    /// ```
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.title.trim().is_empty() {
+            problems.push(String::from("No graph_title"));
+        }
+        if self.fields.is_empty() {
+            problems.push(String::from("No fields"));
+        }
+        for (num, category) in self.categories.iter().enumerate() {
+            if category.is_empty()
+                || !category
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            {
+                problems.push(format!("Invalid category {category:?}"));
+            } else if self.categories[..num].contains(category) {
+                problems.push(format!("Category {category} listed twice"));
+            }
+        }
+        for field in &self.fields {
+            if field.critical.is_some() && field.warning.is_none() {
+                problems.push(format!(
+                    "Field {} has a critical, but no warning threshold",
+                    field.name
+                ));
+            }
+            for (threshold, range) in [("warning", &field.warning), ("critical", &field.critical)] {
+                if let Some(range) = range {
+                    if !valid_range(range) {
+                        problems.push(format!(
+                            "Field {} has an invalid {threshold} threshold {range}",
+                            field.name
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    /// Write the munin config of the graph and all its fields, as
+    /// [MuninPlugin::config](super::MuninPlugin::config) expects.
+    /// Fails without writing anything if the graph is
+    /// [Graph::strict] and [Graph::check] finds a problem.
+    pub fn write_config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        let problems = self.check();
+        if self.strict && !problems.is_empty() {
+            return Err(anyhow!("Graph {}: {}", self.title, problems.join(", ")));
+        }
+        for problem in problems {
+            warn!("Graph {}: {problem}", self.title);
+        }
+        writeln!(handle, "graph_title {}", self.title)?;
+        if let Some(args) = &self.args {
+            writeln!(handle, "graph_args {args}")?;
+        }
+        if let Some(vlabel) = &self.vlabel {
+            writeln!(handle, "graph_vlabel {vlabel}")?;
+        }
+        if let Some(scale) = self.scale {
+            writeln!(handle, "graph_scale {}", if scale { "yes" } else { "no" })?;
+        }
+        for category in &self.categories {
+            writeln!(handle, "graph_category {category}")?;
+        }
+        if let Some(info) = &self.info {
+            writeln!(handle, "graph_info {info}")?;
+        }
+        if let Some(data_size) = &self.data_size {
+            writeln!(handle, "graph_data_size {data_size}")?;
+        }
+        if let Some(order) = &self.order {
+            writeln!(handle, "graph_order {order}")?;
+        } else if self.fields.len() > 1 {
+            let names: Vec<&str> = self.fields.iter().map(Field::name).collect();
+            writeln!(handle, "graph_order {}", names.join(" "))?;
+        }
+        for field in &self.fields {
+            field.write_config(handle)?;
+        }
+        Ok(())
+    }
+}
+
+/// A multigraph plugin, a number of named [Graph]s.
+///
+/// Writes the `multigraph NAME` line before every graph, in the
+/// config as well as when writing values, where it ensures the
+/// graph and all fields exist.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::graph::{Field, Graph, MultiGraph};
+/// # use std::io::BufWriter;
+/// let multigraph = MultiGraph::new()
+///     .graph("if_eth0", Graph::new("eth0 traffic").field(Field::new("rx").label("rx")))
+///     .graph("if_eth1", Graph::new("eth1 traffic").field(Field::new("rx").label("rx")));
+///
+/// let mut handle = BufWriter::new(Vec::new());
+/// multigraph.write_config(&mut handle).unwrap();
+/// multigraph.write_values(&mut handle, "if_eth1", &[("rx", 42.into())], None).unwrap();
+/// // Not defined, so an error
+/// assert!(multigraph.write_values(&mut handle, "if_eth2", &[("rx", 42.into())], None).is_err());
+/// ```
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct MultiGraph {
+    graphs: Vec<(String, Graph)>,
+    order: Vec<String>,
+}
+
+impl MultiGraph {
+    /// Create a multigraph without any graphs
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `graph` named `name`, graphs are written in the order
+    /// added, unless a [MultiGraph::order] is set
+    pub fn graph<N: Into<String>>(mut self, name: N, graph: Graph) -> Self {
+        self.graphs.push((name.into(), graph));
+        self
+    }
+
+    /// Set the order the graphs are written in, by name, which is
+    /// the order munin shows them in. Graphs not listed follow in the
+    /// order they got added. Naming a graph that does not exist, or
+    /// one twice, makes [MultiGraph::write_config] fail.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::graph::{Field, Graph, MultiGraph};
+    /// # use std::io::BufWriter;
+    /// let multigraph = MultiGraph::new()
+    ///     .graph("if_eth1", Graph::new("eth1 traffic").field(Field::new("rx")))
+    ///     .graph("if_eth0", Graph::new("eth0 traffic").field(Field::new("rx")))
+    ///     .order(["if_eth0", "if_eth1"]);
+    ///
+    /// let mut handle = BufWriter::new(Vec::new());
+    /// multigraph.write_config(&mut handle).unwrap();
+    /// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    /// assert!(output.starts_with("multigraph if_eth0\n"));
+    /// ```
+    pub fn order<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.order = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The graphs in the order they get written, see [MultiGraph::order]
+    fn ordered(&self) -> Result<Vec<&(String, Graph)>> {
+        let mut ordered: Vec<&(String, Graph)> = Vec::with_capacity(self.graphs.len());
+        for name in &self.order {
+            let graph = self
+                .graphs
+                .iter()
+                .find(|(graphname, _)| graphname == name)
+                .ok_or_else(|| anyhow!("No graph {name} in multigraph, but in its order"))?;
+            if ordered.iter().any(|(graphname, _)| graphname == name) {
+                return Err(anyhow!("Graph {name} listed twice in multigraph order"));
+            }
+            ordered.push(graph);
+        }
+        for graph in &self.graphs {
+            if !self.order.contains(&graph.0) {
+                ordered.push(graph);
+            }
+        }
+        Ok(ordered)
+    }
+
+    /// The graph named `name`
+    pub fn get(&self, name: &str) -> Option<&Graph> {
+        self.graphs
+            .iter()
+            .find(|(graphname, _)| graphname == name)
+            .map(|(_, graph)| graph)
+    }
+
+    /// Write the config of all graphs, each after its `multigraph`
+    /// line, in the order of [MultiGraph::order]
+    pub fn write_config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        for (name, graph) in self.ordered()? {
+            writeln!(handle, "multigraph {name}")?;
+            graph.write_config(handle)?;
+        }
+        Ok(())
+    }
+
+    /// Write the `multigraph` line for graph `name`, before writing
+    /// its values. Fails if there is no such graph.
+    pub fn write_header<W: Write>(&self, handle: &mut BufWriter<W>, name: &str) -> Result<()> {
+        if self.get(name).is_none() {
+            return Err(anyhow!("No graph {name} in multigraph"));
+        }
+        writeln!(handle, "multigraph {name}")?;
+        Ok(())
+    }
+
+    /// Write the `multigraph` line for graph `name`, followed by the
+    /// `values`, see [Graph::write_values]. Fails, without writing anything,
+    /// if there is no such graph or it lacks one of the fields.
+    pub fn write_values<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        name: &str,
+        values: &[(&str, MuninValue)],
+        epoch: Option<u64>,
+    ) -> Result<()> {
+        let graph = self
+            .get(name)
+            .ok_or_else(|| anyhow!("No graph {name} in multigraph"))?;
+        if let Some(field) = graph.unknown_field(values) {
+            return Err(anyhow!("No field {field} in graph {name}"));
+        }
+        self.write_header(handle, name)?;
+        graph.write_values(handle, values, epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_config() {
+        let graph = Graph::new("Network traffic")
+            .args("--base 1000")
+            .vlabel("bits in (-) / out (+) per ${graph_period}")
+            .scale(true)
+            .category(Category::Network)
+            .info("Traffic of eth0")
+            .field(
+                Field::new("down")
+                    .label("received")
+                    .type_(FieldType::Derive)
+                    .draw("LINE1")
+                    .min("0")
+                    .cdef("down,8,*"),
+            )
+            .field(
+                Field::new("up")
+                    .label("bps")
+                    .type_(FieldType::Derive)
+                    .min("0")
+                    .max("1000000000")
+                    .warning("800000000")
+                    .critical("900000000")
+                    .info("Traffic sent"),
+            );
+        assert_eq!(graph.fields().len(), 2);
+        assert_eq!(graph.fields()[1].name(), "up");
+
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        let expected = [
+            "graph_title Network traffic",
+            "graph_args --base 1000",
+            "graph_vlabel bits in (-) / out (+) per ${graph_period}",
+            "graph_scale yes",
+            "graph_category network",
+            "graph_info Traffic of eth0",
+            "graph_order down up",
+            "down.label received",
+            "down.type DERIVE",
+            "down.draw LINE1",
+            "down.min 0",
+            "down.cdef down,8,*",
+            "up.label bps",
+            "up.type DERIVE",
+            "up.min 0",
+            "up.max 1000000000",
+            "up.warning 800000000",
+            "up.critical 900000000",
+            "up.info Traffic sent",
+        ];
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+        assert!(crate::testing::lint_output(&output).is_empty());
+    }
+
+    #[test]
+    fn test_cdef_scale() {
+        assert_eq!(
+            Field::new("rx").cdef_scale(8.0),
+            Field::new("rx").cdef("rx,8,*")
+        );
+        assert_eq!(
+            Field::new("mem").cdef("mem,2,/").cdef_scale(0.001),
+            Field::new("mem").cdef("mem,0.001,*")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_fieldname() {
+        assert_eq!(sanitize_fieldname("eth0"), "eth0");
+        assert_eq!(sanitize_fieldname("eth0.1"), "eth0_1");
+        assert_eq!(sanitize_fieldname("/dev/sda"), "_dev_sda");
+        assert_eq!(sanitize_fieldname("2ndnic"), "_2ndnic");
+        assert_eq!(sanitize_fieldname("br-lan:0"), "br_lan_0");
+        assert_eq!(sanitize_fieldname("_ok_"), "_ok_");
+        // Not ASCII, not allowed
+        assert_eq!(sanitize_fieldname("wlän"), "wl_n");
+        assert_eq!(sanitize_fieldname(""), "_");
+    }
+
+    #[test]
+    fn test_field_type() {
+        // munin is case-sensitive here
+        assert_eq!(FieldType::Gauge.to_string(), "GAUGE");
+        assert_eq!(FieldType::Counter.to_string(), "COUNTER");
+        assert_eq!(FieldType::Derive.to_string(), "DERIVE");
+        assert_eq!(FieldType::Absolute.to_string(), "ABSOLUTE");
+
+        assert_eq!(Field::new("x").field_type(), FieldType::Gauge);
+        let field = Field::new("x").type_(FieldType::Counter);
+        assert_eq!(field.field_type(), FieldType::Counter);
+    }
+
+    #[test]
+    fn test_threshold() {
+        assert_eq!(Threshold::Above(10.0).to_string(), ":10");
+        assert_eq!(Threshold::Below(-2.5).to_string(), "-2.5:");
+        assert_eq!(Threshold::Outside(5.0, 10.0).to_string(), "5:10");
+
+        let field = Field::new("temp")
+            .warning(Threshold::Outside(10.0, 60.0))
+            .critical(Threshold::Above(80.0));
+        let mut handle = BufWriter::new(Vec::new());
+        field.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "temp.label temp\ntemp.warning 10:60\ntemp.critical :80\n"
+        );
+    }
+
+    #[test]
+    fn test_category() {
+        assert_eq!(Category::Network.to_string(), "network");
+        assert_eq!(Category::OneSec.to_string(), "1sec");
+        assert_eq!(Category::Virtualization.to_string(), "virtualization");
+        assert_eq!(Category::Custom(String::from("lab")).to_string(), "lab");
+
+        let graph = Graph::new("Queue")
+            .categories([
+                Category::Processes,
+                Category::Custom(String::from("lab")),
+                Category::Mail,
+            ])
+            .field(Field::new("mails"));
+        assert!(graph.check().is_empty());
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "graph_title Queue\ngraph_category processes\ngraph_category lab\ngraph_category mail\nmails.label mails\n"
+        );
+        // The single one replaces them all
+        assert_eq!(
+            graph.clone().category(Category::Mail),
+            graph.categories([Category::Mail])
+        );
+
+        let graph = Graph::new("Bad").categories(["mail", "My Lab", "", "mail"]);
+        assert_eq!(
+            graph.check(),
+            [
+                "No fields",
+                "Invalid category \"My Lab\"",
+                "Invalid category \"\"",
+                "Category mail listed twice"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_minimal() {
+        let graph = Graph::new("Minimal").scale(false).field(Field::new("x"));
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "graph_title Minimal\ngraph_scale no\nx.label x\n");
+    }
+
+    #[test]
+    fn test_graph_order() {
+        let graph = Graph::new("Order")
+            .field(Field::new("a"))
+            .field(Field::new("b"))
+            .field(Field::new("c"));
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "graph_title Order\ngraph_order a b c\na.label a\nb.label b\nc.label c\n"
+        );
+
+        // An explicit order wins
+        let graph = graph.order("c b a");
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "graph_title Order\ngraph_order c b a\na.label a\nb.label b\nc.label c\n"
+        );
+    }
+
+    #[test]
+    fn test_system_preset() {
+        let graph = Graph::system_preset("Uptime")
+            .category(Category::Time)
+            .field(Field::new("uptime"));
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        let expected = [
+            "graph_title Uptime",
+            "graph_args --base 1000",
+            "graph_scale no",
+            "graph_category time",
+            "uptime.label uptime",
+        ];
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_graph_args() {
+        let args = GraphArgs::new()
+            .base(1024)
+            .lower_limit(0.0)
+            .upper_limit(100.5)
+            .rigid(true)
+            .logarithmic(true);
+        assert_eq!(
+            args.to_string(),
+            "--base 1024 --lower-limit 0 --upper-limit 100.5 --rigid --logarithmic"
+        );
+        assert_eq!(GraphArgs::new().to_string(), "");
+        assert_eq!(
+            GraphArgs::new()
+                .lower_limit(0.0)
+                .observed(&ObservedRange::new()),
+            GraphArgs::new().lower_limit(0.0)
+        );
+        assert_eq!(
+            GraphArgs::new().base(1000).fixed_range(0.0, 100.0),
+            GraphArgs::new()
+                .base(1000)
+                .lower_limit(0.0)
+                .upper_limit(100.0)
+                .rigid(true)
+        );
+
+        let graph = Graph::new("Memory").args(GraphArgs::new().base(1024).lower_limit(0.0));
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "graph_title Memory\ngraph_args --base 1024 --lower-limit 0\n"
+        );
+    }
+
+    #[test]
+    fn test_data_size() {
+        use crate::streaming::DataSize;
+        use std::time::Duration;
+
+        let graph = Graph::new("Fast").data_size(
+            DataSize::new(Duration::from_secs(86400))
+                .tier(Duration::from_secs(60), Duration::from_secs(604800)),
+        );
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "graph_title Fast\ngraph_data_size custom 1d, 1m for 1w\n"
+        );
+    }
+
+    #[test]
+    fn test_clamp() {
+        let field = Field::new("temp").clamp(-40.0, 125.0);
+        assert_eq!(field.clamped(200.into()), Some(MuninValue::Float(125.0)));
+        assert_eq!(
+            field.clamped((-200.5).into()),
+            Some(MuninValue::Float(-40.0))
+        );
+        assert_eq!(field.clamped(20.into()), None);
+        assert_eq!(
+            field.clamped(MuninValue::decimal(130.0, 1)),
+            Some(MuninValue::Decimal(125.0, 1))
+        );
+        assert_eq!(field.clamped(MuninValue::Unknown), None);
+        assert_eq!(Field::new("temp").clamped(200.into()), None);
+        assert_eq!(field, Field::new("temp").clamp(-40.0, 125.0));
+
+        // The wrong way around
+        let warnings = crate::testing::warnings_of(|| {
+            assert_eq!(Field::new("temp").clamp(125.0, -40.0), field);
+        });
+        assert_eq!(
+            warnings,
+            vec!["Field temp clamped to 125 up to -40, swapping the two"]
+        );
+
+        let mut handle = BufWriter::new(Vec::new());
+        let warnings = crate::testing::warnings_of(|| {
+            field
+                .write_value(&mut handle, 200, Some(1650000000))
+                .unwrap();
+        });
+        assert_eq!(
+            warnings,
+            ["Value 200 of field temp out of range, using 125"]
+        );
+        let warnings = crate::testing::warnings_of(|| {
+            field
+                .write_value(&mut handle, 21.5, Some(1650000001))
+                .unwrap();
+        });
+        assert!(warnings.is_empty());
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "temp.value 1650000000:125\ntemp.value 1650000001:21.5\n"
+        );
+        // Clamping is no config
+        let mut handle = BufWriter::new(Vec::new());
+        field.write_config(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "temp.label temp\n");
+    }
+
+    #[test]
+    fn test_multigraph() {
+        let multigraph = MultiGraph::new()
+            .graph(
+                "disk_sda",
+                Graph::new("sda").field(Field::new("read").label("read")),
+            )
+            .graph(
+                "disk_sdb",
+                Graph::new("sdb")
+                    .field(Field::new("read").label("read"))
+                    .field(Field::new("write").label("write")),
+            );
+
+        let mut handle = BufWriter::new(Vec::new());
+        multigraph.write_config(&mut handle).unwrap();
+        multigraph
+            .write_values(
+                &mut handle,
+                "disk_sda",
+                &[("read", 1.into())],
+                Some(1650000000),
+            )
+            .unwrap();
+        multigraph
+            .write_values(
+                &mut handle,
+                "disk_sdb",
+                &[("read", 2.into()), ("write", 3.into())],
+                Some(1650000000),
+            )
+            .unwrap();
+        // Unknown graph or field
+        assert!(multigraph
+            .write_values(&mut handle, "disk_sdc", &[("read", 1.into())], None)
+            .is_err());
+        assert!(multigraph
+            .write_values(&mut handle, "disk_sda", &[("write", 1.into())], None)
+            .is_err());
+        assert!(multigraph.write_header(&mut handle, "disk_sdc").is_err());
+
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        let expected = [
+            "multigraph disk_sda",
+            "graph_title sda",
+            "read.label read",
+            "multigraph disk_sdb",
+            "graph_title sdb",
+            "graph_order read write",
+            "read.label read",
+            "write.label write",
+            "multigraph disk_sda",
+            "read.value 1650000000:1",
+            "multigraph disk_sdb",
+            "read.value 1650000000:2",
+            "write.value 1650000000:3",
+        ];
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+        assert!(crate::testing::lint_output(&output).is_empty());
+    }
+
+    #[test]
+    fn test_multigraph_order() {
+        let multigraph = MultiGraph::new()
+            .graph("b", Graph::new("B").field(Field::new("x")))
+            .graph("a", Graph::new("A").field(Field::new("x")))
+            .graph("c", Graph::new("C").field(Field::new("x")))
+            .graph("d", Graph::new("D").field(Field::new("x")));
+        let sections = |multigraph: &MultiGraph| {
+            let mut handle = BufWriter::new(Vec::new());
+            multigraph.write_config(&mut handle).unwrap();
+            String::from_utf8(handle.into_inner().unwrap())
+                .unwrap()
+                .lines()
+                .filter(|line| line.starts_with("multigraph "))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        // Order added without an explicit one
+        assert_eq!(
+            sections(&multigraph),
+            [
+                "multigraph b",
+                "multigraph a",
+                "multigraph c",
+                "multigraph d"
+            ]
+        );
+        // Declared order first, the rest as added
+        let multigraph = multigraph.order(["c", "a"]);
+        assert_eq!(
+            sections(&multigraph),
+            [
+                "multigraph c",
+                "multigraph a",
+                "multigraph b",
+                "multigraph d"
+            ]
+        );
+        // Same every time
+        assert_eq!(sections(&multigraph), sections(&multigraph.clone()));
+
+        let mut handle = BufWriter::new(Vec::new());
+        assert!(multigraph
+            .clone()
+            .order(["a", "e"])
+            .write_config(&mut handle)
+            .is_err());
+        assert!(multigraph
+            .order(["a", "a"])
+            .write_config(&mut handle)
+            .is_err());
+        // Fails before writing anything
+        assert!(handle.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(valid_range("10"));
+        assert!(valid_range(":10"));
+        assert!(valid_range("-2.5:"));
+        assert!(valid_range("5:10"));
+        assert!(!valid_range(""));
+        assert!(!valid_range("10:5"));
+        assert!(!valid_range("ten"));
+        assert!(!valid_range("1:2:3"));
+
+        let graph = Graph::new("Temperature")
+            .field(Field::new("ok").warning("60").critical("80"))
+            .field(Field::new("critonly").critical(Threshold::Above(80.0)))
+            .field(Field::new("broken").warning("80:60").critical("90"));
+        assert_eq!(
+            graph.check(),
+            [
+                "Field critonly has a critical, but no warning threshold",
+                "Field broken has an invalid warning threshold 80:60",
+            ]
+        );
+        let mut handle = BufWriter::new(Vec::new());
+        let warnings = crate::testing::warnings_of(|| graph.write_config(&mut handle).unwrap());
+        assert_eq!(
+            warnings,
+            [
+                "Graph Temperature: Field critonly has a critical, but no warning threshold",
+                "Graph Temperature: Field broken has an invalid warning threshold 80:60",
+            ]
+        );
+        // Still written
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert!(output.contains("critonly.critical :80\n"));
+
+        assert!(Graph::new("Fine")
+            .field(Field::new("x").warning("1:2").critical("0:3"))
+            .check()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let graph = Graph::new(" ");
+        assert_eq!(graph.check(), ["No graph_title", "No fields"]);
+        let mut handle = BufWriter::new(Vec::new());
+        let warnings = crate::testing::warnings_of(|| graph.write_config(&mut handle).unwrap());
+        assert_eq!(warnings, ["Graph  : No graph_title", "Graph  : No fields"]);
+        assert!(!handle.into_inner().unwrap().is_empty());
+
+        // Strict fails, without writing anything
+        let mut handle = BufWriter::new(Vec::new());
+        let err = graph
+            .clone()
+            .strict(true)
+            .write_config(&mut handle)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Graph  : No graph_title, No fields");
+        assert!(handle.into_inner().unwrap().is_empty());
+
+        // Nothing to complain about, strict or not
+        let mut handle = BufWriter::new(Vec::new());
+        let graph = Graph::new("Load").field(Field::new("load")).strict(true);
+        let warnings = crate::testing::warnings_of(|| graph.write_config(&mut handle).unwrap());
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_value_order() {
+        let graph = Graph::new("Disk")
+            .field(Field::new("read"))
+            .field(Field::new("write"))
+            .field(Field::new("discard"));
+        let mut handle = BufWriter::new(Vec::new());
+        graph.write_config(&mut handle).unwrap();
+        let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+
+        let mut handle = BufWriter::new(Vec::new());
+        graph
+            .write_values(
+                &mut handle,
+                &[
+                    ("discard", 3.into()),
+                    ("read", 1.into()),
+                    ("write", 2.into()),
+                ],
+                Some(1650000000),
+            )
+            .unwrap();
+        let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        let names = |output: &str, attribute: &str| {
+            output
+                .lines()
+                .filter_map(|line| line.split_once(' ')?.0.strip_suffix(attribute))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&config, ".label"), ["read", "write", "discard"]);
+        assert_eq!(names(&config, ".label"), names(&values, ".value"));
+        assert!(crate::testing::field_mismatches(&config, &values).is_empty());
+
+        let mut handle = BufWriter::new(Vec::new());
+        assert!(graph
+            .write_values(&mut handle, &[("read", 1.into()), ("trim", 2.into())], None)
+            .is_err());
+        assert!(handle.into_inner().unwrap().is_empty());
+    }
+}
@@ -7,12 +7,12 @@
 
 //! Simple way to write munin plugins. There are basically two types of plugins,
 //! - **Simple** or **standard** ones, those are called once every munin
-//! run and gather and output there data at that time. Usually every 5
-//! minutes.
+//!   run and gather and output there data at that time. Usually every 5
+//!   minutes.
 //! - **Streaming** ones, those daemonize themself and _continuously_
-//! gather data, usually caching it in a file, and when munin comes
-//! around after 5 minutes again, they output everything they gathered
-//! in the meantime.
+//!   gather data, usually caching it in a file, and when munin comes
+//!   around after 5 minutes again, they output everything they gathered
+//!   in the meantime.
 //!
 //! Those _streaming_ plugins are needed/useful, when graphs with
 //! resolutions down to the second, rather than the default 5 minutes,
@@ -45,10 +45,14 @@
 //! Guide](http://guide.munin-monitoring.org/en/latest/plugin/writing.html).
 //! For some basics you can also look into the examples throughout
 //! this lib.
+//! Instead of writing every line yourself, you can also describe the
+//! graph using [graph::Graph] and have it write the config.
 //!
 //! **Note**: Streaming plugins should take care of correctly setting
 //! munins `graph_data_size` and `update_rate` option. Those is the
 //! difference in their configuration compared to standard plugins!
+//! Set [Config::update_rate] and [Config::graph_data_size] and use
+//! [streaming::write_streaming_graph_config] to have them written.
 //!
 //! ## acquire()
 //!
@@ -67,10 +71,16 @@
 //! The format to write the data in is the one munin expects,
 //! - _standard_: fieldname.value VALUE
 //! - _streaming_: fieldname.value EPOCH:VALUE
+//!
 //! where fieldname matches the config output, EPOCH is the
 //! unix epoch in seconds and VALUE is whatever value got
 //! calculated.
 //!
+//! Should acquire read from something that may hang, wrap the plugin
+//! in a [TimeoutPlugin] and set [Config::fetch_timeout], so munin is
+//! not kept waiting ([Config::acquire_timeout] for the daemon of a
+//! _streaming_ plugin).
+//!
 //! # Example
 //! The following implements the **load** plugin from munin, graphing
 //! the load average of the system, using the 5-minute value. As
@@ -138,33 +148,73 @@
 //! that frameworks documentation on how to setup/include it.
 //!
 //! If you do not want/need log output, just do nothing.
+//!
+//! # Features
+//! - `async-daemon`: provides `MuninPlugin::daemon_async`, running
+//!   the acquire loop of _streaming_ plugins on tokio, calling
+//!   `MuninPlugin::acquire_async`.
+//! - `logging`: provides `Config::init_logging`, sending the log
+//!   output of the plugin (and this library) to stderr, with the level
+//!   taken from the MUNIN_PLUGIN_LOG environment variable.
+//! - `config-file`: provides `Config::from_toml_file` and
+//!   `Config::to_toml_file`, keeping plugin settings in a TOML file.
 
 // Tell us if we forget to document things
 #![warn(missing_docs)]
 // We do not want to write unsafe code
 #![forbid(unsafe_code)]
 
+pub mod clock;
+pub mod command;
+pub mod composite;
 pub mod config;
-pub use crate::config::Config;
+pub mod error;
+pub mod graph;
+pub mod pluginconf;
+pub mod streaming;
+pub mod testing;
+pub mod timeout;
+pub mod value;
+pub use crate::command::{parse_command, AutoconfResult, MuninCommand};
+pub use crate::composite::CompositePlugin;
+pub use crate::config::{Config, ConfigBuilder, LockStrategy, Sink};
+pub use crate::error::PluginError;
+pub use crate::streaming::AcquireResult;
+use crate::streaming::Ticker;
+pub use crate::timeout::TimeoutPlugin;
+use crate::value::{Aggregation, MuninValue, ObservedRange, StreamSample, SubSecondAggregator};
 
 use anyhow::{anyhow, Result};
 // daemonize
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
 use fs2::FileExt;
 use log::{trace, warn};
 // daemonize
 use daemonize::Daemonize;
+#[allow(deprecated)]
 use spin_sleep::LoopHelper;
 use std::{
+    collections::BTreeMap,
     env,
-    io::{self, BufWriter, Write},
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Read, Write},
+    os::fd::{AsFd, BorrowedFd},
     path::Path,
 };
 // daemonize
 use std::{
     fs::{rename, OpenOptions},
+    os::unix::net::UnixDatagram,
+    panic::{self, AssertUnwindSafe},
     process::{Command, Stdio},
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
+};
+// daemonize
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
 };
 // daemonize
 use tempfile::NamedTempFile;
@@ -194,7 +244,7 @@ pub trait MuninPlugin {
     /// # struct LoadPlugin;
     /// # impl MuninPlugin for LoadPlugin {
     /// # fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config, epoch: u64) -> Result<()> { todo!() }
-    /// # fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<()> { todo!() }
+    /// # fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<(), PluginError> { todo!() }
     /// fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
     ///     writeln!(handle, "graph_title Load average")?;
     ///     writeln!(handle, "graph_args --base 1000 -l 0")?;
@@ -212,6 +262,14 @@ pub trait MuninPlugin {
     /// ```
     fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()>;
 
+    /// The output of [MuninPlugin::config] as a String, say to log
+    /// it or serve it elsewhere, instead of writing it to a handle.
+    fn config_string(&self) -> Result<String> {
+        let mut handle = BufWriter::new(Vec::new());
+        self.config(&mut handle)?;
+        Ok(String::from_utf8(handle.into_inner()?)?)
+    }
+
     /// Acquire data
     ///
     /// Acquire is called whenever data should be gathered. For a
@@ -224,9 +282,16 @@ pub trait MuninPlugin {
     /// to be in munin compatible format:
     /// - _standard_ plugin: fieldname.value VALUE
     /// - _streaming_ plugin: fieldname.value EPOCH:VALUE
+    ///
     /// where fieldname matches the config output, EPOCH is the unix
     /// epoch in seconds and VALUE is whatever value got calculated.
     ///
+    /// Only write to the handle, never print!() to stdout. The
+    /// detached daemon has stdout on /dev/null, but in foreground and
+    /// test runs (and for _standard_ plugins) anything printed ends up
+    /// in the output munin parses. With stdout redirected to a file,
+    /// [Config::debug_stdout] warns about it.
+    ///
     /// # Example 1, _standard_ plugin
     /// ```rust
     /// # pub use munin_plugin::*;
@@ -246,7 +311,7 @@ pub trait MuninPlugin {
     /// #   if_rxbytes: PathBuf,
     /// # };
     /// # impl MuninPlugin for InterfacePlugin {
-    /// # fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<()> { todo!() }
+    /// # fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<(), PluginError> { todo!() }
     /// # fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> { todo!() }
     /// fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config, epoch: u64) -> Result<()> {
     ///     let load = (LoadAverage::new().unwrap().five * 100.0) as isize;
@@ -274,7 +339,7 @@ pub trait MuninPlugin {
     /// #   if_rxbytes: PathBuf,
     /// # };
     /// # impl MuninPlugin for InterfacePlugin {
-    /// # fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<()> { todo!() }
+    /// # fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<(), PluginError> { todo!() }
     /// # fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> { todo!() }
     /// fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config, epoch: u64) -> Result<()> {
     ///     // Read in the received and transferred bytes, store as u64
@@ -301,57 +366,227 @@ pub trait MuninPlugin {
     /// This function is called whenever the plugin gets run with the
     /// acquire argument. That usually happens on fetch and acquire
     /// gets run in the background. `daemon()` will lock its pidfile,
-    /// to show it is running, start a loop, run once a second,
-    /// calling [MuninPlugin::acquire].
+    /// to show it is running, start a loop, run once every
+    /// [Config::acquire_interval] (default a second), calling
+    /// [MuninPlugin::acquire] (via [MuninPlugin::acquire_tick]).
+    ///
+    /// With the `async-daemon` feature, `MuninPlugin::daemon_async`
+    /// provides the same loop on tokio.
+    ///
+    /// Where the data ends up is decided by [Config::acquire_sink],
+    /// by default it is appended to [Config::plugin_cache].
+    ///
+    /// The epoch handed to [MuninPlugin::acquire] is counted from the
+    /// start of the loop (see [streaming::Ticker]), not read from the
+    /// clock on every run, so every run gets its own, increasing
+    /// epoch. Runs missed because acquire took too long are reported
+    /// to [MuninPlugin::ticks_skipped].
+    ///
+    /// # Signals
+    /// Sending `SIGUSR1` to the daemon pauses it, [MuninPlugin::acquire]
+    /// is no longer called (and nothing written) until it receives a
+    /// `SIGUSR2`, which resumes gathering data. Useful during noisy
+    /// maintenance windows, without having to kill the daemon.
+    ///
+    /// `SIGTERM` and `SIGINT` stop the daemon cleanly: the current run
+    /// finishes writing its data, the pidfile gets removed and the
+    /// process exits successfully. Set [Config::catch_signals] to
+    /// false to keep the default behaviour of those signals.
+    ///
+    /// # Pidfile
+    /// [Config::pidfile] is created and locked by the final, detached
+    /// process, which keeps it open (and locked) for as long as it
+    /// runs, and contains its PID. The lock is what tells a fetch, or
+    /// a second acquire, that the daemon runs. The file is rewritten
+    /// in place, never replaced by a rename - a new file would not
+    /// carry the lock, so another daemon could start. After detaching
+    /// the content gets checked against our own PID, and corrected
+    /// (with a warning) should it not match. Missing directories for
+    /// [Config::plugin_cache] and [Config::pidfile] get created first.
+    ///
+    /// # Foreground
+    /// With [Config::foreground] set (or MUNIN_PLUGIN_FOREGROUND=1 in
+    /// the environment), the loop runs in the calling process, for a
+    /// supervisor like systemd. The pidfile gets locked and written
+    /// all the same, so a second acquire still refuses to start.
     #[cfg(not(tarpaulin_include))]
-    fn daemon(&mut self, config: &Config) -> Result<()> {
-        // Need to run as daemon/forked in backgreound, so prepare
-        let daemonize = Daemonize::new()
-            .pid_file(&config.pidfile)
-            .chown_pid_file(true)
-            .working_directory("/tmp");
+    fn daemon(&mut self, config: &Config) -> Result<(), PluginError> {
+        // Need to run as daemon/forked in backgreound, so prepare -
+        // unless asked to stay, then only take the pidfile
+        let _pidfile = prepare_daemon(config)?;
+        Ok(daemon_loop(self, config, register_shutdown(config)?)?)
+    }
 
-        daemonize.start()?;
+    /// Called by [MuninPlugin::daemon] for every run, to
+    /// [MuninPlugin::acquire] data and tell what to store for it.
+    ///
+    /// Defaults to calling acquire, always storing what it wrote.
+    /// Override it to return [AcquireResult::Skip] for runs without
+    /// data, instead of writing bogus values, or
+    /// [AcquireResult::Unknown] to have munin record unknown values.
+    ///
+    /// Only the sync loop calls it, `daemon_async` (of the
+    /// `async-daemon` feature) uses `acquire_async` and stores
+    /// whatever that wrote.
+    fn acquire_tick<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<AcquireResult> {
+        self.acquire(handle, config, epoch)?;
+        Ok(AcquireResult::Wrote)
+    }
 
-        // Repeat once per second
-        let mut loop_helper = LoopHelper::builder().build_with_target_rate(1);
+    /// Gather data in [MuninPlugin::daemon_async], the async variant
+    /// of [MuninPlugin::acquire], which it calls by default.
+    ///
+    /// Override it to await sockets, many files or whatever else
+    /// concurrently, writing to the handle as acquire would. Only
+    /// available with the `async-daemon` feature.
+    #[cfg(feature = "async-daemon")]
+    // Plugins are used by value, without needing the future to be Send
+    #[allow(async_fn_in_trait)]
+    async fn acquire_async<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<()> {
+        self.acquire(handle, config, epoch)
+    }
 
-        // We run forever
-        loop {
-            // Let loop helper prepare
-            loop_helper.loop_start();
+    /// The loop of [MuninPlugin::daemon] on tokio: calls
+    /// [MuninPlugin::acquire_async] once every
+    /// [Config::acquire_interval], using a tokio interval timer, and
+    /// stores the data like the sync loop does. Ends, removing the
+    /// pidfile, on `SIGTERM` or `SIGINT` (see [Config::catch_signals]).
+    ///
+    /// Unlike [MuninPlugin::daemon] it does not detach itself, as
+    /// forking a running runtime is not safe. Call [detach] before
+    /// starting the runtime. Pausing, clock jump detection,
+    /// [Config::catch_acquire_panics] and [MuninPlugin::acquire_tick]
+    /// are only supported by the sync loop. Only available with the
+    /// `async-daemon` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use munin_plugin::{detach, Config, MuninPlugin};
+    /// # use anyhow::Result;
+    /// # use std::io::{BufWriter, Write};
+    /// # struct SocketPlugin;
+    /// # impl MuninPlugin for SocketPlugin {
+    /// #   fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> { todo!() }
+    /// #   fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config, epoch: u64) -> Result<()> { todo!() }
+    /// # }
+    /// # fn main() -> Result<()> {
+    /// let config = Config::new_daemon(String::from("socketplugin"));
+    /// detach(&config)?;
+    /// tokio::runtime::Builder::new_current_thread()
+    ///     .enable_time()
+    ///     .build()?
+    ///     .block_on(SocketPlugin.daemon_async(&config))
+    /// # }
+    /// ```
+    #[cfg(feature = "async-daemon")]
+    #[allow(async_fn_in_trait)]
+    async fn daemon_async(&mut self, config: &Config) -> Result<()> {
+        // Also checks the interval is not zero, which tokio panics on
+        target_rate(config.acquire_interval)?;
+        let mut interval = tokio::time::interval(config.acquire_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
-            // Streaming plugins need the epoch, so provide it
-            let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(); // without the nanosecond part
+        let socket = match config.acquire_sink {
+            Sink::File => None,
+            Sink::UnixDatagram(_) | Sink::FileAndUnixDatagram(_) => Some(UnixDatagram::unbound()?),
+        };
+        let mut ticker = Ticker::new(
+            config.clock.now(),
+            tokio::time::Instant::now().into_std(),
+            config.acquire_interval,
+        )?;
+        let mut pending = PendingData::new(tokio::time::Instant::now().into_std());
+        let mut sequence: u64 = 0;
+        let shutdown = register_shutdown(config)?;
+        if config.reset_cache_on_start {
+            reset_caches(config)?;
+        }
 
-            // Own scope, so file is closed before we sleep. Ensures
-            // we won't have a file open, that fetch just moved away
-            // to send out to munin.
-            {
-                // Open the munin cachefile to store our values, using
-                // a BufWriter to "collect" the writeln!() in acquire
-                // together
-                let mut handle = BufWriter::with_capacity(
-                    config.fetch_size,
-                    OpenOptions::new()
-                        .create(true) // If not there, create
-                        .write(true) // We want to write
-                        .append(true) // We want to append
-                        .open(&config.plugin_cache)?,
-                );
+        while !shutdown.load(Ordering::Relaxed) {
+            // The epoch belongs to the time the run was scheduled for
+            let tick = ticker.tick(interval.tick().await.into_std());
+            if tick.skipped > 0 {
+                self.ticks_skipped(tick.skipped, tick.epoch);
+            }
+            sequence += 1;
 
-                self.acquire(&mut handle, config, epoch)?;
+            let mut handle = BufWriter::with_capacity(config.fetch_size, Vec::new());
+            write_markers(&mut handle, config, sequence, tick.epoch, None)?;
+            let started = Instant::now();
+            self.acquire_async(&mut handle, config, tick.epoch).await?;
+            self.acquire_took(started.elapsed(), config, tick.epoch);
+            if config.freshness_comment {
+                writeln!(handle, "# last-updated {}", tick.epoch)?;
             }
-            // Sleep for the rest of the second
-            loop_helper.loop_sleep();
+            let now = tokio::time::Instant::now().into_std();
+            pending.push(config, socket.as_ref(), &handle.into_inner()?, now)?;
+        }
+        pending.finish(
+            config,
+            socket.as_ref(),
+            tokio::time::Instant::now().into_std(),
+        )?;
+        warn!("Received shutdown signal, stopping data acquisition");
+        shutdown_plugin(self, config);
+        std::fs::remove_file(&config.pidfile)?;
+        Ok(())
+    }
+
+    /// Called by [MuninPlugin::daemon] when runs had to be skipped,
+    /// because the previous one took longer than
+    /// [Config::acquire_interval]. `skipped` is the number of runs
+    /// missed before the one for `epoch`.
+    ///
+    /// The default logs a warning, override it to count or handle
+    /// them differently.
+    fn ticks_skipped(&mut self, skipped: u64, epoch: u64) {
+        warn!("acquire too slow, skipped {skipped} run(s) before epoch {epoch}");
+    }
+
+    /// Called by [MuninPlugin::daemon] (and `daemon_async`) after
+    /// every run of [MuninPlugin::acquire], with the time it `took`
+    /// for `epoch`.
+    ///
+    /// The default logs a warning, if that was longer than
+    /// [Config::acquire_interval], the budget of a run. Override it
+    /// to keep the durations, say for tuning a slow plugin.
+    fn acquire_took(&mut self, took: Duration, config: &Config, epoch: u64) {
+        if over_budget(took, config) {
+            warn!(
+                "acquire for epoch {epoch} took {took:?}, longer than the interval of {:?}",
+                config.acquire_interval
+            );
         }
     }
 
+    /// Called by [MuninPlugin::daemon] when it got asked to stop,
+    /// after the last data got stored, before the pidfile is
+    /// removed.
+    ///
+    /// The place to cleanly close database handles, sockets and
+    /// other connections the plugin keeps open between runs. The
+    /// default does nothing. An error gets logged, the daemon still
+    /// cleans up and exits.
+    fn on_shutdown(&self, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+
     /// Fetch delivers actual data to munin. This is called whenever
-    /// the plugin is called without an argument. If the
-    /// [config::Config::dirtyconfig] setting is true (auto-detected from
-    /// environment set by munin), this will also be called right
-    /// after having called [MuninPlugin::config].
+    /// the plugin is called without an argument. If dirtyconfig is
+    /// enabled (see [config::Config::dirtyconfig_enabled], usually
+    /// auto-detected from environment set by munin), this will also be
+    /// called right after having called [MuninPlugin::config].
     ///
     /// The size of the BufWriter this function uses is configurable
     /// from [Config::fetch_size].
@@ -363,9 +598,18 @@ pub trait MuninPlugin {
     /// expects it).
     ///
     /// For _streaming_ plugins it will create a temporary file beside
-    /// the [config::Config::plugin_cache], will rename the
-    /// [config::Config::plugin_cache] and then use [std::io::copy] to
-    /// "copy" the data to the provided handle.
+    /// the [config::Config::plugin_cache] (or in
+    /// [Config::fetch_tempdir]), will rename the
+    /// [config::Config::plugin_cache] and then copy the data to the
+    /// provided handle, flushing it every [Config::fetch_size] bytes,
+    /// so even a huge cachefile does not pile up in memory.
+    /// The rename happens holding a lock (on a `.lock` file beside the
+    /// cachefile) the daemon also takes for every append, so no data
+    /// ends up in the renamed file after it got copied. The same
+    /// happens for all of [Config::extra_caches].
+    ///
+    /// For _streaming_ plugins the time of the fetch gets recorded, so
+    /// the acquire daemon can adapt to it, see [Config::last_fetch].
     ///
     /// # Overriding this function
     /// If you want to override this function, you should ensure that
@@ -376,18 +620,68 @@ pub trait MuninPlugin {
     /// You read the whole cachefile, then output it to munin, then
     /// delete it - and during the halfsecond this took, new data
     /// appeared in the file, now lost.
-    fn fetch<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<()> {
+    fn fetch<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+    ) -> Result<(), PluginError> {
         // Daemonize means plugin writes a cachefile, so lets output that
         if config.daemonize {
-            // We need a temporary file
-            let fetchpath = NamedTempFile::new_in(&config.plugin_statedir)?;
-            // Rename the cache file, to ensure that acquire doesn't add data
-            // between us outputting data and deleting the file
-            rename(&config.plugin_cache, &fetchpath)?;
-            // Want to read the tempfile now
-            let mut fetchfile = std::fs::File::open(&fetchpath)?;
-            // And ask io::copy to just take it all and shove it into the handle
-            io::copy(&mut fetchfile, handle)?;
+            // Unless the data is not stored in a cachefile at all
+            if let Sink::UnixDatagram(_) = config.acquire_sink {
+                trace!("Data is sent to a socket, nothing to fetch");
+                return Ok(());
+            }
+            // Rename the cache files, to ensure that acquire doesn't add data
+            // between us outputting data and deleting the file. Holding
+            // the cache lock, so no append is in progress - one that
+            // opened the file before the rename would write into the
+            // renamed file after we read it, losing that data
+            let mut fetched = Vec::new();
+            {
+                let _lock = lock_cache(config)?;
+                fetched.push((
+                    None,
+                    &config.plugin_cache,
+                    take_cache(&config.plugin_cache, config.fetch_tempdir.as_deref())?,
+                ));
+                for (name, path) in &config.extra_caches {
+                    match take_cache(path, config.fetch_tempdir.as_deref()) {
+                        Ok(fetchpath) => fetched.push((Some(name), path, fetchpath)),
+                        // Nothing got acquired for this one (yet)
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            let mut updated = None;
+            for (name, path, fetchpath) in fetched {
+                if let Some(name) = name {
+                    writeln!(handle, "multigraph {name}")?;
+                }
+                // Want to read the tempfile now
+                let mut fetchfile = open_cache(fetchpath.path(), config.compress_cache)?;
+                if config.freshness_comment {
+                    // One line per run is of no use to anyone
+                    let newest = copy_stale(&mut io::BufReader::new(fetchfile), handle)?;
+                    updated = updated.max(newest);
+                } else {
+                    // And shove it all into the handle, flushing on the way
+                    copy_flushing(&mut fetchfile, handle, config.fetch_size)?;
+                }
+                if config.keep_fetched_cache {
+                    keep_cache(fetchpath, &path.with_extension("last"))?;
+                }
+            }
+            if let Some(epoch) = updated {
+                writeln!(handle, "# last-updated {epoch}")?;
+            }
+            // Remember when munin came around, for the acquire daemon.
+            // Failing that is no reason to fail the fetch
+            let epoch = config.clock.now_epoch();
+            if let Err(e) = config.record_fetch(epoch) {
+                warn!("Could not record fetch time: {}", e);
+            }
         } else {
             // Not daemonizing, plugin gathers data and wants to output it directly.
             // So we just call acquire, which is expected to write its data to handle.
@@ -396,6 +690,87 @@ pub trait MuninPlugin {
         Ok(())
     }
 
+    /// Replay a recorded cachefile through [MuninPlugin::fetch].
+    ///
+    /// Treats `cachefile` as the cache of a _streaming_ plugin and
+    /// runs fetch against it, writing to the provided handle. Works on
+    /// a copy in a temporary directory below
+    /// [Config::plugin_statedir], so neither `cachefile` nor the real
+    /// cache of the plugin (or its recorded fetch time) get touched.
+    /// Used by the `replay` argument, to reproduce exactly what munin
+    /// got to see from a captured cache.
+    fn replay<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+        cachefile: &Path,
+    ) -> Result<()> {
+        let replaydir = tempfile::tempdir_in(&config.plugin_statedir)?;
+        let mut replayconfig = config.clone();
+        replayconfig.daemonize = true;
+        replayconfig.acquire_sink = Sink::File;
+        replayconfig.plugin_statedir = replaydir.path().to_path_buf();
+        replayconfig.plugin_cache = replaydir.path().join("replay.value");
+        replayconfig.last_fetch_file = replaydir.path().join("replay.lastfetch");
+        replayconfig.extra_caches = BTreeMap::new();
+        std::fs::copy(cachefile, &replayconfig.plugin_cache)?;
+        Ok(self.fetch(handle, &replayconfig)?)
+    }
+
+    /// Answer munins `config` call: write the config using
+    /// [MuninPlugin::config], and if dirtyconfig is enabled (see
+    /// [Config::dirtyconfig_enabled]), follow it with the data from
+    /// [MuninPlugin::fetch].
+    ///
+    /// As the decision comes only from `config`, this can be tested
+    /// without setting munins environment variables, see
+    /// [testing::dirtyconfig_output].
+    fn config_output<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+    ) -> Result<()> {
+        // The graph limits come from the data, so it has to be there
+        // before the config can be written
+        if config.dirtyconfig_enabled() && config.observed_range {
+            trace!("Munin supports dirtyconfig, fetching data for the observed range");
+            let mut data = BufWriter::new(Vec::new());
+            self.fetch(&mut data, config)?;
+            let data = data.into_inner()?;
+            handle.write_all(observed_args(&self.config_string()?, &data).as_bytes())?;
+            handle.write_all(&data)?;
+            return Ok(());
+        }
+        self.config(handle)?;
+        // If munin supports dirtyconfig, send the data now, into the
+        // same buffer, the caller flushes once for both
+        if config.dirtyconfig_enabled() {
+            trace!("Munin supports dirtyconfig, sending data now");
+            self.fetch(handle, config)?;
+        }
+        Ok(())
+    }
+
+    /// Write the config followed by one round of data, with a
+    /// comment line before each, for a developer to look at both
+    /// at once. Used by the `test` argument.
+    ///
+    /// Never daemonizes or touches the cachefile: _standard_ plugins
+    /// run [MuninPlugin::fetch], _streaming_ ones a single
+    /// [MuninPlugin::acquire] with the current epoch.
+    fn run_once<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<()> {
+        writeln!(handle, "# config")?;
+        self.config(handle)?;
+        writeln!(handle, "# fetch")?;
+        if config.daemonize {
+            let epoch = config.clock.now_epoch();
+            self.acquire(handle, config, epoch)?;
+        } else {
+            self.fetch(handle, config)?;
+        }
+        Ok(())
+    }
+
     /// Check whatever is neccessary to decide if the plugin can
     /// auto-configure itself.
     ///
@@ -409,21 +784,109 @@ pub trait MuninPlugin {
         false
     }
 
+    /// Like [MuninPlugin::check_autoconf], but able to tell why the
+    /// plugin can not configure itself, see [AutoconfResult].
+    ///
+    /// Defaults to the answer of [MuninPlugin::check_autoconf],
+    /// without a reason.
+    fn autoconf_result(&self) -> AutoconfResult {
+        self.check_autoconf().into()
+    }
+
     /// Tell munin if the plugin supports autoconf.
     ///
     /// Munin expects a simple yes or no on stdout, so we just print
     /// it, depending on the return value of
+    /// [MuninPlugin::autoconf_result], which defaults to that of
     /// [MuninPlugin::check_autoconf]. The default of that is a plain
     /// false. If it is possible for your plugin to detect, if it can
     /// autoconfigure itself, then implement the logic in
-    /// [MuninPlugin::check_autoconf] and have it return true.
+    /// [MuninPlugin::check_autoconf] and have it return true - or in
+    /// [MuninPlugin::autoconf_result], to also tell why not, like
+    /// `no (network interface eth0 not found)`.
+    ///
+    /// Plugins offering suggestions (see [MuninPlugin::suggest])
+    /// list them in the answer, like `yes (eth0 eth1)`.
     #[cfg(not(tarpaulin_include))]
     fn autoconf(&self) {
-        if self.check_autoconf() {
-            println!("yes")
-        } else {
-            println!("no")
+        println!(
+            "{}",
+            autoconf_answer(&self.autoconf_result(), &self.suggest())
+        );
+    }
+
+    /// Tell munin which instances a wildcard plugin (like `if_`,
+    /// linked as `if_eth0`, `if_eth1`, ...) can be used for, answering
+    /// the `suggest` argument.
+    ///
+    /// Defaults to no suggestions at all.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # pub use munin_plugin::*;
+    /// # use anyhow::Result;
+    /// # use std::io::{BufWriter, Write};
+    /// # struct InterfacePlugin;
+    /// impl MuninPlugin for InterfacePlugin {
+    ///     fn suggest(&self) -> Vec<String> {
+    ///         vec![String::from("eth0"), String::from("eth1")]
+    ///     }
+    /// #   fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> { todo!() }
+    /// #   fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config, epoch: u64) -> Result<()> { todo!() }
+    /// }
+    /// ```
+    fn suggest(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Write the suggestions from [MuninPlugin::suggest], one per
+    /// line, as munin expects in answer to `suggest`.
+    fn suggest_output<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        for suggestion in self.suggest() {
+            writeln!(handle, "{suggestion}")?;
+        }
+        Ok(())
+    }
+
+    /// The munin capabilities (as in the `MUNIN_CAP_*` environment
+    /// variables) the plugin supports, printed one per line for the
+    /// `capabilities` argument.
+    ///
+    /// Defaults to `dirtyconfig`, which [MuninPlugin::config_output]
+    /// handles for every plugin. Plugins writing multigraph output
+    /// (see [graph::MultiGraph]) should add `multigraph`.
+    fn capabilities(&self) -> Vec<&str> {
+        vec!["dirtyconfig"]
+    }
+
+    /// Write the capabilities from [MuninPlugin::capabilities], one
+    /// per line.
+    fn capabilities_output<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        for capability in self.capabilities() {
+            writeln!(handle, "{capability}")?;
         }
+        Ok(())
+    }
+
+    /// Install the plugin for munin, as `link_name` in `plugins_dir`
+    /// (usually `/etc/munin/plugins`), a symlink to the running
+    /// executable. Wildcard plugins get linked once per instance,
+    /// like `if_eth0`, see [MuninPlugin::suggest].
+    ///
+    /// Does nothing if the link already points to us, but errors if
+    /// `link_name` exists as anything else, rather than replacing it.
+    fn install(&self, link_name: &str, plugins_dir: &Path) -> Result<()> {
+        let target = std::fs::canonicalize(env::current_exe()?)?;
+        install_link(&target, &plugins_dir.join(link_name))
+    }
+
+    /// Remove the symlink `link_name` in `plugins_dir` created by
+    /// [MuninPlugin::install]. Does nothing if it is not there, but
+    /// errors if it is not a symlink to the running executable.
+    fn uninstall(&self, link_name: &str, plugins_dir: &Path) -> Result<()> {
+        let target = std::fs::canonicalize(env::current_exe()?)?;
+        uninstall_link(&target, &plugins_dir.join(link_name))
     }
 
     /// A simplified start, only need a name, for the rest, defaults are fine.
@@ -446,190 +909,2018 @@ pub trait MuninPlugin {
     /// The main plugin function, this will deal with parsing
     /// commandline arguments and doing what is expected of the plugin
     /// (present config, fetch values, whatever).
+    ///
+    /// Besides the arguments munin uses, `acquire-once` runs
+    /// [MuninPlugin::acquire] a single time with the current epoch and
+    /// prints its output to stdout, without daemonizing or touching
+    /// the cachefile. Useful to eyeball the data or pipe it elsewhere.
+    /// `healthcheck` checks that the acquire daemon of a streaming
+    /// plugin runs and recently wrote data (see
+    /// [Config::healthcheck_max_age]), printing a status line and
+    /// failing if not.
+    /// `verify` checks the directories and files the plugin uses can
+    /// be used, printing a report and failing if not. The report also
+    /// names [Config::munin_libdir] and [Config::rrdcached_sock], if
+    /// munin set them.
+    /// `replay CACHEFILE` outputs the given cachefile like a fetch
+    /// would, see [MuninPlugin::replay].
+    /// With the MUNIN_PLUGIN_DEBUG environment variable set, a fetch
+    /// also compares the fields it output with those declared by
+    /// [MuninPlugin::config], logging a warning for every mismatch,
+    /// and `test`, `acquire-once` and a daemon in the foreground warn
+    /// about acquire writing to stdout, see [Config::debug_stdout].
+    /// `test` prints the config followed by data, see
+    /// [MuninPlugin::run_once].
+    /// `suggest` lists the instances of a wildcard plugin, see
+    /// [MuninPlugin::suggest].
+    /// `capabilities` lists the munin capabilities the plugin
+    /// supports, see [MuninPlugin::capabilities].
+    /// `configfetch` prints the config followed by the data, as if
+    /// dirtyconfig was enabled, saving a process start for wrappers
+    /// calling the plugin.
+    ///
+    /// Run by hand without arguments (stdout on a terminal and none
+    /// of the environment munin sets), a short note listing the
+    /// arguments goes to stderr first, then data gets fetched as
+    /// usual.
+    ///
+    /// The name the plugin got called as ends up in
+    /// [Config::invoked_as], for wildcard plugins.
     #[cfg(not(tarpaulin_include))]
-    fn start(&mut self, config: Config) -> Result<bool> {
+    fn start(&mut self, mut config: Config) -> Result<bool, PluginError> {
         trace!("Plugin start");
-        trace!("My plugin config: {config:#?}");
-
         // Store arguments for (possible) later use
         let args: Vec<String> = env::args().collect();
+        if let Some(name) = args.first().and_then(|arg0| Path::new(arg0).file_name()) {
+            config.invoked_as = name.to_string_lossy().into_owned();
+        }
+        trace!("My plugin config: {config:#?}");
+
+        // Streaming plugins without those get stored at 5 minute resolution
+        if let Some(warning) = streaming::missing_streaming_settings(&config) {
+            warn!("{warning}");
+        }
 
         // Now go over the args and see what we are supposed to do
-        match args.len() {
+        let command = parse_command(&args);
+        match command {
             // no arguments passed, print data
-            1 => {
+            MuninCommand::Fetch => {
                 trace!("No argument, assuming fetch");
+                // Someone running us by hand may wonder what happens
+                if command::outside_munin(env::vars_os(), io::stdout().is_terminal()) {
+                    eprintln!("{}", command::USAGE_NOTE);
+                }
                 if config.daemonize {
                     // For daemonization we need to check if a copy of us
-                    // with the acquire arg already runs. We do this by
-                    // trying to lock our pidfile. If that works, nothing
-                    // is running, then we need to start us in the
-                    // background.
-                    let lockfile = !Path::exists(&config.pidfile) || {
-                        let lockedfile = OpenOptions::new()
-                            .create(true)
-                            .write(true)
-                            .open(&config.pidfile)?;
-                        lockedfile.try_lock_exclusive().is_ok()
-                    };
-                    // If we could lock, it appears that acquire isn't running. Start it.
-                    if lockfile {
-                        trace!("Could lock the pidfile, will spawn acquire now");
-                        Command::new(&args[0])
-                            .arg("acquire")
-                            .stdin(Stdio::null())
-                            .stdout(Stdio::null())
-                            .stderr(Stdio::null())
-                            .spawn()?;
-                        trace!("Spawned, sleep for 1s, then continue");
-                        // Now we wait one second before going on, so the
-                        // newly spawned process had a chance to generate us
-                        // some data
-                        thread::sleep(Duration::from_secs(1));
+                    // with the acquire arg already runs. If not, we
+                    // need to start us in the background.
+                    // A supervisor runs it, when in the foreground.
+                    if !config.foreground && !daemon_running(&config)? {
+                        trace!("Acquire does not run, will spawn it now");
+                        spawn_acquire(&config, &args[0])?;
                     }
                 }
                 // Daemonized or not, fetch means handing out data, so lets do this.
                 trace!("Calling fetch");
                 // We want to write a possibly large amount to stdout, take and lock it
                 let stdout = io::stdout();
-                // Buffered writer, to gather multiple small writes together
-                let mut handle = BufWriter::with_capacity(config.fetch_size, stdout.lock());
+                // Buffered writer, to gather multiple small writes
+                // together, keeping a copy of everything if we want
+                // to check it
+                let debug = env::var_os("MUNIN_PLUGIN_DEBUG").is_some();
+                let mut handle = BufWriter::with_capacity(
+                    config.fetch_size,
+                    TeeWriter::new(stdout.lock(), debug),
+                );
                 // And give us data, please
                 self.fetch(&mut handle, &config)?;
                 trace!("Done");
                 // And flush the handle, so it can also deal with possible errors
                 handle.flush()?;
+                if debug {
+                    // Flushed above, nothing left in the buffer
+                    let data = handle.into_parts().0.into_copy();
+                    check_fields(self, &data)?;
+                }
 
                 return Ok(true);
             }
-            // Argument passed, check which one and act accordingly
-            2 => match args[1].as_str() {
-                "config" => {
-                    // We want to write a possibly large amount to stdout, take and lock it
-                    let stdout = io::stdout();
-                    {
-                        // Buffered writer, to gather multiple small writes together
-                        let mut handle =
-                            BufWriter::with_capacity(config.config_size, stdout.lock());
-                        self.config(&mut handle)?;
-                        // And flush the handle, so it can also deal with possible errors
-                        handle.flush()?;
+            // configfetch is config with forced dirtyconfig, for
+            // wrappers wanting both in one run
+            MuninCommand::Config | MuninCommand::ConfigFetch => {
+                let mut config = config;
+                if command == MuninCommand::ConfigFetch {
+                    // Always send data after the config
+                    config.dirtyconfig = true;
+                }
+                // We want to write a possibly large amount to stdout, take and lock it
+                let stdout = io::stdout();
+                // Buffered writer, to gather multiple small writes together
+                let mut handle = BufWriter::with_capacity(config.config_size, stdout.lock());
+                self.config_output(&mut handle, &config)?;
+                // And flush the handle, so it can also deal with possible errors
+                handle.flush()?;
+                return Ok(true);
+            }
+            MuninCommand::Autoconf => {
+                self.autoconf();
+                return Ok(true);
+            }
+            MuninCommand::Suggest => {
+                let stdout = io::stdout();
+                let mut handle = BufWriter::new(stdout.lock());
+                self.suggest_output(&mut handle)?;
+                // And flush the handle, so it can also deal with possible errors
+                handle.flush()?;
+                return Ok(true);
+            }
+            MuninCommand::Capabilities => {
+                let stdout = io::stdout();
+                let mut handle = BufWriter::new(stdout.lock());
+                self.capabilities_output(&mut handle)?;
+                // And flush the handle, so it can also deal with possible errors
+                handle.flush()?;
+                return Ok(true);
+            }
+            MuninCommand::Acquire => {
+                trace!("Called acquire to gather data, will run loop forever");
+                // Will only ever process anything after this line, if
+                // one process has our pidfile already locked, ie. if
+                // another acquire is running. (Or if we can not
+                // daemonize for another reason).
+                if let Err(e) = self.daemon(&config) {
+                    let message = format!(
+                        "Could not start plugin {} in daemon mode to gather data - already running? ({})",
+                        config.plugin_name, e
+                    );
+                    return Err(match e {
+                        PluginError::Daemonize(_) => PluginError::Daemonize(message),
+                        _ => anyhow!(message).into(),
+                    });
+                };
+            }
+            MuninCommand::Healthcheck => {
+                let now = config.clock.now_epoch();
+                match check_health(&config, now) {
+                    Ok(status) => {
+                        println!("OK: {status}");
+                        return Ok(true);
                     }
-                    // If munin supports dirtyconfig, send the data now
-                    if config.dirtyconfig {
-                        trace!("Munin supports dirtyconfig, sending data now");
-                        let mut handle = BufWriter::with_capacity(config.fetch_size, stdout.lock());
-                        self.fetch(&mut handle, &config)?;
-                        // And flush the handle, so it can also deal with possible errors
-                        handle.flush()?;
+                    Err(status) => {
+                        println!("CRITICAL: {status}");
+                        return Err(anyhow!("Plugin {} unhealthy", config.plugin_name).into());
                     }
-                    return Ok(true);
                 }
-                "autoconf" => {
-                    self.autoconf();
+            }
+            MuninCommand::Verify => {
+                let (report, ok) = verify_report(&config);
+                print!("{report}");
+                if ok {
                     return Ok(true);
                 }
-                "acquire" => {
-                    trace!("Called acquire to gather data, will run loop forever");
-                    // Will only ever process anything after this line, if
-                    // one process has our pidfile already locked, ie. if
-                    // another acquire is running. (Or if we can not
-                    // daemonize for another reason).
-                    if let Err(e) = self.daemon(&config) {
-                        return Err(anyhow!(
-                            "Could not start plugin {} in daemon mode to gather data - already running? ({})",
-                            config.plugin_name,
-                            e
-                        ));
-                    };
-                }
-                &_ => trace!("Unsupported argument: {}", args[1]),
-            },
-            // Whatever else
-            _ => return Err(anyhow!("No argument given")),
+                return Err(anyhow!("Plugin {} environment broken", config.plugin_name).into());
+            }
+            MuninCommand::AcquireOnce => {
+                trace!("Called acquire-once, gathering data a single time to stdout");
+                // No daemon, no cachefile, just run acquire once
+                // with the current epoch and hand it out directly.
+                let epoch = config.clock.now_epoch();
+                // Into a buffer of its own, so stray writes to stdout
+                // can be told apart
+                let mut handle = BufWriter::with_capacity(config.fetch_size, Vec::new());
+                watch_stdout(&config, epoch, || self.acquire(&mut handle, &config, epoch))?;
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&handle.into_inner().map_err(|e| e.into_error())?)?;
+                stdout.flush()?;
+                return Ok(true);
+            }
+            MuninCommand::Test => {
+                trace!("Called test, showing config and data");
+                let epoch = config.clock.now_epoch();
+                let mut handle = BufWriter::with_capacity(config.fetch_size, Vec::new());
+                watch_stdout(&config, epoch, || self.run_once(&mut handle, &config))?;
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&handle.into_inner().map_err(|e| e.into_error())?)?;
+                stdout.flush()?;
+                return Ok(true);
+            }
+            // Replay a recorded cachefile
+            MuninCommand::Replay(cachefile) => {
+                trace!("Replaying cachefile {}", cachefile.display());
+                let stdout = io::stdout();
+                let mut handle = BufWriter::with_capacity(config.fetch_size, stdout.lock());
+                self.replay(&mut handle, &config, &cachefile)?;
+                // And flush the handle, so it can also deal with possible errors
+                handle.flush()?;
+                return Ok(true);
+            }
+            // A single unknown argument is ignored, more are an error
+            MuninCommand::Unknown(arg) if args.len() == 2 => {
+                trace!("Unsupported argument: {arg}")
+            }
+            MuninCommand::Unknown(arg) => return Err(PluginError::InvalidArgument(arg)),
         }
         Ok(true)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Check if `link` is a symlink pointing to `target`. Errors if it
+/// exists as something else, false if it does not exist.
+fn links_to(target: &Path, link: &Path) -> Result<bool> {
+    match std::fs::read_link(link) {
+        Ok(existing) if existing == target => Ok(true),
+        Ok(existing) => Err(anyhow!(
+            "{} already exists, pointing to {}",
+            link.display(),
+            existing.display()
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(_) if link.exists() => Err(anyhow!(
+            "{} already exists, and is no symlink",
+            link.display()
+        )),
+        Err(e) => Err(anyhow!("Can not check {}: {}", link.display(), e)),
+    }
+}
 
-    // Our plugin struct
-    #[derive(Debug)]
-    struct TestPlugin;
-    impl MuninPlugin for TestPlugin {
-        fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
-            writeln!(handle, "This is a test plugin")?;
-            writeln!(handle, "There is no config")?;
-            Ok(())
-        }
-        fn acquire<W: Write>(
-            &mut self,
-            handle: &mut BufWriter<W>,
-            config: &Config,
-            epoch: u64,
-        ) -> Result<()> {
-            writeln!(handle, "This is a value for {}", config.plugin_name)?;
-            writeln!(handle, "And one more value with epoch {}", epoch)?;
-            Ok(())
-        }
+/// Create the symlink `link` to `target`, see [MuninPlugin::install].
+fn install_link(target: &Path, link: &Path) -> Result<()> {
+    if links_to(target, link)? {
+        trace!("{} already installed", link.display());
+        return Ok(());
     }
+    std::os::unix::fs::symlink(target, link)
+        .map_err(|e| anyhow!("Can not create {}: {}", link.display(), e))
+}
 
-    #[test]
-    fn test_config() {
-        let test = TestPlugin;
+/// Remove the symlink `link` to `target`, see [MuninPlugin::uninstall].
+fn uninstall_link(target: &Path, link: &Path) -> Result<()> {
+    if !links_to(target, link)? {
+        trace!("{} not installed", link.display());
+        return Ok(());
+    }
+    std::fs::remove_file(link).map_err(|e| anyhow!("Can not remove {}: {}", link.display(), e))
+}
 
-        // We want to check the output of config contains our test string
-        // above, so have it "write" it to a variable, then check if
-        // the variable contains what we want
-        let checktext = Vec::new();
-        let mut handle = BufWriter::new(checktext);
-        test.config(&mut handle).unwrap();
-        handle.flush().unwrap();
+/// Detach into the background, the way [MuninPlugin::daemon] does
+/// before starting its loop: fork, lock and write [Config::pidfile]
+/// and change into [Config::working_directory].
+///
+/// Only returns (in the detached process) if that worked, the
+/// calling process exits. Fails if another process holds the
+/// pidfile, that is, the daemon already runs. Call it before
+/// starting any threads, like those of an async runtime.
+#[cfg(not(tarpaulin_include))]
+pub fn detach(config: &Config) -> Result<()> {
+    // Failing in the detached process would go unnoticed
+    config.check_working_directory()?;
+    daemonizer(config)
+        .start()
+        .map_err(|e| PluginError::Daemonize(e.to_string()))?;
 
-        // And now check what got "written" into the variable
-        let (recovered_writer, _buffered_data) = handle.into_parts();
-        let output = String::from_utf8(recovered_writer).unwrap();
-        assert_eq!(
-            output,
-            String::from("This is a test plugin\nThere is no config\n")
+    // Make sure the pidfile names us, the detached process
+    if ensure_pidfile(&config.pidfile, std::process::id())? {
+        warn!(
+            "Pidfile {} did not contain our PID, corrected",
+            config.pidfile.display()
         );
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_fetch_standard() {
-        let mut test = TestPlugin;
+/// Start the acquire daemon, running [Config::spawn_command] with the
+/// `acquire` argument, detached from our stdin, stdout and stderr,
+/// then wait [Config::initial_spawn_delay] for it to gather data.
+fn spawn_acquire(config: &Config, arg0: &str) -> Result<()> {
+    let command = config.spawn_command(arg0);
+    Command::new(&command)
+        .arg("acquire")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow!("Could not spawn {} acquire: {}", command.display(), e))?;
+    if !config.initial_spawn_delay.is_zero() {
+        trace!(
+            "Spawned, sleep for {:?}, then continue",
+            config.initial_spawn_delay
+        );
+        // Give the newly spawned process a chance to generate some data
+        thread::sleep(config.initial_spawn_delay);
+    }
+    Ok(())
+}
 
-        // We want to check the output of fetch contains our test string
-        // above, so have it "write" it to a variable, then check if
-        // the variable contains what we want
-        let checktext = Vec::new();
-        let mut handle = BufWriter::new(checktext);
-        test.fetch(&mut handle, &config::Config::new("test".to_string()))
-            .unwrap();
-        handle.flush().unwrap();
+/// Get ready to run the loop of [MuninPlugin::daemon]: [create_dirs],
+/// then [detach], or with [Config::foreground] set, [lock_pidfile] -
+/// returning the pidfile to keep open (and locked) while the loop
+/// runs.
+fn prepare_daemon(config: &Config) -> Result<Option<std::fs::File>> {
+    create_dirs(config)?;
+    if config.foreground {
+        trace!("Running in the foreground, not detaching");
+        lock_pidfile(config).map(Some)
+    } else {
+        detach(config).map(|_| None)
+    }
+}
 
-        // And now check what got "written" into the variable
-        let (recovered_writer, _buffered_data) = handle.into_parts();
-        let output = String::from_utf8(recovered_writer).unwrap();
-        assert_eq!(
-            output,
-            String::from("This is a value for test\nAnd one more value with epoch 0\n")
-        );
+/// Create the directories [Config::plugin_cache] and
+/// [Config::pidfile] go into, should they be missing. Fails naming
+/// the directory that could not be created.
+fn create_dirs(config: &Config) -> Result<()> {
+    for (what, path) in [
+        ("cachefile", &config.plugin_cache),
+        ("pidfile", &config.pidfile),
+    ] {
+        let dir = parent_dir(path);
+        if dir.is_dir() {
+            continue;
+        }
+        trace!("Creating directory {} for the {what}", dir.display());
+        std::fs::create_dir_all(dir).map_err(|e| {
+            anyhow!(
+                "Can not create directory {} for the {what} {}: {e}",
+                dir.display(),
+                path.display()
+            )
+        })?;
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_fetch_streaming() {
-        let mut config = Config::new(String::from("testplugin"));
-        config.daemonize = true;
-        config.fetch_size = 16384;
+/// Lock [Config::pidfile] and write our PID to it, the way the
+/// detached daemon does, see [MuninPlugin::daemon]. The lock is held
+/// as long as the returned file is open.
+fn lock_pidfile(config: &Config) -> Result<std::fs::File> {
+    let mut pidfile = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&config.pidfile)?;
+    pidfile.try_lock_exclusive().map_err(|e| {
+        PluginError::Daemonize(format!(
+            "Can not lock {}, already running? ({e})",
+            config.pidfile.display()
+        ))
+    })?;
+    // Rewritten in place, a new file would not carry the lock
+    pidfile.set_len(0)?;
+    pidfile.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+    Ok(pidfile)
+}
 
-        let mut test = TestPlugin {};
+/// The loop of [MuninPlugin::daemon], calling [MuninPlugin::acquire]
+/// once every [Config::acquire_interval] until `shutdown` gets set
+/// (see [register_shutdown]), then calling [MuninPlugin::on_shutdown]
+/// and removing [Config::pidfile].
+#[allow(deprecated)]
+fn daemon_loop<P: MuninPlugin + ?Sized>(
+    plugin: &mut P,
+    config: &Config,
+    shutdown: Arc<AtomicBool>,
+) -> Result<()> {
+    // Old data from before a crash is not wanted
+    if config.reset_cache_on_start {
+        reset_caches(config)?;
+    }
 
-        // We need a temporary file
-        let fetchpath = NamedTempFile::new_in(
+    // Start on the top of a second, if asked to
+    if config.align_to_second {
+        thread::sleep(until_next_second(config.clock.now()));
+    }
+
+    // Repeat once per acquire_interval
+    let mut loop_helper =
+        LoopHelper::builder().build_with_target_rate(target_rate(config.acquire_interval)?);
+
+    // Sending to a socket? Then we need one
+    let socket = match config.acquire_sink {
+        Sink::File => None,
+        Sink::UnixDatagram(_) | Sink::FileAndUnixDatagram(_) => Some(UnixDatagram::unbound()?),
+    };
+
+    // Hands out the epochs, one per interval
+    let mut ticker = Ticker::new(config.clock.now(), Instant::now(), config.acquire_interval)?;
+
+    // Collects the data until it is time to store it
+    let mut pending = PendingData::new(Instant::now());
+
+    // Remember the epoch of the last run, to detect clock jumps
+    let mut last_epoch: Option<u64> = None;
+
+    // Count the runs, to make dropped ones visible
+    let mut sequence: u64 = 0;
+
+    // Allow the operator to pause us
+    let mut pause = PauseControl::register()?;
+
+    // We run until asked to stop
+    while !shutdown.load(Ordering::Relaxed) {
+        // Let loop helper prepare
+        loop_helper.loop_start();
+
+        let now = Instant::now();
+        let wall = config.clock.now();
+        let mut tick = ticker.tick(now);
+
+        if pause.paused() {
+            // Forget the epoch, getting resumed is no clock jump
+            last_epoch = None;
+            loop_helper.loop_sleep();
+            continue;
+        }
+
+        // Did the clock jump since the last run?
+        let threshold = config.clock_jump_threshold + config.acquire_interval.as_secs();
+        let jumped = last_epoch.filter(|last| clock_jump(*last, wall.as_secs(), threshold));
+        if let Some(last) = jumped {
+            warn!(
+                "Clock jump detected, epoch went from {last} to {}",
+                wall.as_secs()
+            );
+        }
+        last_epoch = Some(wall.as_secs());
+        // Follow the clock if it jumped or we drifted away from it
+        if jumped.is_some() || tick.epoch.abs_diff(wall.as_secs()) > threshold {
+            ticker = Ticker::new(wall, now, config.acquire_interval)?;
+            tick = ticker.tick(now);
+        } else if tick.skipped > 0 {
+            plugin.ticks_skipped(tick.skipped, tick.epoch);
+        }
+        // Streaming plugins need the epoch, so provide it
+        let epoch = tick.epoch;
+        sequence += 1;
+
+        if let Some(data) = collect_run(plugin, config, sequence, epoch, jumped)? {
+            pending.push(config, socket.as_ref(), &data, Instant::now())?;
+        }
+
+        // Sleep for the rest of the interval
+        loop_helper.loop_sleep();
+    }
+    // Store what is left, then only the pidfile is left to clean up.
+    pending.finish(config, socket.as_ref(), Instant::now())?;
+    warn!("Received shutdown signal, stopping data acquisition");
+    shutdown_plugin(plugin, config);
+    std::fs::remove_file(&config.pidfile)?;
+    Ok(())
+}
+
+/// Run [MuninPlugin::on_shutdown], only logging its error, there is
+/// still cleanup to do.
+fn shutdown_plugin<P: MuninPlugin + ?Sized>(plugin: &P, config: &Config) {
+    if let Err(e) = plugin.on_shutdown(config) {
+        warn!("Plugin {} failed to shut down: {e:#}", config.plugin_name);
+    }
+}
+
+/// How long from `now` (since the unix epoch) until the next whole
+/// second, see [Config::align_to_second]. Zero if `now` is one.
+fn until_next_second(now: Duration) -> Duration {
+    match now.subsec_nanos() {
+        0 => Duration::ZERO,
+        nanos => Duration::from_nanos(u64::from(1_000_000_000 - nanos)),
+    }
+}
+
+/// Data of daemon runs not stored yet, see [Config::flush_interval].
+#[derive(Debug)]
+struct PendingData {
+    data: Vec<u8>,
+    stored: Instant,
+    /// Values of seconds not complete yet, with a sub-second
+    /// [Config::acquire_interval]
+    subsecond: Option<SubSecondRuns>,
+}
+
+impl PendingData {
+    /// Nothing pending, last stored at `now`
+    fn new(now: Instant) -> Self {
+        Self {
+            data: Vec::new(),
+            stored: now,
+            subsecond: None,
+        }
+    }
+
+    /// Add the `data` of a run, storing everything pending (see
+    /// [store_data]) if [Config::flush_interval] passed since the
+    /// last time. With a sub-second [Config::acquire_interval], the
+    /// values of a second only get added once it is complete.
+    fn push(
+        &mut self,
+        config: &Config,
+        socket: Option<&UnixDatagram>,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<()> {
+        if config.acquire_interval < Duration::from_secs(1) {
+            let runs = self
+                .subsecond
+                .get_or_insert_with(|| SubSecondRuns::new(config.subsecond_aggregation));
+            let rest = runs.add(data);
+            self.data.extend_from_slice(&runs.complete(false));
+            self.data.extend_from_slice(&rest);
+        } else {
+            self.data.extend_from_slice(data);
+        }
+        if now.duration_since(self.stored) >= config.flush_interval {
+            self.store(config, socket, now)?;
+        }
+        Ok(())
+    }
+
+    /// Store everything pending now
+    fn store(
+        &mut self,
+        config: &Config,
+        socket: Option<&UnixDatagram>,
+        now: Instant,
+    ) -> Result<()> {
+        if !self.data.is_empty() {
+            store_data(config, socket, &self.data)?;
+            self.data.clear();
+        }
+        self.stored = now;
+        Ok(())
+    }
+
+    /// Store everything pending, including the values of a second
+    /// not complete yet, when the daemon stops
+    fn finish(
+        &mut self,
+        config: &Config,
+        socket: Option<&UnixDatagram>,
+        now: Instant,
+    ) -> Result<()> {
+        if let Some(runs) = &mut self.subsecond {
+            self.data.extend_from_slice(&runs.complete(true));
+        }
+        self.store(config, socket, now)
+    }
+}
+
+/// Folds the values of daemon runs with a sub-second
+/// [Config::acquire_interval] into one per field and second, using
+/// [Config::subsecond_aggregation]. Munin keeps only one value per
+/// epoch, so the runs in between would get lost.
+#[derive(Debug)]
+struct SubSecondRuns {
+    aggregation: Aggregation,
+    /// Per multigraph section ("" for none), in the order first seen
+    sections: Vec<(String, SubSecondAggregator)>,
+    /// Newest epoch seen, all before are complete
+    latest: u64,
+}
+
+impl SubSecondRuns {
+    fn new(aggregation: Aggregation) -> Self {
+        Self {
+            aggregation,
+            sections: Vec::new(),
+            latest: 0,
+        }
+    }
+
+    /// Take the `EPOCH:VALUE` values out of the `data` of a run,
+    /// returning everything else (like comments), to be stored as is.
+    fn add(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut rest = String::new();
+        let mut rest_section = String::new();
+        let mut section = String::new();
+        for line in String::from_utf8_lossy(data).lines() {
+            if let Some(name) = line.trim().strip_prefix("multigraph ") {
+                section = name.trim().to_string();
+                continue;
+            }
+            let sample = line
+                .parse::<StreamSample<String>>()
+                .ok()
+                .and_then(|sample| match sample.value.as_str() {
+                    "U" => Some((sample.field, sample.epoch, f64::NAN)),
+                    value => Some((sample.field, sample.epoch, value.parse().ok()?)),
+                });
+            let Some((field, epoch, value)) = sample else {
+                if section != rest_section {
+                    rest.push_str(&format!("multigraph {section}\n"));
+                    rest_section = section.clone();
+                }
+                rest.push_str(line);
+                rest.push('\n');
+                continue;
+            };
+            let index = match self.sections.iter().position(|(name, _)| *name == section) {
+                Some(index) => index,
+                None => {
+                    let aggregator = SubSecondAggregator::new(self.aggregation);
+                    self.sections.push((section.clone(), aggregator));
+                    self.sections.len() - 1
+                }
+            };
+            self.sections[index].1.add(&field, epoch, value);
+            self.latest = self.latest.max(epoch);
+        }
+        rest.into_bytes()
+    }
+
+    /// The aggregated values of the complete seconds, or of all with
+    /// `everything`, like when the daemon stops.
+    fn complete(&mut self, everything: bool) -> Vec<u8> {
+        let mut data = String::new();
+        // Values outside of any multigraph section have to come first
+        self.sections.sort_by_key(|(name, _)| !name.is_empty());
+        for (name, aggregator) in &mut self.sections {
+            let samples = match everything {
+                true => aggregator.drain(),
+                false => aggregator.drain_before(self.latest),
+            };
+            if samples.is_empty() {
+                continue;
+            }
+            if !name.is_empty() {
+                data.push_str(&format!("multigraph {name}\n"));
+            }
+            for sample in samples {
+                let value = MuninValue::from(sample.value);
+                data.push_str(&format!(
+                    "{}.value {}:{value}\n",
+                    sample.field, sample.epoch
+                ));
+            }
+        }
+        data.into_bytes()
+    }
+}
+
+/// Writer passing everything on to `inner`, optionally keeping a
+/// copy of it.
+#[derive(Debug)]
+struct TeeWriter<W> {
+    inner: W,
+    copy: Option<Vec<u8>>,
+}
+
+impl<W: Write> TeeWriter<W> {
+    /// Write to `inner`, keeping a copy if `keep` is set
+    fn new(inner: W, keep: bool) -> Self {
+        Self {
+            inner,
+            copy: keep.then(Vec::new),
+        }
+    }
+
+    /// Everything written so far (if asked to keep a copy)
+    fn into_copy(self) -> Vec<u8> {
+        self.copy.unwrap_or_default()
+    }
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.extend_from_slice(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `fieldname.value U` for every field declared in the `config`
+/// output, below their multigraph lines, if any. With an `epoch`, in
+/// the streaming format, `fieldname.value EPOCH:U`.
+fn unknown_values(config: &str, epoch: Option<u64>) -> String {
+    let value = match epoch {
+        Some(epoch) => format!("{epoch}:U"),
+        None => String::from("U"),
+    };
+    let mut unknowns = String::new();
+    let mut current = String::new();
+    for (section, field) in testing::fields_by_section(config, |attribute| attribute != "value") {
+        if section != current {
+            unknowns.push_str(&format!("multigraph {section}\n"));
+            current = section;
+        }
+        unknowns.push_str(&format!("{field}.value {value}\n"));
+    }
+    unknowns
+}
+
+/// The `config` output with the smallest and largest value of each
+/// graph in `data` (the fetch output) appended to its `graph_args`,
+/// see [Config::observed_range]. A graph without `graph_args` gets
+/// one after its `graph_title`, one without values stays as it is.
+fn observed_args(config: &str, data: &[u8]) -> String {
+    let section_of = |line: &str| {
+        line.trim()
+            .strip_prefix("multigraph ")
+            .map(|name| name.trim().to_string())
+    };
+    let mut ranges: BTreeMap<String, ObservedRange> = BTreeMap::new();
+    let mut section = String::new();
+    for line in String::from_utf8_lossy(data).lines() {
+        if let Some(name) = section_of(line) {
+            section = name;
+        } else if let Some((key, value)) = line.trim().split_once(char::is_whitespace) {
+            // Streaming values have their epoch in front
+            let value = value.trim().rsplit(':').next().unwrap_or_default();
+            if let (true, Ok(value)) = (key.ends_with(".value"), value.parse::<f64>()) {
+                ranges.entry(section.clone()).or_default().observe(value);
+            }
+        }
+    }
+
+    let mut with_args = Vec::new();
+    let mut section = String::new();
+    for line in config.lines() {
+        if let Some(name) = section_of(line) {
+            section = name;
+        } else if line.trim().starts_with("graph_args ") {
+            with_args.push(section.clone());
+        }
+    }
+
+    let mut output = String::with_capacity(config.len());
+    let mut section = String::new();
+    for line in config.lines() {
+        output.push_str(line);
+        if let Some(name) = section_of(line) {
+            section = name;
+        } else if let Some(range) = ranges.get(&section) {
+            let args = graph::GraphArgs::new().observed(range);
+            let line = line.trim();
+            if line.starts_with("graph_args ") {
+                output.push_str(&format!(" {args}"));
+            } else if line.starts_with("graph_title ") && !with_args.contains(&section) {
+                output.push_str(&format!("\ngraph_args {args}"));
+            }
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Compare the fields declared by [MuninPlugin::config] with those in
+/// the fetched `data`, logging a warning for every mismatch (see
+/// [testing::field_mismatches]). Used when the MUNIN_PLUGIN_DEBUG
+/// environment variable is set. Returns the mismatches.
+fn check_fields<P: MuninPlugin + ?Sized>(plugin: &P, data: &[u8]) -> Result<Vec<String>> {
+    let mut handle = BufWriter::new(Vec::new());
+    plugin.config(&mut handle)?;
+    let config = String::from_utf8_lossy(&handle.into_inner()?).into_owned();
+    let mismatches = testing::field_mismatches(&config, &String::from_utf8_lossy(data));
+    for mismatch in &mismatches {
+        warn!("{mismatch}");
+    }
+    Ok(mismatches)
+}
+
+/// Log a warning for every value in `data` written without an epoch,
+/// if [Config::daemonize] is set. Returns the warnings.
+fn check_epochs(config: &Config, data: &[u8]) -> Vec<String> {
+    if !config.daemonize {
+        return Vec::new();
+    }
+    let warnings = streaming::missing_epochs(data);
+    for warning in &warnings {
+        warn!("{warning}");
+    }
+    warnings
+}
+
+/// Hand the data of one daemon run to [Config::acquire_sink],
+/// appending it to [Config::plugin_cache] and/or sending it to the
+/// socket.
+///
+/// As the data is meant for a _streaming_ plugin, values written
+/// without an epoch get a warning, see [check_epochs].
+fn store_data(config: &Config, socket: Option<&UnixDatagram>, data: &[u8]) -> Result<()> {
+    check_epochs(config, data);
+    if let (Some(socket), Sink::UnixDatagram(path) | Sink::FileAndUnixDatagram(path)) =
+        (socket, &config.acquire_sink)
+    {
+        // Collector not being there should not kill us
+        if let Err(e) = socket.send_to(data, path) {
+            warn!("Could not send data to {}: {}", path.display(), e);
+        }
+    }
+    if let Sink::File | Sink::FileAndUnixDatagram(_) = &config.acquire_sink {
+        // Fetch must not move the files away while we append
+        let _lock = lock_cache(config)?;
+        for (path, data) in split_caches(config, data) {
+            if let Some(limit) = config.max_cache_bytes {
+                trim_cache(path, limit, config.compress_cache)?;
+            }
+            let cachefile = OpenOptions::new()
+                .create(true) // If not there, create
+                .append(true) // We want to append
+                .open(path)?;
+            write_cache(cachefile, &data, config.compress_cache)?;
+        }
+    }
+    Ok(())
+}
+
+/// Split the `data` of one acquire run up into the cachefiles it
+/// belongs to, see [Config::extra_caches].
+///
+/// Everything after a `multigraph NAME` line with NAME in
+/// [Config::extra_caches] goes to that cache, without the line
+/// itself, as fetch writes it once in front of the data of the
+/// cache. All other data goes to [Config::plugin_cache], which is
+/// always first, even if nothing is there for it.
+fn split_caches<'a>(config: &'a Config, data: &[u8]) -> Vec<(&'a Path, Vec<u8>)> {
+    let mut caches: Vec<(&Path, Vec<u8>)> = vec![(&config.plugin_cache, Vec::new())];
+    if config.extra_caches.is_empty() {
+        caches[0].1.extend_from_slice(data);
+        return caches;
+    }
+    let mut current = 0;
+    for line in data.split_inclusive(|&byte| byte == b'\n') {
+        if let Some(name) = line.strip_prefix(b"multigraph ") {
+            let name = String::from_utf8_lossy(name);
+            match config.extra_caches.get(name.trim()) {
+                Some(path) => {
+                    current = match caches.iter().position(|(known, _)| *known == path) {
+                        Some(index) => index,
+                        None => {
+                            caches.push((path, Vec::new()));
+                            caches.len() - 1
+                        }
+                    };
+                    continue;
+                }
+                None => current = 0,
+            }
+        }
+        caches[current].1.extend_from_slice(line);
+    }
+    caches
+}
+
+/// Move the cachefile at `path` away, to a temporary file in
+/// `tempdir` (see [Config::fetch_tempdir]) or else beside it (so the
+/// rename stays on one filesystem), deleted when dropped.
+fn take_cache(path: &Path, tempdir: Option<&Path>) -> io::Result<NamedTempFile> {
+    let fetchpath = NamedTempFile::new_in(tempdir.unwrap_or_else(|| parent_dir(path)))?;
+    move_cache(path, fetchpath.path(), |from, to| rename(from, to))?;
+    Ok(fetchpath)
+}
+
+/// Move the cachefile `from` to `to` using `rename`. Should that fail
+/// as the two are on different filesystems, copy and remove instead.
+fn move_cache<F>(from: &Path, to: &Path, rename: F) -> io::Result<()>
+where
+    F: FnOnce(&Path, &Path) -> io::Result<()>,
+{
+    match rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            trace!(
+                "Can not rename {} to {}, copying",
+                from.display(),
+                to.display()
+            );
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        other => other,
+    }
+}
+
+/// Keep the fetched cachefile as `last`, see
+/// [Config::keep_fetched_cache], copying it there should it be on
+/// another filesystem (see [Config::fetch_tempdir]).
+fn keep_cache(fetchpath: NamedTempFile, last: &Path) -> io::Result<()> {
+    match fetchpath.persist(last) {
+        Ok(_) => Ok(()),
+        Err(e) if e.error.kind() == io::ErrorKind::CrossesDevices => {
+            std::fs::copy(e.file.path(), last).map(|_| ())
+        }
+        Err(e) => Err(e.error),
+    }
+}
+
+/// Append `data` to the opened cachefile, as a gzip member of its own
+/// if `compressed`. Gzip allows concatenating members, reading them
+/// back with a [MultiGzDecoder] gives all the data.
+fn write_cache<W: Write>(mut cachefile: W, data: &[u8], compressed: bool) -> io::Result<()> {
+    if compressed {
+        let mut encoder = GzEncoder::new(cachefile, Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        Ok(())
+    } else {
+        cachefile.write_all(data)
+    }
+}
+
+/// Copy all of `reader` to `handle`, flushing the handle every
+/// `chunk` bytes (see [Config::fetch_size]), returning the number of
+/// bytes copied.
+fn copy_flushing<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    handle: &mut BufWriter<W>,
+    chunk: usize,
+) -> io::Result<u64> {
+    let mut buffer = vec![0; chunk.max(1)];
+    let mut copied = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        handle.write_all(&buffer[..read])?;
+        handle.flush()?;
+        copied += read as u64;
+    }
+}
+
+/// Copy everything from `reader` to `handle`, except for the
+/// `# last-updated EPOCH` lines of [Config::freshness_comment].
+/// Returns the newest EPOCH seen in them.
+fn copy_stale<R: io::BufRead, W: Write>(
+    reader: &mut R,
+    handle: &mut BufWriter<W>,
+) -> io::Result<Option<u64>> {
+    let mut newest = None;
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        match line.strip_prefix(b"# last-updated ") {
+            Some(epoch) => {
+                let epoch = String::from_utf8_lossy(epoch).trim().parse().ok();
+                newest = newest.max(epoch);
+            }
+            None => handle.write_all(&line)?,
+        }
+        line.clear();
+    }
+    handle.flush()?;
+    Ok(newest)
+}
+
+/// Truncate [Config::plugin_cache] and all [Config::extra_caches],
+/// see [Config::reset_cache_on_start]. Missing ones are fine.
+fn reset_caches(config: &Config) -> Result<()> {
+    let _lock = lock_cache(config)?;
+    for path in std::iter::once(&config.plugin_cache).chain(config.extra_caches.values()) {
+        match OpenOptions::new().write(true).truncate(true).open(path) {
+            Ok(_) => trace!("Emptied cachefile {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Open the cachefile at `path` for reading, decompressing it if
+/// `compressed`.
+fn open_cache(path: &Path, compressed: bool) -> io::Result<Box<dyn io::Read>> {
+    let cachefile = std::fs::File::open(path)?;
+    if compressed {
+        Ok(Box::new(MultiGzDecoder::new(io::BufReader::new(cachefile))))
+    } else {
+        Ok(Box::new(cachefile))
+    }
+}
+
+/// Take the lock serializing appends to [Config::plugin_cache] with
+/// the rename of it in [MuninPlugin::fetch], held until the returned
+/// file is dropped.
+///
+/// The lock is on a separate file beside the cachefile, the cachefile
+/// itself gets renamed away, and a lock on it with it.
+fn lock_cache(config: &Config) -> Result<std::fs::File> {
+    let mut path = config.plugin_cache.clone().into_os_string();
+    path.push(".lock");
+    let lockfile = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    lockfile.lock_exclusive().map_err(PluginError::Lock)?;
+    Ok(lockfile)
+}
+
+/// Drop the oldest lines of the cachefile at `path`, if it is larger
+/// than `limit` bytes, keeping (at most) the newest `limit / 2` bytes,
+/// starting at a line. Returns true if it got trimmed.
+///
+/// A `compressed` cachefile keeps the same share of its uncompressed
+/// lines, written back as a single gzip member.
+fn trim_cache(path: &Path, limit: u64, compressed: bool) -> Result<bool> {
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        // Nothing there (fetch just took it), nothing to trim
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if size <= limit {
+        return Ok(false);
+    }
+    let mut content = Vec::new();
+    open_cache(path, compressed)?.read_to_end(&mut content)?;
+    // Compressed, the limit is about what is on disk, so keep the same
+    // share of the uncompressed data
+    let keep = (content.len() as u64).saturating_mul(limit / 2) / size;
+    let cut = content.len().saturating_sub(keep as usize);
+    // Only keep whole lines
+    let start = content[cut..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(content.len(), |pos| cut + pos + 1);
+    warn!(
+        "Cachefile {} is {} bytes, larger than {}, dropping the oldest {} bytes",
+        path.display(),
+        size,
+        limit,
+        start
+    );
+    // Replace it in one go, so a fetch never sees it half written
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut trimmed = NamedTempFile::new_in(dir)?;
+    write_cache(&mut trimmed, &content[start..], compressed)?;
+    trimmed.persist(path)?;
+    Ok(true)
+}
+
+/// Install the handlers for `SIGTERM` and `SIGINT` if
+/// [Config::catch_signals] is set, returning the flag they set.
+fn register_shutdown(config: &Config) -> Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    if config.catch_signals {
+        signal_hook::flag::register(SIGTERM, Arc::clone(&shutdown))?;
+        signal_hook::flag::register(SIGINT, Arc::clone(&shutdown))?;
+    }
+    Ok(shutdown)
+}
+
+/// Pause and resume requests for the daemon loop, set by the SIGUSR1
+/// and SIGUSR2 handlers.
+struct PauseControl {
+    /// SIGUSR1 got received
+    pause: Arc<AtomicBool>,
+    /// SIGUSR2 got received
+    resume: Arc<AtomicBool>,
+    /// Current state
+    paused: bool,
+}
+
+impl PauseControl {
+    /// Without any signal handlers, nothing sets the flags
+    fn new() -> Self {
+        Self {
+            pause: Arc::new(AtomicBool::new(false)),
+            resume: Arc::new(AtomicBool::new(false)),
+            paused: false,
+        }
+    }
+
+    /// Install the signal handlers
+    fn register() -> Result<Self> {
+        let control = Self::new();
+        signal_hook::flag::register(SIGUSR1, Arc::clone(&control.pause))?;
+        signal_hook::flag::register(SIGUSR2, Arc::clone(&control.resume))?;
+        Ok(control)
+    }
+
+    /// Process signals received since the last call and tell if we
+    /// are paused.
+    fn paused(&mut self) -> bool {
+        if self.pause.swap(false, Ordering::Relaxed) {
+            warn!("Received SIGUSR1, pausing data acquisition");
+            self.paused = true;
+        }
+        if self.resume.swap(false, Ordering::Relaxed) {
+            warn!("Received SIGUSR2, resuming data acquisition");
+            self.paused = false;
+        }
+        self.paused
+    }
+}
+
+/// The line [MuninPlugin::autoconf] answers with, listing the
+/// suggestions (if any) when the plugin can autoconfigure itself, or
+/// the reason (if any) why not.
+fn autoconf_answer(autoconf: &AutoconfResult, suggestions: &[String]) -> String {
+    match (autoconf.ok, &autoconf.reason) {
+        (false, None) => String::from("no"),
+        (false, Some(reason)) => format!("no ({reason})"),
+        (true, _) if suggestions.is_empty() => String::from("yes"),
+        (true, _) => format!("yes ({})", suggestions.join(" ")),
+    }
+}
+
+/// What [daemonizer] sets up the [Daemonize] with
+#[derive(Debug, PartialEq)]
+struct DaemonSettings<'a> {
+    pidfile: &'a Path,
+    chown_pidfile: bool,
+    working_directory: &'a Path,
+}
+
+impl<'a> DaemonSettings<'a> {
+    fn new(config: &'a Config) -> Self {
+        Self {
+            pidfile: &config.pidfile,
+            chown_pidfile: config.chown_pidfile,
+            working_directory: &config.working_directory,
+        }
+    }
+}
+
+/// Prepare the [Daemonize] used by [MuninPlugin::daemon]
+fn daemonizer(config: &Config) -> Daemonize<()> {
+    let settings = DaemonSettings::new(config);
+    Daemonize::new()
+        .pid_file(settings.pidfile)
+        .chown_pid_file(settings.chown_pidfile)
+        .working_directory(settings.working_directory)
+}
+
+/// Make sure the pidfile at `path` contains `pid`, rewriting it in
+/// place if not. Returns true if it had to be corrected.
+///
+/// Does not use a temporary file and a rename, as the lock on the
+/// pidfile is bound to the file, not its name.
+fn ensure_pidfile(path: &Path, pid: u32) -> Result<bool> {
+    let content = std::fs::read_to_string(path)?;
+    if content.trim().parse::<u32>().ok() == Some(pid) {
+        return Ok(false);
+    }
+    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
+    // One write, so readers see either nothing or the whole PID
+    file.write_all(format!("{pid}\n").as_bytes())?;
+    Ok(true)
+}
+
+/// Collect the data of one run of the daemon loop: the markers, what
+/// [MuninPlugin::acquire_tick] wrote (or unknowns, if it says so) and
+/// the freshness comment. None if acquire skipped this run.
+///
+/// The data is collected in memory, so it gets stored (or sent) in
+/// one go. Also ensures we won't have a file open while sleeping,
+/// that fetch just moved away to send out to munin.
+fn collect_run<P: MuninPlugin + ?Sized>(
+    plugin: &mut P,
+    config: &Config,
+    sequence: u64,
+    epoch: u64,
+    jumped: Option<u64>,
+) -> Result<Option<Vec<u8>>> {
+    let mut handle = BufWriter::with_capacity(config.fetch_size, Vec::new());
+    write_markers(&mut handle, config, sequence, epoch, jumped)?;
+    match run_acquire(plugin, &mut handle, config, epoch)? {
+        AcquireResult::Wrote => {}
+        AcquireResult::Skip => {
+            trace!("acquire skipped epoch {epoch}");
+            return Ok(None);
+        }
+        AcquireResult::Unknown => {
+            // Forget whatever got written
+            handle = BufWriter::with_capacity(config.fetch_size, Vec::new());
+            write_markers(&mut handle, config, sequence, epoch, jumped)?;
+            let mut plugin_config = BufWriter::new(Vec::new());
+            plugin.config(&mut plugin_config)?;
+            let plugin_config = String::from_utf8_lossy(&plugin_config.into_inner()?).into_owned();
+            handle.write_all(unknown_values(&plugin_config, Some(epoch)).as_bytes())?;
+        }
+    }
+    if config.freshness_comment {
+        writeln!(handle, "# last-updated {epoch}")?;
+    }
+    Ok(Some(handle.into_inner()?))
+}
+
+/// Call [MuninPlugin::acquire_tick] from the daemon loop, with
+/// [Config::debug_stdout] watching stdout while it runs, then tell
+/// [MuninPlugin::acquire_took] how long it took.
+fn run_acquire<P: MuninPlugin + ?Sized, W: Write>(
+    plugin: &mut P,
+    handle: &mut BufWriter<W>,
+    config: &Config,
+    epoch: u64,
+) -> Result<AcquireResult> {
+    let started = Instant::now();
+    let result = watch_stdout(config, epoch, || {
+        call_acquire(plugin, handle, config, epoch)
+    });
+    plugin.acquire_took(started.elapsed(), config, epoch);
+    result
+}
+
+/// Run `acquire`, warning if it wrote to stdout, when
+/// [Config::debug_stdout] is set.
+fn watch_stdout<T>(config: &Config, epoch: u64, acquire: impl FnOnce() -> T) -> T {
+    let guard = match config.debug_stdout {
+        true => WriteGuard::stdout(),
+        false => None,
+    };
+    let result = acquire();
+    if let Some(guard) = guard {
+        guard.check(epoch);
+    }
+    result
+}
+
+/// Notices writes to a file, by comparing its size, see
+/// [Config::debug_stdout].
+#[derive(Debug)]
+struct WriteGuard {
+    file: File,
+    len: u64,
+}
+
+impl WriteGuard {
+    /// Watch stdout, None if it is no file
+    fn stdout() -> Option<Self> {
+        // Whatever print! still buffers would count as ours otherwise
+        let _ = io::stdout().flush();
+        Self::new(io::stdout().as_fd())
+    }
+
+    /// Watch the file behind `fd`, None if it is no (regular) file
+    fn new(fd: BorrowedFd<'_>) -> Option<Self> {
+        let file = File::from(fd.try_clone_to_owned().ok()?);
+        let len = Self::len(&file)?;
+        Some(Self { file, len })
+    }
+
+    fn len(file: &File) -> Option<u64> {
+        let metadata = file.metadata().ok()?;
+        metadata.is_file().then_some(metadata.len())
+    }
+
+    /// Warn if something got written since the guard got created,
+    /// returns true if so.
+    fn check(&self, epoch: u64) -> bool {
+        let _ = io::stdout().flush();
+        match Self::len(&self.file) {
+            Some(len) if len != self.len => {
+                warn!(
+                    "acquire at epoch {epoch} wrote {} bytes to stdout instead of its handle",
+                    len.abs_diff(self.len)
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Did a run of acquire take longer than [Config::acquire_interval]?
+fn over_budget(took: Duration, config: &Config) -> bool {
+    took > config.acquire_interval
+}
+
+/// Call [MuninPlugin::acquire_tick], catching a panic in it if
+/// [Config::catch_acquire_panics] is set, skipping that run.
+fn call_acquire<P: MuninPlugin + ?Sized, W: Write>(
+    plugin: &mut P,
+    handle: &mut BufWriter<W>,
+    config: &Config,
+    epoch: u64,
+) -> Result<AcquireResult> {
+    if !config.catch_acquire_panics {
+        return plugin.acquire_tick(handle, config, epoch);
+    }
+    // The plugin asked for it, so we claim unwind safety, see the
+    // documentation of catch_acquire_panics.
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        plugin.acquire_tick(handle, config, epoch)
+    })) {
+        Ok(result) => result,
+        Err(e) => {
+            let reason = e
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("unknown reason"));
+            warn!("acquire panicked at epoch {epoch}: {reason}, skipping this run");
+            Ok(AcquireResult::Skip)
+        }
+    }
+}
+
+/// Write the diagnostic comment lines the config asks for, before
+/// the data of a daemon run. `jumped` holds the previous epoch if a
+/// clock jump got detected.
+fn write_markers<W: Write>(
+    handle: &mut BufWriter<W>,
+    config: &Config,
+    sequence: u64,
+    epoch: u64,
+    jumped: Option<u64>,
+) -> Result<()> {
+    if config.debug_sequence {
+        writeln!(handle, "# sequence {sequence} epoch {epoch}")?;
+    }
+    if let Some(last) = jumped.filter(|_| config.clock_jump_marker) {
+        writeln!(handle, "# clock jump from {last} to {epoch}")?;
+    }
+    Ok(())
+}
+
+/// Check if the acquire daemon of a streaming plugin runs, the way
+/// [Config::lock_strategy] says.
+fn daemon_running(config: &Config) -> Result<bool> {
+    let running = match config.lock_strategy {
+        LockStrategy::Flock => pidfile_locked(&config.pidfile)?,
+        LockStrategy::PidCheck => pid_alive(&config.pidfile),
+        LockStrategy::Both => pidfile_locked(&config.pidfile)? && pid_alive(&config.pidfile),
+    };
+    Ok(running)
+}
+
+/// Check if someone holds the lock on the pidfile at `path`, by
+/// trying to take it. No pidfile, no lock.
+fn pidfile_locked(path: &Path) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let pidfile = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    Ok(pidfile.try_lock_exclusive().is_err())
+}
+
+/// Check if the process with the PID in the pidfile at `path` exists.
+/// No (readable) pidfile, or no PID in it, means it does not.
+fn pid_alive(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| content.trim().parse::<u32>().ok())
+        .is_some_and(|pid| Path::new(&format!("/proc/{pid}")).exists())
+}
+
+/// Check if the acquire daemon runs (see [daemon_running]) and the
+/// newest data it wrote (see [newest_data]) is not older than
+/// [Config::healthcheck_max_age]. Returns a short status text, as
+/// error if something is wrong.
+fn check_health(config: &Config, now: u64) -> std::result::Result<String, String> {
+    let running = daemon_running(config).map_err(|e| e.to_string())?;
+    if !running {
+        return Err(format!(
+            "acquire daemon for {} not running",
+            config.plugin_name
+        ));
+    }
+
+    let newest = newest_data(config)?
+        .ok_or_else(|| format!("no data in {}", config.plugin_cache.display()))?;
+    let age = now.saturating_sub(newest);
+    if age > config.healthcheck_max_age {
+        return Err(format!("newest data is {age}s old"));
+    }
+    Ok(format!("acquire daemon running, newest data is {age}s old"))
+}
+
+/// The epoch of the newest data the acquire daemon wrote: the newest
+/// EPOCH out of all the fieldname.value EPOCH:VALUE lines in
+/// [Config::plugin_cache] and [Config::extra_caches]. Right after a
+/// fetch took those away, the modification time of a cache the
+/// daemon started again, else the time of that fetch (see
+/// [Config::last_fetch]).
+fn newest_data(config: &Config) -> std::result::Result<Option<u64>, String> {
+    let mut newest = None;
+    let mut modified = None;
+    for path in std::iter::once(&config.plugin_cache).chain(config.extra_caches.values()) {
+        let mut cache = String::new();
+        match open_cache(path, config.compress_cache)
+            .and_then(|mut cachefile| cachefile.read_to_string(&mut cache))
+        {
+            Ok(_) => {}
+            // Fetched, and nothing new yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("can not read {}: {e}", path.display())),
+        }
+        let epoch = cache
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(1)?.split_once(':'))
+            .filter_map(|(epoch, _)| epoch.parse::<u64>().ok())
+            .max();
+        newest = newest.max(epoch);
+        let mtime = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs());
+        modified = modified.max(mtime);
+    }
+    Ok(newest.or(modified).or_else(|| config.last_fetch()))
+}
+
+/// Check that `dir` is writable, by creating a file in it
+fn check_writable(what: &str, dir: &Path) -> std::result::Result<String, String> {
+    match NamedTempFile::new_in(dir) {
+        Ok(_) => Ok(format!("{what} {} is writable", dir.display())),
+        Err(e) => Err(format!("{what} {} is not writable: {e}", dir.display())),
+    }
+}
+
+/// The directory `path` is in
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+}
+
+/// Check the directories and files the plugin uses, for the `verify`
+/// argument. One entry per check, describing the result.
+fn verify_environment(config: &Config) -> Vec<std::result::Result<String, String>> {
+    let mut checks = vec![check_writable("State directory", &config.plugin_statedir)];
+    let piddir = parent_dir(&config.pidfile);
+    checks.push(if piddir.is_dir() {
+        Ok(format!("Pidfile directory {} exists", piddir.display()))
+    } else {
+        Err(format!(
+            "Pidfile directory {} does not exist",
+            piddir.display()
+        ))
+    });
+    if config.daemonize {
+        checks.push(check_writable(
+            "Cache directory",
+            parent_dir(&config.plugin_cache),
+        ));
+        checks.push(match pidfile_locked(&config.pidfile) {
+            Ok(true) => Ok(format!(
+                "Pidfile {} is locked by the running acquire daemon",
+                config.pidfile.display()
+            )),
+            Ok(false) => Ok(format!(
+                "Pidfile {} can be locked",
+                config.pidfile.display()
+            )),
+            Err(e) => Err(format!(
+                "Pidfile {} can not be locked: {e}",
+                config.pidfile.display()
+            )),
+        });
+    }
+    // Only for the record, what munin told us
+    if let Some(libdir) = &config.munin_libdir {
+        checks.push(Ok(format!("Munin libdir is {}", libdir.display())));
+    }
+    if let Some(sock) = &config.rrdcached_sock {
+        checks.push(Ok(format!("Munin uses rrdcached at {}", sock.display())));
+    }
+    checks
+}
+
+/// The report printed for `verify`, one line per check, and if all
+/// of them passed.
+fn verify_report(config: &Config) -> (String, bool) {
+    let mut report = String::new();
+    let mut ok = true;
+    for check in verify_environment(config) {
+        match check {
+            Ok(line) => report.push_str(&format!("OK: {line}\n")),
+            Err(line) => {
+                ok = false;
+                report.push_str(&format!("FAILED: {line}\n"))
+            }
+        }
+    }
+    (report, ok)
+}
+
+/// Turn the interval between two runs into the rate per second the
+/// [LoopHelper] wants.
+fn target_rate(interval: Duration) -> Result<f64> {
+    if interval.is_zero() {
+        return Err(anyhow!("acquire_interval needs to be larger than zero"));
+    }
+    Ok(1.0 / interval.as_secs_f64())
+}
+
+/// Check if the clock jumped between two successive epochs, that is,
+/// if it went backwards or forward by more than `threshold` seconds.
+fn clock_jump(last: u64, epoch: u64, threshold: u64) -> bool {
+    epoch < last || epoch - last > threshold
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Our plugin struct
+    #[derive(Debug)]
+    struct TestPlugin;
+    impl MuninPlugin for TestPlugin {
+        fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+            writeln!(handle, "This is a test plugin")?;
+            writeln!(handle, "There is no config")?;
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            config: &Config,
+            epoch: u64,
+        ) -> Result<()> {
+            writeln!(handle, "This is a value for {}", config.plugin_name)?;
+            writeln!(handle, "And one more value with epoch {}", epoch)?;
+            Ok(())
+        }
+    }
+
+    /// A daemon config for `name`, with all its files in `dir` and a
+    /// short interval, so loops produce data quickly
+    fn daemon_config(name: &str, dir: &Path) -> Config {
+        let mut config = Config::new_daemon(String::from(name));
+        config.plugin_statedir = dir.to_path_buf();
+        config.plugin_cache = dir.join(format!("{name}.value"));
+        config.pidfile = dir.join(format!("{name}.pid"));
+        config.last_fetch_file = dir.join(format!("{name}.lastfetch"));
+        config.statefile = dir.join(format!("{name}.state"));
+        config.acquire_interval = Duration::from_millis(50);
+        config
+    }
+
+    /// Run [daemon_loop] for `plugin` on its own thread, until it
+    /// stored data. Then call `running`, stop the loop as SIGTERM
+    /// would and wait for it to finish.
+    fn run_daemon_loop<P: MuninPlugin + Send + 'static>(
+        mut plugin: P,
+        config: &Config,
+        running: impl FnOnce(),
+    ) {
+        // The pidfile the loop removes when done
+        std::fs::write(&config.pidfile, "42\n").unwrap();
+        // What the SIGTERM handler sets, raising the signal would hit
+        // every test running
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let loopshutdown = Arc::clone(&shutdown);
+        let loopconfig = config.clone();
+        let daemon = thread::spawn(move || daemon_loop(&mut plugin, &loopconfig, loopshutdown));
+        while !config.plugin_cache.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        running();
+        shutdown.store(true, Ordering::Relaxed);
+        daemon.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_harness() {
+        let harness = testing::TestHarness;
+        let config = Config::new(String::from("harness"));
+        assert_eq!(
+            harness.config_output(&TestPlugin).unwrap(),
+            "This is a test plugin\nThere is no config\n"
+        );
+        assert_eq!(
+            harness
+                .acquire_output(&mut TestPlugin, &config, 42)
+                .unwrap(),
+            "This is a value for harness\nAnd one more value with epoch 42\n"
+        );
+        // Not daemonizing, fetch is acquire with epoch 0
+        assert_eq!(
+            harness.fetch_output(&mut TestPlugin, &config).unwrap(),
+            "This is a value for harness\nAnd one more value with epoch 0\n"
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let test = TestPlugin;
+
+        // We want to check the output of config contains our test string
+        // above, so have it "write" it to a variable, then check if
+        // the variable contains what we want
+        let checktext = Vec::new();
+        let mut handle = BufWriter::new(checktext);
+        test.config(&mut handle).unwrap();
+        handle.flush().unwrap();
+
+        // And now check what got "written" into the variable
+        let (recovered_writer, _buffered_data) = handle.into_parts();
+        let output = String::from_utf8(recovered_writer).unwrap();
+        assert_eq!(
+            output,
+            String::from("This is a test plugin\nThere is no config\n")
+        );
+    }
+
+    #[test]
+    fn test_config_output_dirtyconfig() {
+        let mut test = TestPlugin;
+        let config = Config::new(String::from("dirty"));
+
+        let output = testing::dirtyconfig_output(&mut test, &config, false).unwrap();
+        assert_eq!(output, "This is a test plugin\nThere is no config\n");
+
+        let output = testing::dirtyconfig_output(&mut test, &config, true).unwrap();
+        assert_eq!(
+            output,
+            "This is a test plugin\nThere is no config\nThis is a value for dirty\nAnd one more value with epoch 0\n"
+        );
+    }
+
+    #[test]
+    fn test_observed_range() {
+        struct RangePlugin;
+        impl MuninPlugin for RangePlugin {
+            fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+                graph::MultiGraph::new()
+                    .graph(
+                        "temp",
+                        graph::Graph::new("Temperature")
+                            .args("--base 1000")
+                            .field(graph::Field::new("cpu"))
+                            .field(graph::Field::new("disk")),
+                    )
+                    .graph(
+                        "fan",
+                        graph::Graph::new("Fan").field(graph::Field::new("rpm")),
+                    )
+                    .graph(
+                        "idle",
+                        graph::Graph::new("Idle").field(graph::Field::new("x")),
+                    )
+                    .write_config(handle)
+            }
+            fn acquire<W: Write>(
+                &mut self,
+                handle: &mut BufWriter<W>,
+                _config: &Config,
+                _epoch: u64,
+            ) -> Result<()> {
+                writeln!(handle, "multigraph temp")?;
+                writeln!(handle, "cpu.value 1650000000:45.5")?;
+                writeln!(handle, "disk.value 1650000000:31")?;
+                writeln!(handle, "cpu.value 1650000001:52")?;
+                writeln!(handle, "multigraph fan")?;
+                writeln!(handle, "rpm.value 1200")?;
+                writeln!(handle, "multigraph idle")?;
+                writeln!(handle, "x.value U")?;
+                Ok(())
+            }
+        }
+
+        let mut config = Config::new(String::from("range"));
+        config.dirtyconfig = true;
+        // Off, the config stays as it is
+        let output = testing::dirtyconfig_output(&mut RangePlugin, &config, true).unwrap();
+        assert!(output.contains("graph_args --base 1000\n"));
+        assert!(!output.contains("limit"));
+
+        config.observed_range = true;
+        let mut handle = BufWriter::new(Vec::new());
+        RangePlugin.config_output(&mut handle, &config).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        let expected = [
+            "multigraph temp",
+            "graph_title Temperature",
+            "graph_args --base 1000 --lower-limit 31 --upper-limit 52",
+            "graph_order cpu disk",
+            "cpu.label cpu",
+            "disk.label disk",
+            "multigraph fan",
+            "graph_title Fan",
+            "graph_args --lower-limit 1200 --upper-limit 1200",
+            "rpm.label rpm",
+            "multigraph idle",
+            "graph_title Idle",
+            "x.label x",
+        ];
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[..expected.len()], expected);
+        // Followed by the data, unchanged
+        assert_eq!(lines[expected.len()], "multigraph temp");
+        assert!(output.ends_with("x.value U\n"));
+
+        // Not without dirtyconfig, there is no data to derive it from
+        config.dirtyconfig = false;
+        let mut handle = BufWriter::new(Vec::new());
+        RangePlugin.config_output(&mut handle, &config).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert!(!output.contains("limit"));
+    }
+
+    #[test]
+    fn test_config_output_single_pass() {
+        // Records every write reaching it, as one chunk each
+        #[derive(Default)]
+        struct Chunks(Vec<Vec<u8>>);
+        impl Write for Chunks {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut config = Config::new(String::from("single"));
+        config.dirtyconfig = true;
+        let mut handle = BufWriter::new(Chunks::default());
+        TestPlugin.config_output(&mut handle, &config).unwrap();
+        handle.flush().unwrap();
+        let chunks = handle.into_inner().ok().unwrap().0;
+        // Config and data went out in one go, config first
+        assert_eq!(
+            chunks,
+            [b"This is a test plugin\nThere is no config\nThis is a value for single\nAnd one more value with epoch 0\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_run_once() {
+        let mut config = Config::new(String::from("once"));
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.run_once(&mut handle, &config).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "# config\nThis is a test plugin\nThere is no config\n# fetch\nThis is a value for once\nAnd one more value with epoch 0\n"
+        );
+
+        // Streaming plugins get a real epoch, without any cachefile
+        config.daemonize = true;
+        config.plugin_cache = PathBuf::from("/nonexistent/once.value");
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.run_once(&mut handle, &config).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert!(output.starts_with("# config\nThis is a test plugin\n"));
+        assert!(output.contains("# fetch\nThis is a value for once\n"));
+        assert!(!output.contains("epoch 0"));
+    }
+
+    #[test]
+    fn test_unknown_values() {
+        let config = "graph_title Load\nload.label load\nload.warning 10\nfree.label free\nmultigraph disk\nread.label read\n";
+        assert_eq!(
+            unknown_values(config, None),
+            "load.value U\nfree.value U\nmultigraph disk\nread.value U\n"
+        );
+        assert_eq!(
+            unknown_values(config, Some(1650000000)),
+            "load.value 1650000000:U\nfree.value 1650000000:U\nmultigraph disk\nread.value 1650000000:U\n"
+        );
+    }
+
+    #[test]
+    fn test_daemon_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("running"));
+        config.pidfile = dir.path().join("running.pid");
+        config.lock_strategy = LockStrategy::PidCheck;
+        assert!(!daemon_running(&config).unwrap());
+
+        // A live PID, ours
+        std::fs::write(&config.pidfile, format!("{}\n", std::process::id())).unwrap();
+        assert!(daemon_running(&config).unwrap());
+        // Not locked, though
+        config.lock_strategy = LockStrategy::Both;
+        assert!(!daemon_running(&config).unwrap());
+        let lock = std::fs::File::open(&config.pidfile).unwrap();
+        lock.lock_exclusive().unwrap();
+        assert!(daemon_running(&config).unwrap());
+
+        // A dead PID, of a child that finished
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        std::fs::write(&config.pidfile, format!("{pid}\n")).unwrap();
+        config.lock_strategy = LockStrategy::PidCheck;
+        assert!(!daemon_running(&config).unwrap());
+        config.lock_strategy = LockStrategy::Flock;
+        assert!(daemon_running(&config).unwrap());
+        drop(lock);
+        assert!(!daemon_running(&config).unwrap());
+
+        // Garbage is no PID
+        std::fs::write(&config.pidfile, "lala\n").unwrap();
+        config.lock_strategy = LockStrategy::PidCheck;
+        assert!(!daemon_running(&config).unwrap());
+    }
+
+    #[test]
+    fn test_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("if_eth0");
+        let target = std::fs::canonicalize(env::current_exe().unwrap()).unwrap();
+
+        TestPlugin.install("if_eth0", dir.path()).unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), target);
+        // Again is fine
+        TestPlugin.install("if_eth0", dir.path()).unwrap();
+        TestPlugin.uninstall("if_eth0", dir.path()).unwrap();
+        assert!(!link.exists());
+        // Gone already is fine, too
+        TestPlugin.uninstall("if_eth0", dir.path()).unwrap();
+
+        // Not ours to touch
+        std::os::unix::fs::symlink("/usr/share/munin/plugins/if_", &link).unwrap();
+        let err = TestPlugin.install("if_eth0", dir.path()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("pointing to /usr/share/munin/plugins/if_"));
+        assert!(TestPlugin.uninstall("if_eth0", dir.path()).is_err());
+        std::fs::remove_file(&link).unwrap();
+        std::fs::write(&link, "#!/bin/sh\n").unwrap();
+        let err = TestPlugin.install("if_eth0", dir.path()).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("already exists, and is no symlink"));
+
+        // Nowhere to put it
+        let err = TestPlugin
+            .install("if_eth0", &dir.path().join("missing"))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Can not create"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("verify", dir.path());
+        config.munin_libdir = None;
+        config.rrdcached_sock = None;
+        let (report, ok) = verify_report(&config);
+        assert!(ok, "{report}");
+        assert_eq!(report.lines().count(), 4);
+        assert!(report.lines().all(|line| line.starts_with("OK: ")));
+
+        // The environment munin told about gets reported
+        config.munin_libdir = Some(PathBuf::from("/usr/share/munin"));
+        config.rrdcached_sock = Some(PathBuf::from("/run/munin/rrdcached.sock"));
+        let (report, ok) = verify_report(&config);
+        assert!(ok, "{report}");
+        assert!(report.ends_with(
+            "OK: Munin libdir is /usr/share/munin\nOK: Munin uses rrdcached at /run/munin/rrdcached.sock\n"
+        ));
+        config.munin_libdir = None;
+        config.rrdcached_sock = None;
+
+        // Read-only, even for root
+        config.plugin_cache = PathBuf::from("/proc/verify.value");
+        config.pidfile = dir.path().join("missing").join("verify.pid");
+        let (report, ok) = verify_report(&config);
+        assert!(!ok);
+        let failed: Vec<&str> = report
+            .lines()
+            .filter(|line| line.starts_with("FAILED: "))
+            .collect();
+        assert_eq!(failed.len(), 2, "{report}");
+        assert!(failed[0].starts_with("FAILED: Pidfile directory"));
+        assert!(failed[1].starts_with("FAILED: Cache directory /proc is not writable"));
+    }
+
+    #[test]
+    fn test_create_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("nested"));
+        config.plugin_cache = dir.path().join("cache/deeper/nested.value");
+        config.pidfile = dir.path().join("run/nested.pid");
+        create_dirs(&config).unwrap();
+        assert!(dir.path().join("cache/deeper").is_dir());
+        assert!(dir.path().join("run").is_dir());
+        // Existing ones are fine
+        create_dirs(&config).unwrap();
+
+        // Read-only, even for root
+        config.plugin_cache = PathBuf::from("/proc/nested/nested.value");
+        let err = create_dirs(&config).unwrap_err().to_string();
+        assert!(
+            err.starts_with("Can not create directory /proc/nested for the cachefile"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_foreground() {
+        let dir = tempfile::tempdir().unwrap();
+        env::set_var("MUNIN_PLUGIN_FOREGROUND", "1");
+        let mut config = Config::new_daemon(String::from("foreground"));
+        env::remove_var("MUNIN_PLUGIN_FOREGROUND");
+        assert!(config.foreground);
+        config.pidfile = dir.path().join("foreground.pid");
+        std::fs::write(&config.pidfile, "123456789\n").unwrap();
+
+        // Still the same process, nothing detached
+        let pidfile = prepare_daemon(&config).unwrap().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&config.pidfile).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        assert!(daemon_running(&config).unwrap());
+
+        // A second one does not get the lock
+        let err = PluginError::from(prepare_daemon(&config).unwrap_err());
+        assert!(matches!(err, PluginError::Daemonize(_)), "{err}");
+        drop(pidfile);
+        assert!(!daemon_running(&config).unwrap());
+    }
+
+    #[test]
+    fn test_write_guard() {
+        let mut file = tempfile::tempfile().unwrap();
+        let guard = WriteGuard::new(file.as_fd()).unwrap();
+        assert!(!guard.check(1650000000));
+        file.write_all(b"load.value 1\n").unwrap();
+        let warnings = crate::testing::warnings_of(|| assert!(guard.check(1650000000)));
+        assert_eq!(
+            warnings,
+            vec!["acquire at epoch 1650000000 wrote 13 bytes to stdout instead of its handle"]
+        );
+
+        // Nothing to compare on a device
+        let null = File::options().write(true).open("/dev/null").unwrap();
+        assert!(WriteGuard::new(null.as_fd()).is_none());
+    }
+
+    #[test]
+    fn test_check_fields() {
+        // Declares load, but writes lood
+        struct TypoPlugin;
+        impl MuninPlugin for TypoPlugin {
+            fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+                writeln!(handle, "graph_title Load")?;
+                writeln!(handle, "load.label load")?;
+                Ok(())
+            }
+            fn acquire<W: Write>(
+                &mut self,
+                handle: &mut BufWriter<W>,
+                _config: &Config,
+                _epoch: u64,
+            ) -> Result<()> {
+                writeln!(handle, "lood.value 42")?;
+                Ok(())
+            }
+        }
+
+        let config = Config::new(String::from("typo"));
+        let mut handle = BufWriter::new(TeeWriter::new(Vec::new(), true));
+        TypoPlugin.fetch(&mut handle, &config).unwrap();
+        handle.flush().unwrap();
+        let (tee, _) = handle.into_parts();
+        assert_eq!(tee.inner, b"lood.value 42\n");
+        let data = tee.into_copy();
+        assert_eq!(data, b"lood.value 42\n");
+
+        let mismatches = check_fields(&TypoPlugin, &data).unwrap();
+        assert_eq!(mismatches.len(), 2);
+        assert!(mismatches[0].contains("lood"));
+        assert!(check_fields(&TypoPlugin, b"load.value 42\n")
+            .unwrap()
+            .is_empty());
+
+        // No copy, if not asked for
+        let mut tee = TeeWriter::new(Vec::new(), false);
+        tee.write_all(b"load.value 1\n").unwrap();
+        assert!(tee.into_copy().is_empty());
+    }
+
+    #[test]
+    fn test_fetch_standard() {
+        let mut test = TestPlugin;
+
+        // We want to check the output of fetch contains our test string
+        // above, so have it "write" it to a variable, then check if
+        // the variable contains what we want
+        let checktext = Vec::new();
+        let mut handle = BufWriter::new(checktext);
+        test.fetch(&mut handle, &config::Config::new("test".to_string()))
+            .unwrap();
+        handle.flush().unwrap();
+
+        // And now check what got "written" into the variable
+        let (recovered_writer, _buffered_data) = handle.into_parts();
+        let output = String::from_utf8(recovered_writer).unwrap();
+        assert_eq!(
+            output,
+            String::from("This is a value for test\nAnd one more value with epoch 0\n")
+        );
+    }
+
+    #[test]
+    fn test_fetch_streaming() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new(String::from("testplugin"));
+        config.daemonize = true;
+        config.fetch_size = 16384;
+        config.last_fetch_file = statedir.path().join("testplugin.lastfetch");
+
+        let mut test = TestPlugin {};
+
+        // We need a temporary file
+        let fetchpath = NamedTempFile::new_in(
             config
                 .plugin_cache
                 .parent()
@@ -637,36 +2928,1080 @@ mod tests {
         )
         .unwrap();
 
-        {
-            // Setup a bufwriter as daemon() does.
-            let mut handle = BufWriter::with_capacity(
-                config.fetch_size,
-                OpenOptions::new()
-                    .create(true) // If not there, create
-                    .write(true) // We want to write
-                    .append(true) // We want to append
-                    .open(&fetchpath)
-                    .unwrap(),
+        {
+            // Setup a bufwriter as daemon() does.
+            let mut handle = BufWriter::with_capacity(
+                config.fetch_size,
+                OpenOptions::new()
+                    .create(true) // If not there, create
+                    .append(true) // We want to append
+                    .open(&fetchpath)
+                    .unwrap(),
+            );
+
+            // And have acquire write to it
+            test.acquire(&mut handle, &config, 42).unwrap();
+        }
+
+        // And we want to access the tempfile and read from it
+        (_, config.plugin_cache) = fetchpath.keep().unwrap();
+        let checktext = Vec::new();
+        let mut handle = BufWriter::new(checktext);
+
+        test.fetch(&mut handle, &config).unwrap();
+        handle.flush().unwrap();
+        let (recovered_writer, _buffered_data) = handle.into_parts();
+        let output = String::from_utf8(recovered_writer).unwrap();
+
+        assert_eq!(
+            output,
+            String::from("This is a value for testplugin\nAnd one more value with epoch 42\n")
+        );
+    }
+
+    #[test]
+    fn test_fetch_streaming_datagram() {
+        let mut config = Config::new_daemon(String::from("testplugin-datagram"));
+        config.acquire_sink = Sink::UnixDatagram(PathBuf::from("/nonexistent/socket"));
+
+        // No cachefile exists, but fetch must not try to use one.
+        let mut test = TestPlugin;
+        let mut handle = BufWriter::new(Vec::new());
+        test.fetch(&mut handle, &config).unwrap();
+        let (recovered_writer, _buffered_data) = handle.into_parts();
+        assert!(recovered_writer.is_empty());
+    }
+
+    #[test]
+    fn test_pause_control() {
+        assert!(!PauseControl::register().unwrap().paused());
+
+        // Set the flags as the handlers would, raising the signals
+        // would pause every daemon loop of the tests running
+        let mut pause = PauseControl::new();
+        assert!(!pause.paused());
+        pause.pause.store(true, Ordering::Relaxed);
+        assert!(pause.paused());
+        // Stays paused until told otherwise
+        assert!(pause.paused());
+        pause.resume.store(true, Ordering::Relaxed);
+        assert!(!pause.paused());
+    }
+
+    // Panics on every second call
+    struct PanicPlugin {
+        calls: u64,
+    }
+    impl MuninPlugin for PanicPlugin {
+        fn config<W: Write>(&self, _handle: &mut BufWriter<W>) -> Result<()> {
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            _config: &Config,
+            epoch: u64,
+        ) -> Result<()> {
+            self.calls += 1;
+            if self.calls % 2 == 0 {
+                panic!("Oops at {}", epoch);
+            }
+            writeln!(handle, "test.value {}:{}", epoch, self.calls)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_acquire_panics() {
+        let mut config = Config::new_daemon(String::from("panicker"));
+        config.catch_acquire_panics = true;
+        let mut test = PanicPlugin { calls: 0 };
+        let mut handle = BufWriter::new(Vec::new());
+        for epoch in 1..=3 {
+            run_acquire(&mut test, &mut handle, &config, epoch).unwrap();
+        }
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "test.value 1:1\ntest.value 3:3\n");
+
+        // Without catching, the panic goes through
+        config.catch_acquire_panics = false;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut handle = BufWriter::new(Vec::new());
+            run_acquire(&mut test, &mut handle, &config, 4)
+        }));
+        assert!(result.is_err());
+    }
+
+    // Result of acquire_tick depends on the epoch
+    struct FlakyPlugin;
+    impl MuninPlugin for FlakyPlugin {
+        fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+            writeln!(handle, "flaky.label flaky")?;
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            _config: &Config,
+            epoch: u64,
+        ) -> Result<()> {
+            writeln!(handle, "flaky.value {epoch}:1")?;
+            Ok(())
+        }
+        fn acquire_tick<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            config: &Config,
+            epoch: u64,
+        ) -> Result<AcquireResult> {
+            match epoch % 3 {
+                0 | 1 => {
+                    // Half a line, to be thrown away
+                    write!(handle, "flaky.val")?;
+                    Ok(if epoch % 3 == 0 {
+                        AcquireResult::Skip
+                    } else {
+                        AcquireResult::Unknown
+                    })
+                }
+                _ => {
+                    self.acquire(handle, config, epoch)?;
+                    Ok(AcquireResult::Wrote)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_acquire_result() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("flaky"));
+        config.plugin_cache = statedir.path().join("flaky.value");
+        config.freshness_comment = true;
+
+        // Skip stores nothing at all, not even the comments
+        assert_eq!(
+            collect_run(&mut FlakyPlugin, &config, 1, 3, None).unwrap(),
+            None
+        );
+        assert_eq!(
+            TestPlugin
+                .acquire_tick(&mut BufWriter::new(Vec::new()), &config, 3)
+                .unwrap(),
+            AcquireResult::Wrote
+        );
+
+        for epoch in 3..6 {
+            if let Some(data) = collect_run(&mut FlakyPlugin, &config, 1, epoch, None).unwrap() {
+                store_data(&config, None, &data).unwrap();
+            }
+        }
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        assert_eq!(
+            cache,
+            "flaky.value 4:U\n# last-updated 4\nflaky.value 5:1\n# last-updated 5\n"
+        );
+
+        // Fetch hands on only the newest of the lines
+        config.last_fetch_file = statedir.path().join("flaky.lastfetch");
+        for epoch in 6..9 {
+            if let Some(data) = collect_run(&mut FlakyPlugin, &config, 1, epoch, None).unwrap() {
+                store_data(&config, None, &data).unwrap();
+            }
+        }
+        let mut handle = BufWriter::new(Vec::new());
+        FlakyPlugin.fetch(&mut handle, &config).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output.matches("# last-updated").count(), 1);
+        assert!(output.ends_with("\n# last-updated 8\n"));
+    }
+
+    #[test]
+    fn test_subsecond_runs() {
+        let mut runs = SubSecondRuns::new(Aggregation::Mean);
+        let rest = runs.add(b"# sequence 1 epoch 100\nmultigraph temp\ncpu.value 100:40\ndisk.value 100:U\nnote.value 7\n");
+        // Nothing complete yet, the comment and the value nobody can
+        // fold go through
+        assert!(runs.complete(false).is_empty());
+        assert_eq!(
+            String::from_utf8(rest).unwrap(),
+            "# sequence 1 epoch 100\nmultigraph temp\nnote.value 7\n"
+        );
+        runs.add(b"multigraph temp\ncpu.value 100:50\nmultigraph fan\nrpm.value 100:1200\n");
+        runs.add(b"multigraph temp\ncpu.value 101:60\n");
+        assert_eq!(
+            String::from_utf8(runs.complete(false)).unwrap(),
+            "multigraph temp\ncpu.value 100:45\ndisk.value 100:U\nmultigraph fan\nrpm.value 100:1200\n"
+        );
+        // Second 101 only when the daemon stops
+        assert!(runs.complete(false).is_empty());
+        assert_eq!(
+            String::from_utf8(runs.complete(true)).unwrap(),
+            "multigraph temp\ncpu.value 101:60\n"
+        );
+
+        // In the daemon, with a sub-second interval
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("subsecond"));
+        config.plugin_cache = statedir.path().join("subsecond.value");
+        config.acquire_interval = Duration::from_millis(250);
+        config.subsecond_aggregation = Aggregation::Max;
+        let start = Instant::now();
+        let mut pending = PendingData::new(start);
+        for (epoch, value) in [(5, 1), (5, 3), (5, 2), (5, 1), (6, 4)] {
+            let data = format!("load.value {epoch}:{value}\n");
+            pending.push(&config, None, data.as_bytes(), start).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&config.plugin_cache).unwrap(),
+            "load.value 5:3\n"
+        );
+        pending.finish(&config, None, start).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&config.plugin_cache).unwrap(),
+            "load.value 5:3\nload.value 6:4\n"
+        );
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("flush"));
+        config.plugin_cache = statedir.path().join("flush.value");
+        config.flush_interval = Duration::from_secs(3);
+
+        let start = Instant::now();
+        let mut pending = PendingData::new(start);
+        for second in 0..3 {
+            let line = format!("load.value {second}:1\n");
+            let now = start + Duration::from_secs(second);
+            pending.push(&config, None, line.as_bytes(), now).unwrap();
+            assert!(!config.plugin_cache.exists());
+        }
+        pending
+            .push(
+                &config,
+                None,
+                b"load.value 3:1\n",
+                start + Duration::from_secs(3),
+            )
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&config.plugin_cache).unwrap(),
+            "load.value 0:1\nload.value 1:1\nload.value 2:1\nload.value 3:1\n"
+        );
+
+        // What is left gets stored at the end
+        pending
+            .push(
+                &config,
+                None,
+                b"load.value 4:1\n",
+                start + Duration::from_secs(4),
+            )
+            .unwrap();
+        pending
+            .store(&config, None, start + Duration::from_secs(4))
+            .unwrap();
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        assert!(cache.ends_with("load.value 3:1\nload.value 4:1\n"));
+
+        // By default every run is stored right away
+        config.flush_interval = Duration::ZERO;
+        pending
+            .push(&config, None, b"load.value 5:1\n", start)
+            .unwrap();
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        assert!(cache.ends_with("load.value 5:1\n"));
+    }
+
+    #[test]
+    fn test_write_markers() {
+        let mut config = Config::new(String::from("markers"));
+        let mut handle = BufWriter::new(Vec::new());
+        write_markers(&mut handle, &config, 1, 100, Some(90)).unwrap();
+        assert!(handle.get_ref().is_empty() && handle.buffer().is_empty());
+
+        config.debug_sequence = true;
+        config.clock_jump_marker = true;
+        write_markers(&mut handle, &config, 2, 101, None).unwrap();
+        write_markers(&mut handle, &config, 3, 110, Some(101)).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "# sequence 2 epoch 101\n# sequence 3 epoch 110\n# clock jump from 101 to 110\n"
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new(String::from("replayer"));
+        config.plugin_statedir = dir.path().to_path_buf();
+        config.plugin_cache = dir.path().join("munin.replayer.value");
+        config.last_fetch_file = dir.path().join("replayer.lastfetch");
+
+        let recorded = dir.path().join("recorded.value");
+        let data = "load.value 1650000000:1\nload.value 1650000001:2\n";
+        std::fs::write(&recorded, data).unwrap();
+
+        let mut test = TestPlugin;
+        let mut handle = BufWriter::new(Vec::new());
+        test.replay(&mut handle, &config, &recorded).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, data);
+
+        // The recorded file stays, nothing else appeared
+        assert_eq!(std::fs::read_to_string(&recorded).unwrap(), data);
+        assert!(!config.plugin_cache.exists());
+        assert!(!config.last_fetch_file.exists());
+    }
+
+    #[test]
+    fn test_check_health() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("healthy"));
+        config.pidfile = dir.path().join("healthy.pid");
+        config.plugin_cache = dir.path().join("healthy.value");
+        config.last_fetch_file = dir.path().join("healthy.lastfetch");
+
+        // No pidfile, not running
+        assert!(check_health(&config, 1000).is_err());
+
+        // Pidfile exists, but nobody holds the lock
+        std::fs::write(&config.pidfile, "1\n").unwrap();
+        assert!(check_health(&config, 1000).is_err());
+
+        // Pretend to be the daemon
+        let pidfile = std::fs::File::open(&config.pidfile).unwrap();
+        pidfile.lock_exclusive().unwrap();
+        // But no data yet
+        assert!(check_health(&config, 1000).is_err());
+
+        std::fs::write(
+            &config.plugin_cache,
+            "# comment\nload.value 995:1\nload.value 998:2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            check_health(&config, 1000),
+            Ok(String::from(
+                "acquire daemon running, newest data is 2s old"
+            ))
+        );
+        assert_eq!(
+            check_health(&config, 1020),
+            Err(String::from("newest data is 22s old"))
+        );
+
+        // A fetch took the data away, it was fresh then
+        std::fs::remove_file(&config.plugin_cache).unwrap();
+        config.record_fetch(1018).unwrap();
+        assert_eq!(
+            check_health(&config, 1020),
+            Ok(String::from(
+                "acquire daemon running, newest data is 2s old"
+            ))
+        );
+
+        // Data in another cache counts as well
+        let extra = dir.path().join("healthy-free.value");
+        config
+            .extra_caches
+            .insert(String::from("healthy_free"), extra.clone());
+        std::fs::write(&extra, "free.value 1019:3\n").unwrap();
+        assert!(check_health(&config, 1020)
+            .unwrap()
+            .ends_with("newest data is 1s old"));
+
+        // The daemon started a new cache, nothing but comments in it yet
+        std::fs::remove_file(&extra).unwrap();
+        std::fs::write(&config.plugin_cache, "# sequence 1 epoch 1\n").unwrap();
+        let now = config.clock.now_epoch();
+        assert!(check_health(&config, now).is_ok());
+        assert!(check_health(&config, now + 20).is_err());
+    }
+
+    #[test]
+    fn test_daemonizer() {
+        let mut config = Config::new_daemon(String::from("chown"));
+        assert!(config.chown_pidfile);
+        assert!(DaemonSettings::new(&config).chown_pidfile);
+        config.chown_pidfile = false;
+        assert_eq!(
+            DaemonSettings::new(&config),
+            DaemonSettings {
+                pidfile: &config.pidfile,
+                chown_pidfile: false,
+                working_directory: &config.working_directory,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ensure_pidfile() {
+        let mut pidfile = NamedTempFile::new().unwrap();
+        write!(pidfile, "1").unwrap();
+        assert!(ensure_pidfile(pidfile.path(), 4223).unwrap());
+        assert_eq!(std::fs::read_to_string(pidfile.path()).unwrap(), "4223\n");
+        // Already right, nothing to do
+        assert!(!ensure_pidfile(pidfile.path(), 4223).unwrap());
+
+        // The lock held on the file survives the rewrite
+        let locked = std::fs::File::open(pidfile.path()).unwrap();
+        locked.lock_exclusive().unwrap();
+        assert!(ensure_pidfile(pidfile.path(), 815).unwrap());
+        let other = std::fs::File::open(pidfile.path()).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+    }
+
+    #[test]
+    fn test_daemon_loop_shutdown() {
+        let statedir = tempfile::tempdir().unwrap();
+        let config = daemon_config("shutdown", statedir.path());
+        run_daemon_loop(TestPlugin, &config, || {});
+
+        assert!(!config.pidfile.exists());
+        // The last run got written completely
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        assert!(cache.ends_with("\n"));
+        assert!(cache.contains("This is a value for shutdown"));
+    }
+
+    #[test]
+    fn test_reset_cache_on_start() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("reset", statedir.path());
+        config.reset_cache_on_start = true;
+        config
+            .extra_caches
+            .insert(String::from("disk"), statedir.path().join("disk.value"));
+        std::fs::write(&config.pidfile, "42\n").unwrap();
+        std::fs::write(&config.plugin_cache, "load.value 1000000000:1\n").unwrap();
+        std::fs::write(&config.extra_caches["disk"], "read.value 1000000000:1\n").unwrap();
+
+        let shutdown = Arc::new(AtomicBool::new(true));
+        daemon_loop(&mut TestPlugin, &config, shutdown).unwrap();
+        assert_eq!(std::fs::read(&config.plugin_cache).unwrap(), b"");
+        assert_eq!(std::fs::read(&config.extra_caches["disk"]).unwrap(), b"");
+
+        // Without the flag, it stays
+        std::fs::write(&config.pidfile, "42\n").unwrap();
+        std::fs::write(&config.plugin_cache, "load.value 1000000000:1\n").unwrap();
+        config.reset_cache_on_start = false;
+        let shutdown = Arc::new(AtomicBool::new(true));
+        daemon_loop(&mut TestPlugin, &config, shutdown).unwrap();
+        assert_eq!(
+            std::fs::read(&config.plugin_cache).unwrap(),
+            b"load.value 1000000000:1\n"
+        );
+    }
+
+    #[test]
+    fn test_acquire_took() {
+        // Takes its time, and notes how long
+        struct SlowPlugin(Vec<Duration>);
+        impl MuninPlugin for SlowPlugin {
+            fn config<W: Write>(&self, _handle: &mut BufWriter<W>) -> Result<()> {
+                Ok(())
+            }
+            fn acquire<W: Write>(
+                &mut self,
+                handle: &mut BufWriter<W>,
+                _config: &Config,
+                epoch: u64,
+            ) -> Result<()> {
+                thread::sleep(Duration::from_millis(60));
+                writeln!(handle, "slow.value {epoch}:1")?;
+                Ok(())
+            }
+            fn acquire_took(&mut self, took: Duration, config: &Config, _epoch: u64) {
+                assert!(over_budget(took, config));
+                self.0.push(took);
+            }
+        }
+
+        let mut config = Config::new_daemon(String::from("slow"));
+        config.acquire_interval = Duration::from_millis(50);
+        let mut plugin = SlowPlugin(Vec::new());
+        let data = collect_run(&mut plugin, &config, 1, 1650000000, None).unwrap();
+        assert_eq!(data.unwrap(), b"slow.value 1650000000:1\n");
+        assert_eq!(plugin.0.len(), 1);
+        assert!(plugin.0[0] >= Duration::from_millis(60));
+
+        // Within the budget
+        assert!(!over_budget(Duration::from_millis(10), &config));
+
+        // The default warns, if over budget
+        let warnings = testing::warnings_of(|| {
+            TestPlugin.acquire_took(Duration::from_millis(10), &config, 1650000000);
+        });
+        assert!(warnings.is_empty());
+        let warnings = testing::warnings_of(|| {
+            TestPlugin.acquire_took(Duration::from_secs(1), &config, 1650000000);
+        });
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].starts_with("acquire for epoch 1650000000 took 1s, longer than"),
+            "{}",
+            warnings[0]
+        );
+        // Also when the daemon loop calls it, with no budget at all
+        config.acquire_interval = Duration::ZERO;
+        let warnings = testing::warnings_of(|| {
+            let mut handle = BufWriter::new(Vec::new());
+            run_acquire(&mut TestPlugin, &mut handle, &config, 1650000001).unwrap();
+        });
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("acquire for epoch 1650000001 took"));
+    }
+
+    #[test]
+    fn test_on_shutdown() {
+        // Notes it got shut down
+        struct ClosingPlugin(Arc<AtomicBool>);
+        impl MuninPlugin for ClosingPlugin {
+            fn config<W: Write>(&self, _handle: &mut BufWriter<W>) -> Result<()> {
+                Ok(())
+            }
+            fn acquire<W: Write>(
+                &mut self,
+                handle: &mut BufWriter<W>,
+                _config: &Config,
+                epoch: u64,
+            ) -> Result<()> {
+                writeln!(handle, "conn.value {epoch}:1")?;
+                Ok(())
+            }
+            fn on_shutdown(&self, _config: &Config) -> Result<()> {
+                self.0.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        let statedir = tempfile::tempdir().unwrap();
+        let config = daemon_config("closing", statedir.path());
+        let closed = Arc::new(AtomicBool::new(false));
+        let plugin = ClosingPlugin(Arc::clone(&closed));
+        run_daemon_loop(plugin, &config, || {
+            assert!(!closed.load(Ordering::Relaxed));
+        });
+        assert!(closed.load(Ordering::Relaxed));
+        assert!(!config.pidfile.exists());
+
+        // The default does nothing
+        TestPlugin.on_shutdown(&config).unwrap();
+    }
+
+    #[cfg(feature = "async-daemon")]
+    #[tokio::test(start_paused = true)]
+    async fn test_daemon_async() {
+        // One line per run
+        struct LinePlugin;
+        impl MuninPlugin for LinePlugin {
+            fn config<W: Write>(&self, _handle: &mut BufWriter<W>) -> Result<()> {
+                Ok(())
+            }
+            fn acquire<W: Write>(
+                &mut self,
+                handle: &mut BufWriter<W>,
+                _config: &Config,
+                epoch: u64,
+            ) -> Result<()> {
+                writeln!(handle, "line.value {epoch}:1")?;
+                Ok(())
+            }
+        }
+
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("async"));
+        config.plugin_cache = statedir.path().join("async.value");
+        config.acquire_interval = Duration::from_secs(1);
+
+        // Runs right away and after one second, then gets cancelled
+        let mut plugin = LinePlugin;
+        let daemon = plugin.daemon_async(&config);
+        assert!(tokio::time::timeout(Duration::from_millis(1500), daemon)
+            .await
+            .is_err());
+
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        let epochs: Vec<u64> = cache
+            .lines()
+            .map(|line| line.parse::<value::StreamSample<String>>().unwrap().epoch)
+            .collect();
+        assert_eq!(epochs.len(), 2);
+        assert_eq!(epochs[1], epochs[0] + 1);
+    }
+
+    #[test]
+    fn test_daemon_loop_clock() {
+        struct FixedClock;
+        impl clock::Clock for FixedClock {
+            fn now_epoch(&self) -> u64 {
+                1650000000
+            }
+        }
+
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("clock", statedir.path());
+        config.clock = clock::SharedClock::new(FixedClock);
+        run_daemon_loop(TestPlugin, &config, || {});
+
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        assert!(cache
+            .starts_with("This is a value for clock\nAnd one more value with epoch 1650000000\n"));
+    }
+
+    #[test]
+    fn test_align_to_second() {
+        // Starts three quarters into a second, then runs along
+        struct LateClock(Instant);
+        impl clock::Clock for LateClock {
+            fn now_epoch(&self) -> u64 {
+                self.now().as_secs()
+            }
+            fn now(&self) -> Duration {
+                Duration::from_millis(1650000000750) + self.0.elapsed()
+            }
+        }
+
+        assert_eq!(
+            until_next_second(Duration::from_millis(1650000000750)),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            until_next_second(Duration::from_secs(1650000000)),
+            Duration::ZERO
+        );
+
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("aligned", statedir.path());
+        config.align_to_second = true;
+        config.clock = clock::SharedClock::new(LateClock(Instant::now()));
+        run_daemon_loop(TestPlugin, &config, || {});
+
+        // Not still in the second the loop got started in
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        assert!(
+            cache.starts_with(
+                "This is a value for aligned\nAnd one more value with epoch 1650000001\n"
+            ),
+            "{cache}"
+        );
+    }
+
+    #[test]
+    fn test_store_fetch_race() {
+        let statedir = tempfile::tempdir().unwrap();
+        let config = daemon_config("race", statedir.path());
+
+        let runs = 20000u64;
+        let writeconfig = config.clone();
+        let writer = thread::spawn(move || {
+            for epoch in 0..runs {
+                let line = format!("load.value {epoch}:1\n");
+                store_data(&writeconfig, None, line.as_bytes()).unwrap();
+            }
+        });
+
+        let mut fetched = Vec::new();
+        let fetch = |fetched: &mut Vec<u8>| {
+            let mut handle = BufWriter::new(Vec::new());
+            // No cachefile (yet) is fine here
+            if TestPlugin.fetch(&mut handle, &config).is_ok() {
+                fetched.extend(handle.into_inner().unwrap());
+            }
+        };
+        while !writer.is_finished() {
+            fetch(&mut fetched);
+        }
+        writer.join().unwrap();
+        fetch(&mut fetched);
+
+        // Every data point exactly once, in order
+        let fetched = String::from_utf8(fetched).unwrap();
+        let epochs: Vec<u64> = fetched
+            .lines()
+            .map(|line| line.parse::<value::StreamSample<String>>().unwrap().epoch)
+            .collect();
+        assert_eq!(epochs, (0..runs).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_trim_cache() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("trim"));
+        config.plugin_cache = statedir.path().join("trim.value");
+        config.max_cache_bytes = Some(100);
+
+        // Nothing there yet
+        assert!(!trim_cache(&config.plugin_cache, 100, false).unwrap());
+
+        // Each line is 24 bytes
+        for epoch in 1650000000..1650000010u64 {
+            let line = format!("load.value {epoch}:1\n");
+            store_data(&config, None, line.as_bytes()).unwrap();
+        }
+        let cache = std::fs::read_to_string(&config.plugin_cache).unwrap();
+        // Trimmed to the newest two lines whenever it was at 120
+        // bytes, before appending the next one
+        assert_eq!(cache.len(), 96);
+        let lines: Vec<&str> = cache.lines().collect();
+        assert_eq!(lines.first(), Some(&"load.value 1650000006:1"));
+        assert_eq!(lines.last(), Some(&"load.value 1650000009:1"));
+
+        // A limit smaller than a line drops everything but what comes next
+        assert!(trim_cache(&config.plugin_cache, 10, false).unwrap());
+        assert_eq!(std::fs::read_to_string(&config.plugin_cache).unwrap(), "");
+    }
+
+    #[test]
+    fn test_spawn_acquire() {
+        let mut config = Config::new_daemon(String::from("spawn"));
+        config.acquire_command = Some(PathBuf::from("/nonexistent/spawn"));
+        let err = spawn_acquire(&config, "spawn").unwrap_err().to_string();
+        assert!(err.starts_with("Could not spawn /nonexistent/spawn acquire: "));
+    }
+
+    #[test]
+    fn test_initial_spawn_delay() {
+        let mut config = Config::new_daemon(String::from("delay"));
+        config.acquire_command = Some(PathBuf::from("true"));
+        config.initial_spawn_delay = Duration::from_millis(300);
+        let start = Instant::now();
+        spawn_acquire(&config, "delay").unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        config.initial_spawn_delay = Duration::ZERO;
+        let start = Instant::now();
+        spawn_acquire(&config, "delay").unwrap();
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_check_epochs() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = Config::new_daemon(String::from("epochs"));
+        config.plugin_cache = statedir.path().join("epochs.value");
+        let data = b"load.value 1650000000:1\nload.value 42\n";
+        assert_eq!(check_epochs(&config, data).len(), 1);
+        // Still gets stored
+        store_data(&config, None, data).unwrap();
+        assert_eq!(std::fs::read(&config.plugin_cache).unwrap(), data);
+
+        // Standard plugins do not use an epoch
+        config.daemonize = false;
+        assert!(check_epochs(&config, data).is_empty());
+    }
+
+    #[test]
+    fn test_fetch_error() {
+        let mut config = Config::new_daemon(String::from("missing"));
+        config.plugin_cache = PathBuf::from("/nonexistent/munin.missing.value");
+        let mut handle = BufWriter::new(Vec::new());
+        let err = TestPlugin.fetch(&mut handle, &config).unwrap_err();
+        assert!(matches!(err, PluginError::Io(_)), "{err:?}");
+    }
+
+    #[test]
+    fn test_extra_caches() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("extra", statedir.path());
+        for name in ["disk_sda", "disk_sdb"] {
+            config.extra_caches.insert(
+                name.to_string(),
+                statedir.path().join(format!("{name}.value")),
             );
+        }
 
-            // And have acquire write to it
-            test.acquire(&mut handle, &config, 42).unwrap();
+        for epoch in [1650000000, 1650000001] {
+            let data = format!(
+                "multigraph disk_sda\nread.value {epoch}:1\nmultigraph disk_sdb\nread.value {epoch}:2\nmultigraph disk_sdc\nread.value {epoch}:3\n"
+            );
+            store_data(&config, None, data.as_bytes()).unwrap();
         }
+        assert_eq!(
+            std::fs::read_to_string(statedir.path().join("disk_sda.value")).unwrap(),
+            "read.value 1650000000:1\nread.value 1650000001:1\n"
+        );
 
-        // And we want to access the tempfile and read from it
-        (_, config.plugin_cache) = fetchpath.keep().unwrap();
-        let checktext = Vec::new();
-        let mut handle = BufWriter::new(checktext);
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        let fetched = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        let expected = [
+            // Not an extra cache, stays in the main one
+            "multigraph disk_sdc",
+            "read.value 1650000000:3",
+            "multigraph disk_sdc",
+            "read.value 1650000001:3",
+            "multigraph disk_sda",
+            "read.value 1650000000:1",
+            "read.value 1650000001:1",
+            "multigraph disk_sdb",
+            "read.value 1650000000:2",
+            "read.value 1650000001:2",
+        ];
+        assert_eq!(fetched.lines().collect::<Vec<_>>(), expected);
+        // All drained
+        assert!(!config.plugin_cache.exists());
+        for path in config.extra_caches.values() {
+            assert!(!path.exists());
+        }
 
-        test.fetch(&mut handle, &config).unwrap();
-        handle.flush().unwrap();
-        let (recovered_writer, _buffered_data) = handle.into_parts();
-        let output = String::from_utf8(recovered_writer).unwrap();
+        // Nothing for the extra caches is fine
+        store_data(&config, None, b"").unwrap();
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        assert!(handle.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_keep_fetched_cache() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("keep", statedir.path());
+        config.keep_fetched_cache = true;
 
+        store_data(&config, None, b"load.value 1650000000:1\n").unwrap();
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        assert_eq!(handle.into_inner().unwrap(), b"load.value 1650000000:1\n");
+        assert!(!config.plugin_cache.exists());
         assert_eq!(
-            output,
-            String::from("This is a value for testplugin\nAnd one more value with epoch 42\n")
+            std::fs::read_to_string(config.plugin_cache.with_extension("last")).unwrap(),
+            "load.value 1650000000:1\n"
+        );
+    }
+
+    #[test]
+    fn test_move_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("move.value");
+        let to = dir.path().join("move.fetch");
+        std::fs::write(&from, "load.value 1650000000:1\n").unwrap();
+
+        // Pretend the two are on different filesystems
+        move_cache(&from, &to, |_, _| {
+            Err(io::Error::from(io::ErrorKind::CrossesDevices))
+        })
+        .unwrap();
+        assert!(!from.exists());
+        let mut handle = BufWriter::new(Vec::new());
+        io::copy(&mut std::fs::File::open(&to).unwrap(), &mut handle).unwrap();
+        assert_eq!(handle.into_inner().unwrap(), b"load.value 1650000000:1\n");
+
+        // Other errors are not hidden
+        std::fs::write(&from, "load.value 1650000001:1\n").unwrap();
+        let err = move_cache(&from, &to, |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(from.exists());
+    }
+
+    #[test]
+    fn test_copy_flushing() {
+        // Remembers the most bytes it got between two flushes
+        #[derive(Debug, Default)]
+        struct FlushWatcher {
+            data: Vec<u8>,
+            unflushed: usize,
+            most: usize,
+        }
+        impl Write for FlushWatcher {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.data.extend_from_slice(buf);
+                self.unflushed += buf.len();
+                self.most = self.most.max(self.unflushed);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.unflushed = 0;
+                Ok(())
+            }
+        }
+
+        let cache: Vec<u8> = (0..200000u64)
+            .flat_map(|epoch| format!("load.value {}:{epoch}\n", 1650000000 + epoch).into_bytes())
+            .collect();
+        let mut handle = BufWriter::with_capacity(8192, FlushWatcher::default());
+        let copied = copy_flushing(&mut cache.as_slice(), &mut handle, 8192).unwrap();
+        assert_eq!(copied, cache.len() as u64);
+        let watcher = handle.into_inner().unwrap();
+        assert!(watcher.data == cache);
+        assert!(watcher.most <= 8192, "{} bytes unflushed", watcher.most);
+
+        // Through fetch as well
+        let statedir = tempfile::tempdir().unwrap();
+        let config = daemon_config("large", statedir.path());
+        std::fs::write(&config.plugin_cache, &cache).unwrap();
+        let mut handle = BufWriter::with_capacity(config.fetch_size, FlushWatcher::default());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        let watcher = handle.into_inner().unwrap();
+        assert!(watcher.data == cache);
+        assert!(watcher.most <= config.fetch_size);
+    }
+
+    #[test]
+    fn test_fetch_tempdir() {
+        let statedir = tempfile::tempdir().unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("elsewhere", statedir.path());
+        config.fetch_tempdir = Some(tempdir.path().to_path_buf());
+        config.keep_fetched_cache = true;
+        std::fs::write(&config.plugin_cache, "load.value 1650000000:1\n").unwrap();
+
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        assert_eq!(handle.into_inner().unwrap(), b"load.value 1650000000:1\n");
+        assert!(!config.plugin_cache.exists());
+        assert_eq!(
+            std::fs::read(config.plugin_cache.with_extension("last")).unwrap(),
+            b"load.value 1650000000:1\n"
+        );
+        // The tempfile is gone again
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 0);
+
+        // Only the directory it goes to changes
+        let fetchpath = take_cache(
+            &config.plugin_cache.with_extension("last"),
+            Some(tempdir.path()),
+        )
+        .unwrap();
+        assert!(fetchpath.path().starts_with(tempdir.path()));
+    }
+
+    #[test]
+    fn test_compress_cache() {
+        let statedir = tempfile::tempdir().unwrap();
+        let mut config = daemon_config("compress", statedir.path());
+        config.compress_cache = true;
+
+        let lines: Vec<String> = (1650000000..1650000010u64)
+            .map(|epoch| format!("load.value {epoch}:1\n"))
+            .collect();
+        for line in &lines {
+            store_data(&config, None, line.as_bytes()).unwrap();
+        }
+        // On disk it is gzip
+        let cache = std::fs::read(&config.plugin_cache).unwrap();
+        assert_eq!(&cache[..2], &[0x1f, 0x8b]);
+
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        let fetched = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(fetched, lines.concat());
+        assert!(!config.plugin_cache.exists());
+
+        // Trimming keeps it readable
+        for line in &lines {
+            store_data(&config, None, line.as_bytes()).unwrap();
+        }
+        let size = std::fs::metadata(&config.plugin_cache).unwrap().len();
+        assert!(trim_cache(&config.plugin_cache, size - 1, true).unwrap());
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.fetch(&mut handle, &config).unwrap();
+        let fetched = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert!(!fetched.is_empty());
+        assert!(lines.concat().ends_with(&fetched));
+    }
+
+    #[test]
+    fn test_target_rate() {
+        assert_eq!(target_rate(Duration::from_secs(1)).unwrap(), 1.0);
+        assert_eq!(target_rate(Duration::from_secs(10)).unwrap(), 0.1);
+        assert_eq!(target_rate(Duration::from_millis(500)).unwrap(), 2.0);
+        assert!(target_rate(Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_clock_jump() {
+        // Normal tick, or a slipped one
+        assert!(!clock_jump(100, 101, 5));
+        assert!(!clock_jump(100, 103, 5));
+        // Same second twice is no jump
+        assert!(!clock_jump(100, 100, 5));
+        // Forward jump
+        assert!(clock_jump(100, 106, 5));
+        // Backwards always is
+        assert!(clock_jump(100, 99, 5));
+    }
+
+    // A wildcard plugin, suggesting two instances
+    struct SuggestPlugin;
+    impl MuninPlugin for SuggestPlugin {
+        fn config<W: Write>(&self, _handle: &mut BufWriter<W>) -> Result<()> {
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            _handle: &mut BufWriter<W>,
+            _config: &Config,
+            _epoch: u64,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn suggest(&self) -> Vec<String> {
+            vec![String::from("eth0"), String::from("eth1")]
+        }
+    }
+
+    #[test]
+    fn test_suggest() {
+        let mut handle = BufWriter::new(Vec::new());
+        SuggestPlugin.suggest_output(&mut handle).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "eth0\neth1\n");
+
+        // Default is to suggest nothing
+        assert!(TestPlugin.suggest().is_empty());
+    }
+
+    #[test]
+    fn test_outside_munin() {
+        // No munin environment, but no terminal either: no note, just
+        // the fetch
+        assert!(!command::outside_munin(Vec::new(), false));
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin
+            .fetch(&mut handle, &Config::new(String::from("by-hand")))
+            .unwrap();
+        assert!(!handle.into_inner().unwrap().is_empty());
+        assert!(command::USAGE_NOTE.contains("config, configfetch, autoconf"));
+    }
+
+    #[test]
+    fn test_config_string() {
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.config(&mut handle).unwrap();
+        let written = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(TestPlugin.config_string().unwrap(), written);
+        assert_eq!(written, "This is a test plugin\nThere is no config\n");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(TestPlugin.capabilities(), ["dirtyconfig"]);
+        let mut handle = BufWriter::new(Vec::new());
+        TestPlugin.capabilities_output(&mut handle).unwrap();
+        assert_eq!(handle.into_inner().unwrap(), b"dirtyconfig\n");
+    }
+
+    #[test]
+    fn test_autoconf_answer() {
+        let suggestions = SuggestPlugin.suggest();
+        let yes = AutoconfResult::yes();
+        assert_eq!(autoconf_answer(&yes, &suggestions), "yes (eth0 eth1)");
+        assert_eq!(autoconf_answer(&yes, &[]), "yes");
+        assert_eq!(autoconf_answer(&false.into(), &suggestions), "no");
+        let no = AutoconfResult::no("network interface eth0 not found");
+        assert_eq!(
+            autoconf_answer(&no, &suggestions),
+            "no (network interface eth0 not found)"
         );
+        // Defaults to check_autoconf
+        assert_eq!(TestPlugin.autoconf_result(), AutoconfResult::from(false));
     }
 
     #[test]
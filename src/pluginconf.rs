@@ -0,0 +1,185 @@
+//! Read munin plugin-conf.d style configuration files
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! munin-node reads its plugin configuration from files in
+//! `/etc/munin/plugin-conf.d/`, looking like
+//!
+//! ```text
+//! [if_*]
+//! user root
+//! env.exclude lo
+//!
+//! [load]
+//! env.warning 10
+//! ```
+//!
+//! and hands the `env.*` entries to the plugin as environment
+//! variables. To run a plugin with its real configuration outside of
+//! munin, the functions in here parse such a file and can apply the
+//! matching `env.*` entries to the process environment.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use anyhow::Result;
+use log::trace;
+use std::{collections::HashMap, env, fs, path::Path};
+
+/// Parse plugin-conf.d content and return all `env.*` entries that
+/// apply to the plugin named `plugin_name`, with the `env.` prefix
+/// stripped.
+///
+/// Sections apply if their name equals the plugin name or matches it
+/// as a glob, where `*` matches any sequence of characters (as in
+/// `[if_*]`). Sections are applied in the order they appear, so a
+/// later section overrides values set by an earlier one. Lines other
+/// than `env.*` (like `user` or `group`) are ignored, so are empty
+/// lines and comments starting with `#`.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::pluginconf::parse_plugin_conf;
+/// let conf = "[if_*]\nenv.exclude lo\n[load]\nenv.warning 10\n";
+/// let env = parse_plugin_conf(conf, "if_eth0");
+/// assert_eq!(env.get("exclude"), Some(&String::from("lo")));
+/// assert_eq!(env.get("warning"), None);
+/// ```
+pub fn parse_plugin_conf(content: &str, plugin_name: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    // Lines before the first section apply to nobody
+    let mut active = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            active = glob_match(section.trim(), plugin_name);
+            trace!("Section {section} applies to {plugin_name}: {active}");
+            continue;
+        }
+
+        if !active {
+            continue;
+        }
+
+        if let Some(entry) = line.strip_prefix("env.") {
+            // Name and value are separated by whitespace, the value
+            // is the rest of the line and may contain spaces itself.
+            let (name, value) = match entry.split_once(char::is_whitespace) {
+                Some((name, value)) => (name, value.trim()),
+                None => (entry, ""),
+            };
+            result.insert(name.to_string(), value.to_string());
+        }
+    }
+    result
+}
+
+/// Read the plugin-conf.d style file at `path` and return the `env.*`
+/// entries applying to `plugin_name`, see [parse_plugin_conf].
+pub fn read_plugin_conf(path: &Path, plugin_name: &str) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)?;
+    Ok(parse_plugin_conf(&content, plugin_name))
+}
+
+/// Read the plugin-conf.d style file at `path` and set all `env.*`
+/// entries applying to `plugin_name` in the process environment, the
+/// same way munin-node would before running the plugin.
+///
+/// Meant for testing a plugin outside of munin, call it early in
+/// `main()`, before any threads are started.
+pub fn apply_plugin_conf(path: &Path, plugin_name: &str) -> Result<()> {
+    for (name, value) in read_plugin_conf(path, plugin_name)? {
+        trace!("Setting environment {name}={value}");
+        env::set_var(name, value);
+    }
+    Ok(())
+}
+
+/// Match `name` against `pattern`, where `*` in pattern matches any
+/// (possibly empty) sequence of characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There always is a first element, even for an empty pattern
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    // No * at all, needs to be an exact match
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("load", "load"));
+        assert!(!glob_match("load", "loadavg"));
+        assert!(glob_match("if_*", "if_eth0"));
+        assert!(glob_match("if_*", "if_"));
+        assert!(!glob_match("if_*", "cpu"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*_err_*", "if_err_eth0"));
+        assert!(!glob_match("*_err_*", "if_eth0"));
+    }
+
+    #[test]
+    fn test_parse_plugin_conf() {
+        let conf = "# Some comment
+env.ignored before any section
+
+[*]
+env.global yes
+env.warning 5
+
+[if_*]
+user root
+env.exclude lo docker0
+
+[if_eth0]
+env.warning 10
+";
+        let env = parse_plugin_conf(conf, "if_eth0");
+        assert_eq!(env.len(), 3);
+        assert_eq!(env.get("global"), Some(&String::from("yes")));
+        assert_eq!(env.get("exclude"), Some(&String::from("lo docker0")));
+        // Later section wins
+        assert_eq!(env.get("warning"), Some(&String::from("10")));
+        assert_eq!(env.get("ignored"), None);
+        assert_eq!(env.get("user"), None);
+
+        let env = parse_plugin_conf(conf, "load");
+        assert_eq!(env.len(), 2);
+        assert_eq!(env.get("warning"), Some(&String::from("5")));
+    }
+
+    #[test]
+    fn test_apply_plugin_conf() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "[testplugin]\nenv.MUNIN_PLUGIN_PLUGINCONF_TEST works").unwrap();
+        apply_plugin_conf(file.path(), "testplugin").unwrap();
+        assert_eq!(
+            env::var("MUNIN_PLUGIN_PLUGINCONF_TEST"),
+            Ok(String::from("works"))
+        );
+    }
+}
@@ -0,0 +1,461 @@
+//! Helpers for the munin configuration of streaming plugins
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! Streaming plugins deliver data at a higher resolution than the
+//! default 5 minutes, and need to tell munin about it using
+//! `update_rate` and `graph_data_size`. The functions in here help to
+//! get those right. [Ticker] hands out the epochs for the data
+//! points, one per interval.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use crate::Config;
+use anyhow::{anyhow, Result};
+use std::{
+    fmt,
+    io::{BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+/// Munin time units and their length in seconds, largest first.
+const UNITS: &[(char, u64)] = &[
+    ('w', 604800),
+    ('d', 86400),
+    ('h', 3600),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Render a number of seconds in the largest munin time unit that
+/// fits without remainder, e.g. `86400` as `1d`.
+fn format_seconds(seconds: u64) -> String {
+    // 's' divides everything, so we always find a unit
+    let (unit, length) = UNITS
+        .iter()
+        .find(|(_, length)| seconds % *length == 0)
+        .unwrap_or(&('s', 1));
+    format!("{}{}", seconds / length, unit)
+}
+
+/// Render a `graph_data_size` directive that keeps `points` data
+/// points at a resolution of `interval`.
+///
+/// `interval` is expected to be the `update_rate` of the plugin, that
+/// is how often it delivers a value, and has to be whole seconds, as
+/// that is what munin works with. Both `points` and `interval` need
+/// to be larger than zero.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::streaming::graph_data_size_for_points;
+/// # use std::time::Duration;
+/// // One day worth of data at 1 second resolution
+/// let size = graph_data_size_for_points(86400, Duration::from_secs(1)).unwrap();
+/// assert_eq!(size, "graph_data_size custom 1d");
+/// ```
+pub fn graph_data_size_for_points(points: u64, interval: Duration) -> Result<String> {
+    if points == 0 {
+        return Err(anyhow!("Number of data points needs to be larger than 0"));
+    }
+    if interval.as_secs() == 0 || interval.subsec_nanos() != 0 {
+        return Err(anyhow!(
+            "Interval needs to be whole seconds and at least one, got {:?}",
+            interval
+        ));
+    }
+    let seconds = points
+        .checked_mul(interval.as_secs())
+        .ok_or_else(|| anyhow!("{} data points at {:?} is too long", points, interval))?;
+    Ok(format!(
+        "graph_data_size custom {}",
+        format_seconds(seconds)
+    ))
+}
+
+/// How much data munin keeps for a _streaming_ plugin, at which
+/// resolution: the value of `graph_data_size custom ...`.
+///
+/// Starts with the time kept at full resolution (the `update_rate`),
+/// every [DataSize::tier] adds a coarser resolution and how long that
+/// is kept. Durations are whole seconds, written in the largest munin
+/// time unit that fits. Converts into a String, for
+/// [Config::graph_data_size] or
+/// [Graph::data_size](crate::graph::Graph::data_size).
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::streaming::DataSize;
+/// # use std::time::Duration;
+/// // 1 per second for a day, 1 per minute for a week
+/// let size = DataSize::new(Duration::from_secs(86400))
+///     .tier(Duration::from_secs(60), Duration::from_secs(7 * 86400));
+/// assert_eq!(size.to_string(), "custom 1d, 1m for 1w");
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DataSize {
+    full: Duration,
+    tiers: Vec<(Duration, Duration)>,
+}
+
+impl DataSize {
+    /// Keep data at full resolution for `keep`
+    pub fn new(keep: Duration) -> Self {
+        Self {
+            full: keep,
+            tiers: Vec::new(),
+        }
+    }
+
+    /// Then keep one value per `resolution` for `keep`
+    pub fn tier(mut self, resolution: Duration, keep: Duration) -> Self {
+        self.tiers.push((resolution, keep));
+        self
+    }
+}
+
+impl fmt::Display for DataSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "custom {}", format_seconds(self.full.as_secs()))?;
+        for (resolution, keep) in &self.tiers {
+            write!(
+                f,
+                ", {} for {}",
+                format_seconds(resolution.as_secs()),
+                format_seconds(keep.as_secs())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<DataSize> for String {
+    fn from(size: DataSize) -> Self {
+        size.to_string()
+    }
+}
+
+/// Write the `update_rate` and `graph_data_size` directives set in
+/// [Config::update_rate] and [Config::graph_data_size], for use in
+/// [MuninPlugin::config](crate::MuninPlugin::config) of a _streaming_
+/// plugin. Directives not set in the config are left out.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{streaming::write_streaming_graph_config, Config};
+/// # use std::io::BufWriter;
+/// let mut config = Config::new_daemon(String::from("fast"));
+/// config.update_rate = Some(1);
+/// config.graph_data_size = Some(String::from("custom 1d"));
+///
+/// let mut handle = BufWriter::new(Vec::new());
+/// write_streaming_graph_config(&mut handle, &config).unwrap();
+/// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "update_rate 1\ngraph_data_size custom 1d\n");
+/// ```
+pub fn write_streaming_graph_config<W: Write>(
+    handle: &mut BufWriter<W>,
+    config: &Config,
+) -> Result<()> {
+    if let Some(rate) = config.update_rate {
+        writeln!(handle, "update_rate {rate}")?;
+    }
+    if let Some(size) = &config.graph_data_size {
+        writeln!(handle, "graph_data_size {size}")?;
+    }
+    Ok(())
+}
+
+/// Check if a daemonizing plugin has [Config::update_rate] and
+/// [Config::graph_data_size] set, as it should. Returns a message
+/// naming the missing settings, if any.
+pub(crate) fn missing_streaming_settings(config: &Config) -> Option<String> {
+    if !config.daemonize {
+        return None;
+    }
+    let mut missing = Vec::new();
+    if config.update_rate.is_none() {
+        missing.push("update_rate");
+    }
+    if config.graph_data_size.is_none() {
+        missing.push("graph_data_size");
+    }
+    if missing.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "Streaming plugin {} has no {} configured, munin will store its data at the default 5 minute resolution",
+        config.plugin_name,
+        missing.join(" and ")
+    ))
+}
+
+/// Find the `fieldname.value VALUE` lines in `data` written without
+/// an epoch, the _standard_ format, which munin ignores coming from a
+/// _streaming_ plugin. Returns a message for each of them.
+pub(crate) fn missing_epochs(data: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once(char::is_whitespace)?;
+            key.strip_suffix(".value")?;
+            if value.contains(':') {
+                None
+            } else {
+                Some(format!(
+                    "Streaming plugin wrote \"{}\" without an epoch, munin ignores it, use {} EPOCH:{}",
+                    line.trim(),
+                    key,
+                    value.trim()
+                ))
+            }
+        })
+        .collect()
+}
+
+/// What a run of [MuninPlugin::acquire_tick](crate::MuninPlugin::acquire_tick)
+/// did, telling the daemon what to store for it.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum AcquireResult {
+    /// Data got written to the handle, store it
+    #[default]
+    Wrote,
+    /// No data this time (say, the source is unavailable for a
+    /// moment), store nothing at all for this run
+    Skip,
+    /// The values are unknown, store `fieldname.value EPOCH:U` for
+    /// every field of the config instead of whatever got written
+    Unknown,
+}
+
+/// One tick of a [Ticker]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Tick {
+    /// Unix epoch in seconds this tick belongs to
+    pub epoch: u64,
+    /// Number of ticks that passed without being handed out since the
+    /// previous one, because the loop was too slow
+    pub skipped: u64,
+}
+
+/// Derive the epochs of a loop running once every `interval` from
+/// the time it started, instead of asking the clock on every run.
+///
+/// Asking the clock in every run makes the epochs drift with the
+/// loop: a run starting late repeats or skips a second. A Ticker
+/// instead counts the intervals passed since it was started, using
+/// the monotonic [Instant], so every tick gets its own epoch (for
+/// intervals of at least one second, shorter ones share a second and
+/// the daemon folds their values into one, see
+/// [Config::subsecond_aggregation]), always increasing. Should a run
+/// take longer than the interval, the ticks it missed are reported in
+/// [Tick::skipped], rather than written late with a wrong epoch.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::streaming::{Tick, Ticker};
+/// # use std::time::{Duration, Instant};
+/// let start = Instant::now();
+/// let mut ticker = Ticker::new(Duration::from_secs(1650000000), start, Duration::from_secs(1)).unwrap();
+/// assert_eq!(ticker.tick(start), Tick { epoch: 1650000000, skipped: 0 });
+/// // A slow run, next loop starts 2.5 seconds later
+/// let tick = ticker.tick(start + Duration::from_millis(2500));
+/// assert_eq!(tick, Tick { epoch: 1650000002, skipped: 1 });
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Ticker {
+    /// Wall clock time (since the unix epoch) at `start`
+    wall: Duration,
+    /// Monotonic time of the start
+    start: Instant,
+    /// Time between two ticks
+    interval: Duration,
+    /// Number of the last tick handed out
+    last: Option<u128>,
+}
+
+impl Ticker {
+    /// Start ticking every `interval`, `start` being the same moment
+    /// as `wall`, the time since the unix epoch. `interval` needs to
+    /// be larger than zero.
+    pub fn new(wall: Duration, start: Instant, interval: Duration) -> Result<Self> {
+        if interval.is_zero() {
+            return Err(anyhow!("Interval needs to be larger than zero"));
+        }
+        Ok(Self {
+            wall,
+            start,
+            interval,
+            last: None,
+        })
+    }
+
+    /// Hand out the tick for a run of the loop starting at `now`.
+    ///
+    /// Is never the tick handed out before, even if `now` still is
+    /// within its interval (a loop waking up a little early).
+    pub fn tick(&mut self, now: Instant) -> Tick {
+        let due = now.saturating_duration_since(self.start).as_nanos() / self.interval.as_nanos();
+        let (number, skipped) = match self.last {
+            Some(last) => {
+                let number = due.max(last + 1);
+                (number, number - last - 1)
+            }
+            None => (due, 0),
+        };
+        self.last = Some(number);
+        let offset = self.interval.as_nanos() * number;
+        let epoch = (self.wall.as_nanos() + offset) / 1_000_000_000;
+        Tick {
+            epoch: epoch as u64,
+            skipped: skipped as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_epochs() {
+        let data = b"# last-updated 1650000000\nmultigraph load\nload.value 1650000000:1\nload.value 42\nfree.value U\n";
+        assert_eq!(
+            missing_epochs(data),
+            [
+                "Streaming plugin wrote \"load.value 42\" without an epoch, munin ignores it, use load.value EPOCH:42",
+                "Streaming plugin wrote \"free.value U\" without an epoch, munin ignores it, use free.value EPOCH:U",
+            ]
+        );
+        assert!(missing_epochs(b"load.value 1650000000:1\n").is_empty());
+    }
+
+    #[test]
+    fn test_format_seconds() {
+        assert_eq!(format_seconds(1), "1s");
+        assert_eq!(format_seconds(90), "90s");
+        assert_eq!(format_seconds(300), "5m");
+        assert_eq!(format_seconds(7200), "2h");
+        assert_eq!(format_seconds(2 * 86400), "2d");
+        assert_eq!(format_seconds(2 * 604800), "2w");
+    }
+
+    #[test]
+    fn test_graph_data_size_for_points() {
+        assert_eq!(
+            graph_data_size_for_points(3600, Duration::from_secs(1)).unwrap(),
+            "graph_data_size custom 1h"
+        );
+        assert_eq!(
+            graph_data_size_for_points(2016, Duration::from_secs(300)).unwrap(),
+            "graph_data_size custom 1w"
+        );
+        assert!(graph_data_size_for_points(0, Duration::from_secs(1)).is_err());
+        assert!(graph_data_size_for_points(10, Duration::ZERO).is_err());
+        assert!(graph_data_size_for_points(10, Duration::from_millis(1500)).is_err());
+        assert!(graph_data_size_for_points(u64::MAX, Duration::from_secs(2)).is_err());
+    }
+
+    #[test]
+    fn test_data_size() {
+        let size = DataSize::new(Duration::from_secs(86400))
+            .tier(Duration::from_secs(60), Duration::from_secs(604800))
+            .tier(Duration::from_secs(300), Duration::from_secs(400 * 86400));
+        assert_eq!(size.to_string(), "custom 1d, 1m for 1w, 5m for 400d");
+
+        let mut config = Config::new_daemon(String::from("fast"));
+        config.graph_data_size = Some(DataSize::new(Duration::from_secs(7200)).into());
+        assert_eq!(config.graph_data_size.as_deref(), Some("custom 2h"));
+    }
+
+    #[test]
+    fn test_missing_streaming_settings() {
+        let mut config = Config::new_daemon(String::from("fast"));
+        let warning = missing_streaming_settings(&config).unwrap();
+        assert!(warning.contains("update_rate and graph_data_size"));
+
+        config.update_rate = Some(1);
+        let warning = missing_streaming_settings(&config).unwrap();
+        assert!(!warning.contains("update_rate"));
+
+        config.graph_data_size = Some(String::from("custom 1d"));
+        assert_eq!(missing_streaming_settings(&config), None);
+
+        // Standard plugins do not need it
+        assert_eq!(
+            missing_streaming_settings(&Config::new(String::from("slow"))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_write_streaming_graph_config() {
+        let mut config = Config::new_daemon(String::from("fast"));
+        let mut handle = BufWriter::new(Vec::new());
+        write_streaming_graph_config(&mut handle, &config).unwrap();
+        assert!(handle.buffer().is_empty());
+
+        config.update_rate = Some(10);
+        write_streaming_graph_config(&mut handle, &config).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "update_rate 10\n");
+    }
+
+    #[test]
+    fn test_ticker_slow_acquire() {
+        let start = Instant::now();
+        let mut ticker =
+            Ticker::new(Duration::from_secs(1000), start, Duration::from_secs(1)).unwrap();
+        // Every run takes 1.5 seconds
+        let ticks: Vec<Tick> = (0..6)
+            .map(|run| ticker.tick(start + Duration::from_millis(1500 * run)))
+            .collect();
+        let epochs: Vec<u64> = ticks.iter().map(|tick| tick.epoch).collect();
+        assert_eq!(epochs, [1000, 1001, 1003, 1004, 1006, 1007]);
+        // Every tick advances the epoch by one, missed ones got reported
+        for pair in ticks.windows(2) {
+            assert_eq!(pair[1].epoch - pair[0].epoch, 1 + pair[1].skipped);
+        }
+        let skipped: u64 = ticks.iter().map(|tick| tick.skipped).sum();
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn test_ticker() {
+        let start = Instant::now();
+        let wall = Duration::from_millis(1_000_700);
+        let mut ticker = Ticker::new(wall, start, Duration::from_millis(500)).unwrap();
+        assert_eq!(ticker.tick(start).epoch, 1000);
+        // Waking up early still gives the next tick
+        assert_eq!(
+            ticker.tick(start + Duration::from_millis(499)),
+            Tick {
+                epoch: 1001,
+                skipped: 0
+            }
+        );
+        assert!(Ticker::new(wall, start, Duration::ZERO).is_err());
+
+        // Two runs a second share their epoch, the daemon folds them
+        // into one value, so munin gets every epoch once
+        let mut ticker = Ticker::new(wall, start, Duration::from_millis(500)).unwrap();
+        let mut runs = crate::SubSecondRuns::new(crate::value::Aggregation::Last);
+        let mut stored = Vec::new();
+        for (run, millis) in [0, 499, 1000, 1500, 2000].into_iter().enumerate() {
+            let tick = ticker.tick(start + Duration::from_millis(millis));
+            runs.add(format!("load.value {}:{run}\n", tick.epoch).as_bytes());
+            stored.extend(runs.complete(false));
+        }
+        stored.extend(runs.complete(true));
+        let stored = String::from_utf8(stored).unwrap();
+        assert_eq!(
+            stored,
+            "load.value 1000:0\nload.value 1001:2\nload.value 1002:4\n"
+        );
+    }
+}
@@ -0,0 +1,642 @@
+//! Helpers for testing munin plugins
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! Munin silently drops a lot of things it does not like, which
+//! makes broken plugins hard to spot. The functions in here check
+//! plugin output against munins format rules, so plugin tests can
+//! catch those mistakes.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use crate::{Config, MuninPlugin};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::BufWriter,
+};
+
+/// Global (graph wide) directives munin knows about.
+const GRAPH_DIRECTIVES: &[&str] = &[
+    "graph",
+    "graph_args",
+    "graph_category",
+    "graph_data_size",
+    "graph_height",
+    "graph_info",
+    "graph_order",
+    "graph_period",
+    "graph_printf",
+    "graph_scale",
+    "graph_title",
+    "graph_total",
+    "graph_vlabel",
+    "graph_width",
+    "host_name",
+    "multigraph",
+    "update_rate",
+];
+
+/// Field attributes munin knows about.
+const FIELD_ATTRIBUTES: &[&str] = &[
+    "cdef",
+    "colour",
+    "critical",
+    "draw",
+    "extinfo",
+    "graph",
+    "graph_data_size",
+    "info",
+    "label",
+    "line",
+    "max",
+    "min",
+    "negative",
+    "stack",
+    "sum",
+    "type",
+    "unknown_limit",
+    "update_rate",
+    "value",
+    "warning",
+];
+
+/// One problem found by [lint_output].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct LintWarning {
+    /// Line number (starting at 1) the problem was found on.
+    pub line: usize,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Check if `name` is a valid munin field name, that is it matches
+/// `[A-Za-z_][A-Za-z0-9_]*`.
+fn valid_fieldname(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check if `value` is something munin accepts as a value: a number
+/// or U for unknown.
+fn valid_value(value: &str) -> bool {
+    value == "U" || value.parse::<f64>().is_ok()
+}
+
+/// Check plugin output against munins format rules and return a list
+/// of everything that looks wrong. An empty list means munin should
+/// be happy.
+///
+/// `output` is expected to contain the config output followed by the
+/// fetch output, as munin sees it with dirtyconfig. Checked are:
+/// - every field with a value has been declared in the config (has
+///   any attribute, like `label`, set), per multigraph section,
+/// - field names only use valid characters,
+/// - graph directives and field attributes are known to munin,
+/// - values are numbers or `U`, and either all or none of them use
+///   the streaming `EPOCH:VALUE` format.
+///
+/// Empty lines and comments starting with `#` are ignored.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::testing::lint_output;
+/// let output = "graph_title Load\nload.label load\nload.value 3\n";
+/// assert!(lint_output(output).is_empty());
+///
+/// let output = "graph_title Load\nload.label load\nlood.value 3\n";
+/// assert_eq!(lint_output(output).len(), 1);
+/// ```
+pub fn lint_output(output: &str) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    let mut warn = |line: usize, message: String| warnings.push(LintWarning { line, message });
+
+    // Declared fields per multigraph section, the unnamed one is ""
+    let mut declared: HashMap<String, HashSet<String>> = HashMap::new();
+    // Fields with values, with line number and section
+    let mut values: Vec<(usize, String, String)> = Vec::new();
+    let mut section = String::new();
+    // Have we seen streaming format values? None until first value
+    let mut streaming: Option<bool> = None;
+
+    for (num, line) in output.lines().enumerate() {
+        let num = num + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once(char::is_whitespace) {
+            Some((key, value)) => (key, value.trim()),
+            None => (line, ""),
+        };
+
+        let Some((field, attribute)) = key.rsplit_once('.') else {
+            // Graph wide directive
+            if !GRAPH_DIRECTIVES.contains(&key) {
+                warn(num, format!("unknown directive {key}"));
+            }
+            if key == "multigraph" {
+                section = value.to_string();
+            }
+            continue;
+        };
+
+        if !valid_fieldname(field) {
+            warn(num, format!("invalid field name {field}"));
+        }
+        if !FIELD_ATTRIBUTES.contains(&attribute) {
+            warn(
+                num,
+                format!("unknown attribute {attribute} for field {field}"),
+            );
+        }
+
+        if attribute != "value" {
+            declared
+                .entry(section.clone())
+                .or_default()
+                .insert(field.to_string());
+            continue;
+        }
+        values.push((num, section.clone(), field.to_string()));
+
+        let (is_streaming, data) = match value.split_once(':') {
+            Some((epoch, data)) => {
+                if epoch.parse::<u64>().is_err() {
+                    warn(num, format!("invalid epoch {epoch} for field {field}"));
+                }
+                (true, data)
+            }
+            None => (false, value),
+        };
+        if !valid_value(data) {
+            warn(num, format!("invalid value {data} for field {field}"));
+        }
+        match streaming {
+            None => streaming = Some(is_streaming),
+            Some(s) if s != is_streaming => warn(
+                num,
+                format!("field {field} mixes standard and EPOCH:VALUE format"),
+            ),
+            Some(_) => {}
+        }
+    }
+
+    for (num, section, field) in values {
+        if !declared.get(&section).is_some_and(|d| d.contains(&field)) {
+            warn(num, format!("value for undeclared field {field}"));
+        }
+    }
+    warnings
+}
+
+/// Collect the field names `output` mentions with an attribute
+/// matching `wanted`, per multigraph section.
+pub(crate) fn fields_by_section(
+    output: &str,
+    wanted: impl Fn(&str) -> bool,
+) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut section = String::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if key == "multigraph" {
+            section = value.trim().to_string();
+            continue;
+        }
+        if let Some((field, attribute)) = key.rsplit_once('.') {
+            let entry = (section.clone(), field.to_string());
+            if wanted(attribute) && !fields.contains(&entry) {
+                fields.push(entry);
+            }
+        }
+    }
+    fields
+}
+
+/// Compare the fields declared in `config` output with those that
+/// got a value in `data` (the fetch output) and describe every
+/// mismatch: values for fields the config does not know, and
+/// declared fields without a value. An empty list means both agree.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::testing::field_mismatches;
+/// let config = "graph_title Load\nload.label load\n";
+/// assert!(field_mismatches(config, "load.value 3\n").is_empty());
+/// assert_eq!(field_mismatches(config, "lood.value 3\n").len(), 2);
+/// ```
+pub fn field_mismatches(config: &str, data: &str) -> Vec<String> {
+    let declared = fields_by_section(config, |attribute| attribute != "value");
+    let valued = fields_by_section(data, |attribute| attribute == "value");
+    let describe = |(section, field): &(String, String), what: &str| {
+        if section.is_empty() {
+            format!("field {field} {what}")
+        } else {
+            format!("field {field} of multigraph {section} {what}")
+        }
+    };
+
+    let mut mismatches: Vec<String> = valued
+        .iter()
+        .filter(|field| !declared.contains(field))
+        .map(|field| describe(field, "has a value, but is not declared in config"))
+        .collect();
+    mismatches.extend(
+        declared
+            .iter()
+            .filter(|field| !valued.contains(field))
+            .map(|field| describe(field, "is declared in config, but got no value")),
+    );
+    mismatches
+}
+
+/// Run the `config` call of `plugin` with dirtyconfig forced on or
+/// off and return everything it output.
+///
+/// Uses a copy of `config` with [Config::dirtyconfig] set to
+/// `dirtyconfig`, so the result does not depend on the
+/// MUNIN_CAP_DIRTYCONFIG environment variable. With `dirtyconfig` true the output has to
+/// contain the config followed by the data, otherwise only the
+/// config.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{testing::dirtyconfig_output, Config, MuninPlugin};
+/// # use anyhow::Result;
+/// # use std::io::{BufWriter, Write};
+/// struct LoadPlugin;
+/// impl MuninPlugin for LoadPlugin {
+///     fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+///         writeln!(handle, "load.label load")?;
+///         Ok(())
+///     }
+///     fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, _config: &Config, _epoch: u64) -> Result<()> {
+///         writeln!(handle, "load.value 42")?;
+///         Ok(())
+///     }
+/// }
+/// let config = Config::new(String::from("load"));
+/// let output = dirtyconfig_output(&mut LoadPlugin, &config, true).unwrap();
+/// assert_eq!(output, "load.label load\nload.value 42\n");
+/// let output = dirtyconfig_output(&mut LoadPlugin, &config, false).unwrap();
+/// assert_eq!(output, "load.label load\n");
+/// ```
+pub fn dirtyconfig_output<P: MuninPlugin>(
+    plugin: &mut P,
+    config: &Config,
+    dirtyconfig: bool,
+) -> Result<String> {
+    let mut config = config.clone();
+    config.dirtyconfig = dirtyconfig;
+
+    let mut handle = BufWriter::new(Vec::new());
+    plugin.config_output(&mut handle, &config)?;
+    let output = handle.into_inner()?;
+    Ok(String::from_utf8(output)?)
+}
+
+/// Run the parts of a plugin and get their output as [String], no
+/// munin environment, processes or cachefiles of a daemon needed.
+///
+/// Does the dance with a [BufWriter] around a [Vec] plugin tests
+/// otherwise need for every call.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{testing::TestHarness, Config, MuninPlugin};
+/// # use anyhow::Result;
+/// # use std::io::{BufWriter, Write};
+/// struct LoadPlugin;
+/// impl MuninPlugin for LoadPlugin {
+///     fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+///         writeln!(handle, "load.label load")?;
+///         Ok(())
+///     }
+///     fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, _config: &Config, epoch: u64) -> Result<()> {
+///         writeln!(handle, "load.value {}:42", epoch)?;
+///         Ok(())
+///     }
+/// }
+/// let config = Config::new(String::from("load"));
+/// let harness = TestHarness;
+/// assert_eq!(harness.config_output(&LoadPlugin).unwrap(), "load.label load\n");
+/// assert_eq!(
+///     harness.acquire_output(&mut LoadPlugin, &config, 1650000000).unwrap(),
+///     "load.value 1650000000:42\n"
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct TestHarness;
+
+impl TestHarness {
+    /// Output of [MuninPlugin::config]
+    pub fn config_output<P: MuninPlugin>(&self, plugin: &P) -> Result<String> {
+        Self::collect(|handle| plugin.config(handle))
+    }
+
+    /// Output of [MuninPlugin::fetch] with `config`. For a `config`
+    /// with [Config::daemonize] set, this is what is in the cachefile.
+    pub fn fetch_output<P: MuninPlugin>(&self, plugin: &mut P, config: &Config) -> Result<String> {
+        Self::collect(|handle| Ok(plugin.fetch(handle, config)?))
+    }
+
+    /// Output of [MuninPlugin::acquire] with `config` and `epoch`
+    pub fn acquire_output<P: MuninPlugin>(
+        &self,
+        plugin: &mut P,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<String> {
+        Self::collect(|handle| plugin.acquire(handle, config, epoch))
+    }
+
+    /// Run `write` against a buffer and return what it wrote
+    fn collect<F>(write: F) -> Result<String>
+    where
+        F: FnOnce(&mut BufWriter<Vec<u8>>) -> Result<()>,
+    {
+        let mut handle = BufWriter::new(Vec::new());
+        write(&mut handle)?;
+        Ok(String::from_utf8(handle.into_inner()?)?)
+    }
+}
+
+/// Run `plugin`s acquire and collect the values of `fields` it wrote.
+fn acquire_values<P: MuninPlugin>(
+    plugin: &mut P,
+    config: &Config,
+    epoch: u64,
+    fields: &[&str],
+) -> Result<HashMap<String, f64>> {
+    let mut handle = BufWriter::new(Vec::new());
+    plugin.acquire(&mut handle, config, epoch)?;
+    let output = String::from_utf8(handle.into_inner()?)?;
+
+    let mut values = HashMap::new();
+    for line in output.lines() {
+        let Some((key, value)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        let Some(field) = key.strip_suffix(".value") else {
+            continue;
+        };
+        if !fields.contains(&field) {
+            continue;
+        }
+        // Streaming plugins prefix the value with EPOCH:
+        let value = value.rsplit(':').next().unwrap_or(value);
+        let value: f64 = value
+            .parse()
+            .map_err(|_| anyhow!("Field {field} has non-numeric value {value}"))?;
+        values.insert(field.to_string(), value);
+    }
+    Ok(values)
+}
+
+/// Check that the counter `fields` of `plugin` do not decrease.
+///
+/// Runs acquire twice, with `epoch` and `epoch + 1`, and returns an
+/// error naming every field of `fields` that is missing from the
+/// output of either run or has a smaller value in the second run.
+/// Values of type COUNTER should only ever grow (until they wrap),
+/// a decrease usually means a wrong field got used or a reset is not
+/// handled.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{testing::check_monotonic, Config, MuninPlugin};
+/// # use anyhow::Result;
+/// # use std::io::{BufWriter, Write};
+/// struct Packets {
+///     count: u64,
+/// }
+/// impl MuninPlugin for Packets {
+///     fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+///         Ok(())
+///     }
+///     fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, _config: &Config, epoch: u64) -> Result<()> {
+///         self.count += 10;
+///         writeln!(handle, "packets.value {}:{}", epoch, self.count)?;
+///         Ok(())
+///     }
+/// }
+/// let config = Config::new_daemon(String::from("packets"));
+/// check_monotonic(&mut Packets { count: 0 }, &config, &["packets"], 1650000000).unwrap();
+/// ```
+pub fn check_monotonic<P: MuninPlugin>(
+    plugin: &mut P,
+    config: &Config,
+    fields: &[&str],
+    epoch: u64,
+) -> Result<()> {
+    let first = acquire_values(plugin, config, epoch, fields)?;
+    let second = acquire_values(plugin, config, epoch + 1, fields)?;
+
+    let mut problems = Vec::new();
+    for field in fields {
+        match (first.get(*field), second.get(*field)) {
+            (Some(a), Some(b)) if b < a => {
+                problems.push(format!("{field} decreased from {a} to {b}"))
+            }
+            (Some(_), Some(_)) => {}
+            _ => problems.push(format!("{field} missing in output")),
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Counter check failed: {}", problems.join(", ")))
+    }
+}
+
+/// Collects what the current thread logs as warning, for the tests
+/// of this crate.
+#[cfg(test)]
+struct WarningCollector;
+
+#[cfg(test)]
+thread_local! {
+    static WARNINGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(test)]
+impl log::Log for WarningCollector {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.with(|warnings| warnings.borrow_mut().push(record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Run `f`, returning the warnings it logged (on this thread). Sets
+/// up [WarningCollector] as logger on first use, no test may install
+/// another one.
+#[cfg(test)]
+pub(crate) fn warnings_of<F: FnOnce()>(f: F) -> Vec<String> {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&WarningCollector).expect("Another logger got installed");
+        log::set_max_level(log::LevelFilter::Warn);
+    });
+    WARNINGS.with(|warnings| warnings.borrow_mut().clear());
+    f();
+    WARNINGS.with(|warnings| warnings.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // good grows, bad shrinks, both written in streaming format
+    struct CounterPlugin {
+        runs: u64,
+    }
+    impl MuninPlugin for CounterPlugin {
+        fn config<W: Write>(&self, _handle: &mut BufWriter<W>) -> Result<()> {
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            _config: &Config,
+            epoch: u64,
+        ) -> Result<()> {
+            self.runs += 1;
+            writeln!(handle, "good.value {}:{}", epoch, 100 + self.runs)?;
+            writeln!(handle, "bad.value {}:{}", epoch, 100 - self.runs)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_check_monotonic() {
+        let config = Config::new_daemon(String::from("counter"));
+        let mut plugin = CounterPlugin { runs: 0 };
+        assert!(check_monotonic(&mut plugin, &config, &["good"], 1000).is_ok());
+
+        let err = check_monotonic(&mut plugin, &config, &["good", "bad", "ugly"], 1000)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Counter check failed: bad decreased from 97 to 96, ugly missing in output"
+        );
+    }
+
+    #[test]
+    fn test_valid_fieldname() {
+        assert!(valid_fieldname("load"));
+        assert!(valid_fieldname("_eth0_rx"));
+        assert!(!valid_fieldname("eth0.1"));
+        assert!(!valid_fieldname("2ndnic"));
+        assert!(!valid_fieldname(""));
+    }
+
+    #[test]
+    fn test_lint_clean() {
+        let output = "graph_title Interface
+graph_args --base 1000
+# A comment
+eth0_rx.label received
+eth0_tx.label sent
+eth0_rx.value 1650000000:42
+eth0_tx.value 1650000000:U
+";
+        assert_eq!(lint_output(output), Vec::new());
+    }
+
+    #[test]
+    fn test_lint_problems() {
+        let output = "graph_title Broken
+graph_colour red
+load.label load
+load.waring 10
+eth0.1.label eth
+lood.value 42
+load.value 1650000000:23
+load.value abc
+";
+        let warnings = lint_output(output);
+        let lines: Vec<usize> = warnings.iter().map(|w| w.line).collect();
+        assert_eq!(lines, vec![2, 4, 5, 7, 8, 6]);
+        assert_eq!(warnings[0].message, "unknown directive graph_colour");
+        assert_eq!(
+            warnings[1].message,
+            "unknown attribute waring for field load"
+        );
+        assert_eq!(
+            warnings[5].to_string(),
+            "line 6: value for undeclared field lood"
+        );
+    }
+
+    #[test]
+    fn test_lint_multigraph() {
+        let output = "multigraph one
+a.label A
+multigraph two
+b.label B
+multigraph one
+a.value 1
+multigraph two
+a.value 2
+";
+        let warnings = lint_output(output);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].line, 8);
+    }
+
+    #[test]
+    fn test_field_mismatches() {
+        let config = "graph_title Load\nload.label load\nload.warning 10\n";
+        assert!(field_mismatches(config, "load.value 3\n").is_empty());
+        assert!(field_mismatches(config, "# comment\nload.value 1650000000:3\n").is_empty());
+        assert_eq!(
+            field_mismatches(config, "lood.value 3\n"),
+            vec![
+                "field lood has a value, but is not declared in config",
+                "field load is declared in config, but got no value",
+            ]
+        );
+
+        // Multigraph sections are kept apart
+        let config = "multigraph a\nx.label x\nmultigraph b\ny.label y\n";
+        let data = "multigraph a\ny.value 1\nmultigraph b\ny.value 1\n";
+        assert_eq!(
+            field_mismatches(config, data),
+            vec![
+                "field y of multigraph a has a value, but is not declared in config",
+                "field x of multigraph a is declared in config, but got no value",
+            ]
+        );
+    }
+}
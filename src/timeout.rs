@@ -0,0 +1,383 @@
+//! Give up on an acquire that hangs
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! A [TimeoutPlugin] wraps another plugin and runs its
+//! [MuninPlugin::acquire] on a thread of its own, waiting at most
+//! [Config::fetch_timeout] for it. A plugin reading from the network
+//! can then no longer hang munins fetch (and with it the whole node),
+//! the hung call gets left behind and munin records unknown values.
+//! The same goes for the daemon of a _streaming_ plugin with
+//! [Config::acquire_timeout], which keeps its cadence.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use crate::{unknown_values, AcquireResult, Config, MuninPlugin, PluginError};
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::{
+    io::{BufWriter, Write},
+    sync::{
+        mpsc::{self, RecvTimeoutError, TryRecvError},
+        Arc, Mutex, MutexGuard, PoisonError, TryLockError,
+    },
+    thread,
+    time::Duration,
+};
+
+/// What a run of acquire on the worker thread hands back: its
+/// result and what it wrote
+type Run = (Result<AcquireResult>, Vec<u8>);
+
+/// A plugin whose [MuninPlugin::acquire] can time out, see
+/// [Config::fetch_timeout] and [Config::acquire_timeout].
+///
+/// Every acquire runs on a thread of its own, writing into a buffer
+/// that gets copied to the handle once it is done. Should it take
+/// longer than the timeout, the thread is left running and the
+/// caller gets a [PluginError::Timeout], after `field.value U` got
+/// written for all fields of the config. The next acquire only starts
+/// once the one left behind finished, until then it times out right
+/// away. What the late one wrote gets dropped.
+///
+/// In the daemon a timeout is no error, the run gets
+/// [AcquireResult::Unknown] (so munin records unknown values for its
+/// epoch) and the loop goes on. Runs while the one left behind is
+/// still busy are [AcquireResult::Skip]ped.
+///
+/// [MuninPlugin::config] is answered from the last output seen while
+/// acquire is still busy. Without a timeout set, acquire runs as
+/// usual, on the calling thread.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{Config, MuninPlugin, PluginError, TimeoutPlugin};
+/// # use anyhow::Result;
+/// # use std::{io::{BufWriter, Write}, time::Duration};
+/// struct Remote;
+/// impl MuninPlugin for Remote {
+///     fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+///         writeln!(handle, "latency.label latency")?;
+///         Ok(())
+///     }
+///     fn acquire<W: Write>(&mut self, handle: &mut BufWriter<W>, _config: &Config, _epoch: u64) -> Result<()> {
+///         // The remote end does not answer
+///         std::thread::sleep(Duration::from_secs(5));
+///         writeln!(handle, "latency.value 42")?;
+///         Ok(())
+///     }
+/// }
+///
+/// let mut plugin = TimeoutPlugin::new(Remote);
+/// let mut config = Config::new(String::from("remote"));
+/// config.fetch_timeout = Some(Duration::from_millis(100));
+/// let mut handle = BufWriter::new(Vec::new());
+/// let err = plugin.fetch(&mut handle, &config).unwrap_err();
+/// assert!(matches!(err, PluginError::Timeout(_)));
+/// assert_eq!(handle.into_inner().unwrap(), b"latency.value U\n");
+/// ```
+pub struct TimeoutPlugin<P> {
+    plugin: Arc<Mutex<P>>,
+    /// Last output of the config of the plugin, for while it is busy
+    config: Mutex<String>,
+    capabilities: Vec<String>,
+    /// A run that timed out and did not finish yet
+    running: Option<mpsc::Receiver<Run>>,
+}
+
+impl<P: MuninPlugin + Send + 'static> TimeoutPlugin<P> {
+    /// Wrap `plugin`
+    pub fn new(plugin: P) -> Self {
+        let config = plugin.config_string().unwrap_or_default();
+        let capabilities = plugin
+            .capabilities()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        Self {
+            plugin: Arc::new(Mutex::new(plugin)),
+            config: Mutex::new(config),
+            capabilities,
+            running: None,
+        }
+    }
+
+    /// The plugin, waiting for a run of acquire to finish. One that
+    /// panicked does not keep it from being used.
+    fn lock(&self) -> MutexGuard<'_, P> {
+        self.plugin.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Is the run that timed out before still busy? Drops what it
+    /// wrote, should it be done by now.
+    fn busy(&mut self) -> bool {
+        if let Some(running) = &self.running {
+            match running.try_recv() {
+                Err(TryRecvError::Empty) => return true,
+                Ok(_) => warn!("Dropping the data of an acquire that timed out"),
+                Err(TryRecvError::Disconnected) => {}
+            }
+            self.running = None;
+        }
+        false
+    }
+
+    /// Run the acquire of the plugin on a thread of its own, waiting
+    /// at most `timeout` for it. With `tick`, it is
+    /// [MuninPlugin::acquire_tick]. None if it took longer, or the
+    /// run that timed out before is still busy.
+    fn run(&mut self, config: &Config, epoch: u64, timeout: Duration, tick: bool) -> Option<Run> {
+        if self.busy() {
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let plugin = Arc::clone(&self.plugin);
+        let config = config.clone();
+        thread::spawn(move || {
+            let mut plugin = plugin.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut handle = BufWriter::new(Vec::new());
+            let result = if tick {
+                plugin.acquire_tick(&mut handle, &config, epoch)
+            } else {
+                plugin
+                    .acquire(&mut handle, &config, epoch)
+                    .map(|()| AcquireResult::Wrote)
+            };
+            let run = match handle.into_inner() {
+                Ok(data) => (result, data),
+                Err(e) => (Err(e.into_error().into()), Vec::new()),
+            };
+            // Nobody listens any more if it took too long
+            let _ = sender.send(run);
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(run) => Some(run),
+            Err(RecvTimeoutError::Timeout) => {
+                self.running = Some(receiver);
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Some((Err(anyhow!("acquire panicked")), Vec::new()))
+            }
+        }
+    }
+}
+
+impl<P: MuninPlugin + Send + 'static> MuninPlugin for TimeoutPlugin<P> {
+    fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        let output = match self.plugin.try_lock() {
+            Ok(plugin) => plugin.config_string()?,
+            Err(TryLockError::Poisoned(e)) => e.into_inner().config_string()?,
+            // Busy in acquire
+            Err(TryLockError::WouldBlock) => {
+                let output = self.config.lock().unwrap_or_else(PoisonError::into_inner);
+                handle.write_all(output.as_bytes())?;
+                return Ok(());
+            }
+        };
+        handle.write_all(output.as_bytes())?;
+        *self.config.lock().unwrap_or_else(PoisonError::into_inner) = output;
+        Ok(())
+    }
+
+    /// Times out after [Config::fetch_timeout], or in the daemon (of
+    /// the `async-daemon` feature, the sync one uses
+    /// [MuninPlugin::acquire_tick]) after [Config::acquire_timeout].
+    /// There a timeout only writes unknown values, it is no error.
+    fn acquire<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<()> {
+        let timeout = match config.daemonize {
+            true => config.acquire_timeout,
+            false => config.fetch_timeout,
+        };
+        let Some(timeout) = timeout else {
+            return self.lock().acquire(handle, config, epoch);
+        };
+        match self.run(config, epoch, timeout, false) {
+            Some((result, data)) => {
+                handle.write_all(&data)?;
+                result.map(|_| ())
+            }
+            None => {
+                warn!("acquire for epoch {epoch} did not finish within {timeout:?}, giving up");
+                let epoch = (epoch > 0).then_some(epoch);
+                handle.write_all(unknown_values(&self.config_string()?, epoch).as_bytes())?;
+                match config.daemonize {
+                    true => Ok(()),
+                    false => Err(PluginError::Timeout(timeout).into()),
+                }
+            }
+        }
+    }
+
+    fn acquire_tick<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<AcquireResult> {
+        let Some(timeout) = config.acquire_timeout else {
+            return self.lock().acquire_tick(handle, config, epoch);
+        };
+        if self.busy() {
+            warn!("Skipping epoch {epoch}, acquire that timed out is still busy");
+            return Ok(AcquireResult::Skip);
+        }
+        match self.run(config, epoch, timeout, true) {
+            Some((result, data)) => {
+                handle.write_all(&data)?;
+                result
+            }
+            None => {
+                warn!("acquire for epoch {epoch} did not finish within {timeout:?}, giving up");
+                Ok(AcquireResult::Unknown)
+            }
+        }
+    }
+
+    fn ticks_skipped(&mut self, skipped: u64, epoch: u64) {
+        match self.plugin.try_lock() {
+            Ok(mut plugin) => plugin.ticks_skipped(skipped, epoch),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().ticks_skipped(skipped, epoch),
+            Err(TryLockError::WouldBlock) => {}
+        }
+    }
+
+    fn acquire_took(&mut self, took: Duration, config: &Config, epoch: u64) {
+        match self.plugin.try_lock() {
+            Ok(mut plugin) => plugin.acquire_took(took, config, epoch),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().acquire_took(took, config, epoch),
+            // Timed out, that got logged already
+            Err(TryLockError::WouldBlock) => {}
+        }
+    }
+
+    fn capabilities(&self) -> Vec<&str> {
+        self.capabilities.iter().map(String::as_str).collect()
+    }
+
+    fn check_autoconf(&self) -> bool {
+        self.lock().check_autoconf()
+    }
+
+    fn autoconf_result(&self) -> crate::AutoconfResult {
+        self.lock().autoconf_result()
+    }
+
+    fn suggest(&self) -> Vec<String> {
+        self.lock().suggest()
+    }
+
+    /// Shuts the plugin down, unless it is still busy in an acquire
+    /// that timed out
+    fn on_shutdown(&self, config: &Config) -> Result<()> {
+        match self.plugin.try_lock() {
+            Ok(plugin) => plugin.on_shutdown(config),
+            Err(TryLockError::Poisoned(e)) => e.into_inner().on_shutdown(config),
+            Err(TryLockError::WouldBlock) => {
+                warn!("Plugin still busy in acquire, not shutting it down");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Sleeps for as long as given in acquire, then writes one value
+    struct Sleepy(Duration);
+    impl MuninPlugin for Sleepy {
+        fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+            writeln!(handle, "multigraph sleepy")?;
+            writeln!(handle, "nap.label nap")?;
+            Ok(())
+        }
+        fn acquire<W: Write>(
+            &mut self,
+            handle: &mut BufWriter<W>,
+            _config: &Config,
+            _epoch: u64,
+        ) -> Result<()> {
+            thread::sleep(self.0);
+            writeln!(handle, "multigraph sleepy")?;
+            writeln!(handle, "nap.value 1")?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fetch_timeout() {
+        let mut config = Config::new(String::from("sleepy"));
+        config.fetch_timeout = Some(Duration::from_millis(100));
+
+        // Fast enough, the data goes through
+        let mut plugin = TimeoutPlugin::new(Sleepy(Duration::ZERO));
+        let mut handle = BufWriter::new(Vec::new());
+        plugin.fetch(&mut handle, &config).unwrap();
+        assert_eq!(
+            handle.into_inner().unwrap(),
+            b"multigraph sleepy\nnap.value 1\n"
+        );
+
+        // Too slow, fetch gives up in time
+        let mut plugin = TimeoutPlugin::new(Sleepy(Duration::from_secs(3)));
+        let started = Instant::now();
+        let mut handle = BufWriter::new(Vec::new());
+        let err = plugin.fetch(&mut handle, &config).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(
+            matches!(err, PluginError::Timeout(timeout) if timeout == Duration::from_millis(100)),
+            "{err}"
+        );
+        assert_eq!(
+            handle.into_inner().unwrap(),
+            b"multigraph sleepy\nnap.value U\n"
+        );
+        // Still busy, so the config comes from before
+        assert_eq!(
+            plugin.config_string().unwrap(),
+            "multigraph sleepy\nnap.label nap\n"
+        );
+        // And the next fetch fails right away
+        let started = Instant::now();
+        let mut handle = BufWriter::new(Vec::new());
+        assert!(plugin.fetch(&mut handle, &config).is_err());
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_acquire_timeout() {
+        let mut config = Config::new_daemon(String::from("sleepy"));
+        config.acquire_timeout = Some(Duration::from_millis(100));
+
+        let mut plugin = TimeoutPlugin::new(Sleepy(Duration::ZERO));
+        let data = crate::collect_run(&mut plugin, &config, 1, 1650000000, None).unwrap();
+        assert_eq!(data.unwrap(), b"multigraph sleepy\nnap.value 1\n");
+
+        // Too slow, the run gets unknown values and the loop goes on
+        let mut plugin = TimeoutPlugin::new(Sleepy(Duration::from_secs(3)));
+        let started = Instant::now();
+        let data = crate::collect_run(&mut plugin, &config, 1, 1650000000, None).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            data.unwrap(),
+            b"multigraph sleepy\nnap.value 1650000000:U\n"
+        );
+        // Still busy, the next run gets skipped, right away
+        let started = Instant::now();
+        let data = crate::collect_run(&mut plugin, &config, 2, 1650000001, None).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(data.is_none());
+    }
+}
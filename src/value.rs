@@ -0,0 +1,824 @@
+//! Typed values for munin plugins
+//! SPDX-License-Identifier: MIT AND Apache-2.0
+//! Copyright (C) 2022 Joerg Jaspert <joerg@ganneff.de>
+//!
+//! Instead of formatting `fieldname.value EPOCH:VALUE` by hand in
+//! every plugin, the types in here know how munin wants to see them.
+
+// We do not want to write unsafe code
+#![forbid(unsafe_code)]
+
+use crate::Config;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// A value of a munin field.
+///
+/// Displays the way munin wants it, with floats that are not a
+/// number (or infinite) and [MuninValue::Unknown] as `U`. Mostly
+/// created using [From], from any integer or float type, or an
+/// [Option] of those, mapping [None] to unknown.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::value::MuninValue;
+/// assert_eq!(MuninValue::from(42).to_string(), "42");
+/// assert_eq!(MuninValue::from(0.5).to_string(), "0.5");
+/// assert_eq!(MuninValue::from(f64::NAN).to_string(), "U");
+/// assert_eq!(MuninValue::from(None::<u64>).to_string(), "U");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MuninValue {
+    /// Signed integer
+    Integer(i64),
+    /// Unsigned integer, for counters that do not fit an i64
+    Unsigned(u64),
+    /// Floating point number
+    Float(f64),
+    /// Floating point number, with a fixed number of decimals, see
+    /// [MuninValue::decimal]
+    Decimal(f64, usize),
+    /// No value known, munin's `U`
+    Unknown,
+}
+
+impl fmt::Display for MuninValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MuninValue::Integer(value) => write!(f, "{value}"),
+            MuninValue::Unsigned(value) => write!(f, "{value}"),
+            MuninValue::Float(value) if value.is_finite() => write!(f, "{value}"),
+            MuninValue::Decimal(value, precision) if value.is_finite() => {
+                write!(f, "{value:.precision$}")
+            }
+            MuninValue::Float(_) | MuninValue::Decimal(..) | MuninValue::Unknown => {
+                write!(f, "U")
+            }
+        }
+    }
+}
+
+impl MuninValue {
+    /// `value` with always `precision` decimals, in plain decimal
+    /// notation, never with an exponent - no matter how large or small
+    /// it is.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use munin_plugin::value::MuninValue;
+    /// assert_eq!(MuninValue::decimal(0.1234567, 3).to_string(), "0.123");
+    /// assert_eq!(MuninValue::decimal(1e-7, 9).to_string(), "0.000000100");
+    /// assert_eq!(MuninValue::decimal(42.0, 0).to_string(), "42");
+    /// ```
+    pub fn decimal(value: f64, precision: usize) -> Self {
+        MuninValue::Decimal(value, precision)
+    }
+}
+
+/// Implement From for a list of types, mapping to one variant
+macro_rules! munin_value_from {
+    ($variant:ident, $target:ty, $($source:ty),+) => {
+        $(
+            impl From<$source> for MuninValue {
+                fn from(value: $source) -> Self {
+                    MuninValue::$variant(<$target>::from(value))
+                }
+            }
+        )+
+    };
+}
+
+munin_value_from!(Integer, i64, i8, i16, i32, i64);
+munin_value_from!(Unsigned, u64, u8, u16, u32, u64);
+munin_value_from!(Float, f64, f32, f64);
+
+impl<V: Into<MuninValue>> From<Option<V>> for MuninValue {
+    fn from(value: Option<V>) -> Self {
+        value.map_or(MuninValue::Unknown, Into::into)
+    }
+}
+
+/// Write one value of `field` to the handle, as `field.value VALUE`
+/// for a _standard_ plugin, or as `field.value EPOCH:VALUE` for a
+/// _streaming_ one, when given an `epoch`.
+///
+/// # Examples
+///
+/// ```rust
+/// # use munin_plugin::value::write_value;
+/// # use std::io::BufWriter;
+/// let mut handle = BufWriter::new(Vec::new());
+/// write_value(&mut handle, "load", 0.42, None).unwrap();
+/// write_value(&mut handle, "load", 0.42, Some(1650000000)).unwrap();
+/// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "load.value 0.42\nload.value 1650000000:0.42\n");
+/// ```
+pub fn write_value<W: Write, V: Into<MuninValue>>(
+    handle: &mut BufWriter<W>,
+    field: &str,
+    value: V,
+    epoch: Option<u64>,
+) -> Result<()> {
+    let value = value.into();
+    match epoch {
+        Some(epoch) => writeln!(handle, "{field}.value {epoch}:{value}")?,
+        None => writeln!(handle, "{field}.value {value}")?,
+    }
+    Ok(())
+}
+
+/// Write the values of many fields at once, as [write_value] does
+/// for one, each `(field, value)` on its own line.
+///
+/// All lines get formatted into one buffer first, which then goes to
+/// the handle with a single write, saving the overhead of a write
+/// per line for plugins with hundreds of fields.
+///
+/// # Examples
+///
+/// ```rust
+/// # use munin_plugin::value::write_values;
+/// # use std::io::BufWriter;
+/// let mut handle = BufWriter::new(Vec::new());
+/// write_values(&mut handle, &[("rx", 42), ("tx", 23)], Some(1650000000)).unwrap();
+/// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "rx.value 1650000000:42\ntx.value 1650000000:23\n");
+/// ```
+pub fn write_values<W: Write, F: AsRef<str>, V: Into<MuninValue> + Copy>(
+    handle: &mut BufWriter<W>,
+    values: &[(F, V)],
+    epoch: Option<u64>,
+) -> Result<()> {
+    let mut lines = String::with_capacity(values.len() * 32);
+    for (field, value) in values {
+        let (field, value) = (field.as_ref(), (*value).into());
+        // Writing to a String does not fail
+        let _ = match epoch {
+            Some(epoch) => writeln!(lines, "{field}.value {epoch}:{value}"),
+            None => writeln!(lines, "{field}.value {value}"),
+        };
+    }
+    handle.write_all(lines.as_bytes())?;
+    Ok(())
+}
+
+/// Like [write_value], followed by a `field.extinfo TEXT` line,
+/// which munin shows along the value, say to explain a spike.
+///
+/// Newlines in `extinfo` turn into spaces, munin reads one line.
+///
+/// # Examples
+///
+/// ```rust
+/// # use munin_plugin::value::write_value_extinfo;
+/// # use std::io::BufWriter;
+/// let mut handle = BufWriter::new(Vec::new());
+/// write_value_extinfo(&mut handle, "queue", 4223, None, "Mail server down").unwrap();
+/// let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "queue.value 4223\nqueue.extinfo Mail server down\n");
+/// ```
+pub fn write_value_extinfo<W: Write, V: Into<MuninValue>>(
+    handle: &mut BufWriter<W>,
+    field: &str,
+    value: V,
+    epoch: Option<u64>,
+    extinfo: &str,
+) -> Result<()> {
+    write_value(handle, field, value, epoch)?;
+    writeln!(
+        handle,
+        "{field}.extinfo {}",
+        extinfo.replace(['\r', '\n'], " ")
+    )?;
+    Ok(())
+}
+
+/// One data point of a _streaming_ plugin.
+///
+/// Displays exactly as munin expects it in the cachefile,
+/// `fieldname.value EPOCH:VALUE`.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::value::StreamSample;
+/// let sample = StreamSample::new("eth0_rx", 1650000000, 4223);
+/// assert_eq!(sample.to_string(), "eth0_rx.value 1650000000:4223");
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct StreamSample<V> {
+    /// Name of the field, as declared in the config
+    pub field: String,
+    /// Unix epoch in seconds the value belongs to
+    pub epoch: u64,
+    /// The value itself
+    pub value: V,
+}
+
+impl<V> StreamSample<V> {
+    /// Create a new sample for `field` at `epoch`.
+    pub fn new<F: Into<String>>(field: F, epoch: u64, value: V) -> Self {
+        Self {
+            field: field.into(),
+            epoch,
+            value,
+        }
+    }
+}
+
+impl<V: fmt::Display> fmt::Display for StreamSample<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.value {}:{}", self.field, self.epoch, self.value)
+    }
+}
+
+/// Parse a `fieldname.value EPOCH:VALUE` line, as found in the
+/// cachefile, keeping the value as string.
+impl FromStr for StreamSample<String> {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let invalid = || anyhow!("Not a streaming value line: {line}");
+        let (key, data) = line.trim().split_once(' ').ok_or_else(invalid)?;
+        let field = key.strip_suffix(".value").ok_or_else(invalid)?;
+        let (epoch, value) = data.trim().split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            field: field.to_string(),
+            epoch: epoch.parse().map_err(|_| invalid())?,
+            value: value.to_string(),
+        })
+    }
+}
+
+/// The smallest and largest value seen of one or more fields, for
+/// the `graph_args` of an auto-ranging graph, see
+/// [Config::observed_range](crate::Config::observed_range) and
+/// [GraphArgs::observed](crate::graph::GraphArgs::observed).
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::value::{MuninValue, ObservedRange};
+/// let mut range = ObservedRange::new();
+/// range.observe(42);
+/// range.observe(-0.5);
+/// range.observe(MuninValue::Unknown);
+/// assert_eq!(range.min(), Some(-0.5));
+/// assert_eq!(range.max(), Some(42.0));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ObservedRange {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl ObservedRange {
+    /// A range nothing got observed in yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take `value` into account. Unknown values, and floats that are
+    /// not a number or infinite, are ignored.
+    pub fn observe<V: Into<MuninValue>>(&mut self, value: V) {
+        let value = match value.into() {
+            MuninValue::Integer(value) => value as f64,
+            MuninValue::Unsigned(value) => value as f64,
+            MuninValue::Float(value) | MuninValue::Decimal(value, _) if value.is_finite() => value,
+            MuninValue::Float(_) | MuninValue::Decimal(..) | MuninValue::Unknown => return,
+        };
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+
+    /// The smallest value seen, None if none was
+    pub fn min(&self) -> Option<f64> {
+        self.min
+    }
+
+    /// The largest value seen, None if none was
+    pub fn max(&self) -> Option<f64> {
+        self.max
+    }
+}
+
+/// Write all `samples` to the handle, one line each, as
+/// [MuninPlugin::acquire](super::MuninPlugin::acquire) of a
+/// _streaming_ plugin is expected to.
+///
+/// # Examples
+///
+/// ```rust
+/// # use munin_plugin::value::{write_samples, StreamSample};
+/// # use std::io::BufWriter;
+/// let mut handle = BufWriter::new(Vec::new());
+/// let samples = [
+///     StreamSample::new("eth0_rx", 1650000000, 4223),
+///     StreamSample::new("eth0_tx", 1650000000, 815),
+/// ];
+/// write_samples(&mut handle, &samples).unwrap();
+/// ```
+pub fn write_samples<W: Write, V: fmt::Display>(
+    handle: &mut BufWriter<W>,
+    samples: &[StreamSample<V>],
+) -> Result<()> {
+    for sample in samples {
+        writeln!(handle, "{sample}")?;
+    }
+    Ok(())
+}
+
+/// How [SubSecondAggregator] collapses all samples of one field that
+/// fall into the same second.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Aggregation {
+    /// Use the last sample seen
+    Last,
+    /// Use the arithmetic mean of all samples
+    Mean,
+    /// Use the smallest sample
+    Min,
+    /// Use the largest sample
+    Max,
+}
+
+/// Samples of one field within one second
+#[derive(Clone, Debug, PartialEq)]
+struct Accumulator {
+    count: u64,
+    sum: f64,
+    last: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Accumulator {
+    fn new(value: f64) -> Self {
+        Self {
+            count: 1,
+            sum: value,
+            last: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.last = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn result(&self, aggregation: Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Last => self.last,
+            Aggregation::Mean => self.sum / self.count as f64,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+        }
+    }
+}
+
+/// Collapse samples taken faster than once a second into one value
+/// per field and second.
+///
+/// Munin epochs are whole seconds, writing multiple values for a
+/// field with the same epoch does not add resolution, later ones
+/// simply overwrite the earlier ones. A plugin sampling more often
+/// than once a second (for example from a background thread) can
+/// [SubSecondAggregator::add] every sample, and have the aggregator
+/// reduce them to the one value per second munin can store, using
+/// the chosen [Aggregation].
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::value::{Aggregation, StreamSample, SubSecondAggregator};
+/// let mut agg = SubSecondAggregator::new(Aggregation::Mean);
+/// agg.add("latency", 1650000000, 2.0);
+/// agg.add("latency", 1650000000, 4.0);
+/// agg.add("latency", 1650000001, 5.0);
+/// // Only hand out seconds that are complete
+/// let samples = agg.drain_before(1650000001);
+/// assert_eq!(samples, vec![StreamSample::new("latency", 1650000000, 3.0)]);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubSecondAggregator {
+    aggregation: Aggregation,
+    /// Per epoch the fields, in the order first seen
+    samples: BTreeMap<u64, Vec<(String, Accumulator)>>,
+}
+
+impl SubSecondAggregator {
+    /// Create an empty aggregator using `aggregation`.
+    pub fn new(aggregation: Aggregation) -> Self {
+        Self {
+            aggregation,
+            samples: BTreeMap::new(),
+        }
+    }
+
+    /// Add a sample of `field`, taken within second `epoch`.
+    pub fn add(&mut self, field: &str, epoch: u64, value: f64) {
+        let fields = self.samples.entry(epoch).or_default();
+        match fields.iter_mut().find(|(name, _)| name == field) {
+            Some((_, acc)) => acc.add(value),
+            None => fields.push((field.to_string(), Accumulator::new(value))),
+        }
+    }
+
+    /// Remove and return the aggregated values of all seconds before
+    /// `epoch`, ordered by epoch, fields in the order they got added.
+    ///
+    /// Samples for `epoch` and later stay, as more may arrive for
+    /// them. Pass the current epoch to only get complete seconds.
+    pub fn drain_before(&mut self, epoch: u64) -> Vec<StreamSample<f64>> {
+        let keep = self.samples.split_off(&epoch);
+        let done = std::mem::replace(&mut self.samples, keep);
+        self.collect(done)
+    }
+
+    /// Remove and return the aggregated values of all seconds.
+    pub fn drain(&mut self) -> Vec<StreamSample<f64>> {
+        let done = std::mem::take(&mut self.samples);
+        self.collect(done)
+    }
+
+    /// Turn the accumulated samples into their final values
+    fn collect(&self, done: BTreeMap<u64, Vec<(String, Accumulator)>>) -> Vec<StreamSample<f64>> {
+        done.into_iter()
+            .flat_map(|(epoch, fields)| {
+                fields.into_iter().map(move |(field, acc)| {
+                    StreamSample::new(field, epoch, acc.result(self.aggregation))
+                })
+            })
+            .collect()
+    }
+}
+
+/// How wide a [Counter] is, that is where it wraps around to zero.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum CounterWidth {
+    /// Wraps after [u32::MAX], like many SNMP and older kernel counters
+    Bits32,
+    /// Wraps after [u64::MAX]
+    #[default]
+    Bits64,
+}
+
+impl CounterWidth {
+    /// How much a counter of this width grew from `previous` to
+    /// `value`, None if it can not have (like a reset of a 32 bit
+    /// counter to a larger one). A 64 bit counter does not wrap in
+    /// practice, so one going down got reset, which is None too.
+    fn delta(&self, previous: u64, value: u64) -> Option<u64> {
+        match self {
+            CounterWidth::Bits64 => value.checked_sub(previous),
+            CounterWidth::Bits32 => {
+                let previous = u32::try_from(previous).ok()?;
+                let value = u32::try_from(value).ok()?;
+                Some(u64::from(value.wrapping_sub(previous)))
+            }
+        }
+    }
+}
+
+/// Turn readings of a counter field (bytes, packets, ...) into a
+/// rate per second, for _standard_ plugins.
+///
+/// Every [Counter::rate] stores the reading and its epoch in a file
+/// of its own beside [Config::statefile], named like it plus
+/// `.counters`, keyed by the field name, so multiple counters of a
+/// plugin can share it. The next run computes the rate from there.
+/// The state of the plugin itself (see [Config::save_state]) stays
+/// untouched.
+///
+/// A 32 bit counter smaller than before is taken to have wrapped
+/// around, a 64 bit one to have been reset (say, by a reboot), its
+/// rate is [MuninValue::Unknown]. So is the rate without a previous
+/// reading (the very first run), or with no time passed since.
+///
+/// # Examples
+///
+/// ```
+/// # use munin_plugin::{value::{Counter, CounterWidth, MuninValue}, Config};
+/// # let dir = tempfile::tempdir().unwrap();
+/// let mut config = Config::new(String::from("traffic"));
+/// # config.statefile = dir.path().join("traffic.state");
+/// let counter = Counter::new("eth0_rx", CounterWidth::Bits64);
+/// assert_eq!(counter.rate(&config, 1000, 1650000000).unwrap(), MuninValue::Unknown);
+/// assert_eq!(counter.rate(&config, 4000, 1650000300).unwrap(), MuninValue::Float(10.0));
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Counter {
+    field: String,
+    width: CounterWidth,
+}
+
+impl Counter {
+    /// Create a counter for `field`, wrapping at `width`.
+    pub fn new<F: Into<String>>(field: F, width: CounterWidth) -> Self {
+        Self {
+            field: field.into(),
+            width,
+        }
+    }
+
+    /// Rate per second since the last call, with the counter now at
+    /// `value` at `epoch`. Stores `value` and `epoch` for the next.
+    pub fn rate(&self, config: &Config, value: u64, epoch: u64) -> Result<MuninValue> {
+        let path = Counter::statefile(config);
+        let mut readings: BTreeMap<String, (u64, u64)> =
+            crate::config::load_json(&path).unwrap_or_default();
+        let previous = readings.insert(self.field.clone(), (value, epoch));
+        crate::config::save_json(&path, &readings)?;
+        Ok(self.rate_since(previous, value, epoch))
+    }
+
+    /// The file the counters of a plugin keep their readings in
+    fn statefile(config: &Config) -> PathBuf {
+        let mut path = config.statefile.clone().into_os_string();
+        path.push(".counters");
+        PathBuf::from(path)
+    }
+
+    /// Rate per second from the `previous` (value, epoch) reading
+    fn rate_since(&self, previous: Option<(u64, u64)>, value: u64, epoch: u64) -> MuninValue {
+        let Some((previous, since)) = previous else {
+            return MuninValue::Unknown;
+        };
+        if epoch <= since {
+            return MuninValue::Unknown;
+        }
+        self.width
+            .delta(previous, value)
+            .map(|delta| delta as f64 / (epoch - since) as f64)
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_munin_value() {
+        assert_eq!(MuninValue::from(-3i8).to_string(), "-3");
+        assert_eq!(
+            MuninValue::from(u64::MAX).to_string(),
+            "18446744073709551615"
+        );
+        assert_eq!(MuninValue::from(1.5f32).to_string(), "1.5");
+        assert_eq!(MuninValue::from(f64::NAN).to_string(), "U");
+        assert_eq!(MuninValue::from(f64::INFINITY).to_string(), "U");
+        assert_eq!(MuninValue::from(Some(7u32)), MuninValue::Unsigned(7));
+        assert_eq!(MuninValue::from(None::<f64>), MuninValue::Unknown);
+        assert_eq!(MuninValue::Unknown.to_string(), "U");
+    }
+
+    #[test]
+    fn test_decimal() {
+        let tiny = MuninValue::decimal(1e-7, 10).to_string();
+        assert_eq!(tiny, "0.0000001000");
+        let huge = MuninValue::decimal(1.5e22, 2).to_string();
+        assert_eq!(huge, "15000000000000000000000.00");
+        assert_eq!(MuninValue::decimal(-2.345, 2).to_string(), "-2.35");
+        assert_eq!(MuninValue::decimal(f64::NAN, 2).to_string(), "U");
+        // Plain floats do not get an exponent either
+        assert_eq!(MuninValue::from(1e-7).to_string(), "0.0000001");
+        for value in [tiny, huge, MuninValue::from(1e300).to_string()] {
+            assert!(!value.contains(['e', 'E']), "{value}");
+        }
+
+        let mut handle = BufWriter::new(Vec::new());
+        write_value(
+            &mut handle,
+            "load",
+            MuninValue::decimal(0.5, 2),
+            Some(1650000000),
+        )
+        .unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "load.value 1650000000:0.50\n");
+    }
+
+    #[test]
+    fn test_write_value() {
+        let mut handle = BufWriter::new(Vec::new());
+        write_value(&mut handle, "load", 42, None).unwrap();
+        write_value(&mut handle, "load", 42, Some(1650000000)).unwrap();
+        write_value(&mut handle, "temp", f64::NAN, None).unwrap();
+        write_value(&mut handle, "temp", f64::NAN, Some(1650000000)).unwrap();
+        write_value(&mut handle, "temp", MuninValue::Unknown, None).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "load.value 42\nload.value 1650000000:42\ntemp.value U\ntemp.value 1650000000:U\ntemp.value U\n"
+        );
+    }
+
+    #[test]
+    fn test_write_values() {
+        let fields: Vec<(String, MuninValue)> = (0..300)
+            .map(|n| (format!("field{n}"), MuninValue::from(n * 7)))
+            .chain([(String::from("gone"), MuninValue::Unknown)])
+            .collect();
+        for epoch in [None, Some(1650000000)] {
+            let mut single = BufWriter::new(Vec::new());
+            for (field, value) in &fields {
+                write_value(&mut single, field, *value, epoch).unwrap();
+            }
+            let mut batch = BufWriter::new(Vec::new());
+            write_values(&mut batch, &fields, epoch).unwrap();
+            assert_eq!(batch.into_inner().unwrap(), single.into_inner().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_write_value_extinfo() {
+        let mut handle = BufWriter::new(Vec::new());
+        write_value_extinfo(&mut handle, "load", 42, None, "Backup\nrunning").unwrap();
+        write_value(&mut handle, "free", 7, None).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "load.value 42\nload.extinfo Backup running\nfree.value 7\n"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            StreamSample::new("load", 1650000000, 0.42).to_string(),
+            "load.value 1650000000:0.42"
+        );
+        assert_eq!(
+            StreamSample::new("load", 1650000000, "U").to_string(),
+            "load.value 1650000000:U"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let sample: StreamSample<String> = "load.value 1650000000:42".parse().unwrap();
+        assert_eq!(
+            sample,
+            StreamSample::new("load", 1650000000, String::from("42"))
+        );
+        assert!("load.value 42".parse::<StreamSample<String>>().is_err());
+        assert!("load.label load".parse::<StreamSample<String>>().is_err());
+        assert!("load.value abc:42".parse::<StreamSample<String>>().is_err());
+    }
+
+    #[test]
+    fn test_observed_range() {
+        let mut range = ObservedRange::new();
+        assert_eq!(range.min(), None);
+        range.observe(f64::NAN);
+        range.observe(None::<u64>);
+        assert_eq!(range, ObservedRange::new());
+        range.observe(MuninValue::decimal(3.25, 1));
+        assert_eq!((range.min(), range.max()), (Some(3.25), Some(3.25)));
+        range.observe(u64::MAX);
+        range.observe(-7);
+        range.observe(f64::INFINITY);
+        assert_eq!(range.min(), Some(-7.0));
+        assert_eq!(range.max(), Some(u64::MAX as f64));
+    }
+
+    #[test]
+    fn test_subsecond_aggregator() {
+        let samples = [
+            ("a", 10, 1.0),
+            ("b", 10, 10.0),
+            ("a", 10, 3.0),
+            ("a", 10, 2.0),
+            ("b", 11, 20.0),
+            ("a", 11, 7.0),
+            ("a", 12, 9.0),
+        ];
+        let expected = [
+            (Aggregation::Last, [2.0, 10.0]),
+            (Aggregation::Mean, [2.0, 10.0]),
+            (Aggregation::Min, [1.0, 10.0]),
+            (Aggregation::Max, [3.0, 10.0]),
+        ];
+        for (aggregation, values) in expected {
+            let mut agg = SubSecondAggregator::new(aggregation);
+            for (field, epoch, value) in samples {
+                agg.add(field, epoch, value);
+            }
+            assert_eq!(
+                agg.drain_before(11),
+                vec![
+                    StreamSample::new("a", 10, values[0]),
+                    StreamSample::new("b", 10, values[1]),
+                ]
+            );
+            // Field order as added within the second
+            assert_eq!(
+                agg.drain(),
+                vec![
+                    StreamSample::new("b", 11, 20.0),
+                    StreamSample::new("a", 11, 7.0),
+                    StreamSample::new("a", 12, 9.0),
+                ]
+            );
+            assert!(agg.drain().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_counter() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::new(String::from("counter"));
+        config.statefile = dir.path().join("counter.state");
+        let rx = Counter::new("rx", CounterWidth::Bits64);
+        let tx = Counter::new("tx", CounterWidth::Bits32);
+
+        // First run, nothing to compare with
+        assert_eq!(
+            rx.rate(&config, 1000, 1650000000).unwrap(),
+            MuninValue::Unknown
+        );
+        let near_wrap = u64::from(u32::MAX) - 99;
+        assert_eq!(
+            tx.rate(&config, near_wrap, 1650000000).unwrap(),
+            MuninValue::Unknown
+        );
+
+        // Normal increment, both share the statefile
+        assert_eq!(
+            rx.rate(&config, 4000, 1650000300).unwrap(),
+            MuninValue::Float(10.0)
+        );
+        // Wrapped at 32 bit: 100 to reach zero, then 200 more
+        assert_eq!(
+            tx.rate(&config, 200, 1650000300).unwrap(),
+            MuninValue::Float(1.0)
+        );
+
+        // Reset at 64 bit, no wrap
+        assert_eq!(
+            rx.rate_since(Some((u64::MAX - 9, 1650000000)), 20, 1650000010),
+            MuninValue::Unknown
+        );
+        assert_eq!(
+            rx.rate(&config, 1000, 1650000600).unwrap(),
+            MuninValue::Unknown
+        );
+        assert_eq!(
+            rx.rate(&config, 1600, 1650000900).unwrap(),
+            MuninValue::Float(2.0)
+        );
+
+        // The counters have their own file, the plugin state is safe
+        assert!(dir.path().join("counter.state.counters").exists());
+        config.save_state(&4711u64).unwrap();
+        assert_eq!(
+            rx.rate(&config, 2200, 1650001200).unwrap(),
+            MuninValue::Float(2.0)
+        );
+        assert_eq!(config.load_state::<u64>(), Some(4711));
+        // No time passed, or a 32 bit counter that can not have wrapped
+        assert_eq!(
+            rx.rate_since(Some((1, 1650000000)), 2, 1650000000),
+            MuninValue::Unknown
+        );
+        assert_eq!(
+            tx.rate_since(Some((u64::MAX, 1650000000)), 2, 1650000010),
+            MuninValue::Unknown
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let samples = [
+            StreamSample::new("eth0_rx", 1650000000, 4223),
+            StreamSample::new("eth0_tx", 1650000000, 815),
+            StreamSample::new("eth0_rx", 1650000001, 4242),
+        ];
+        let mut handle = BufWriter::new(Vec::new());
+        write_samples(&mut handle, &samples).unwrap();
+        let output = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+
+        let parsed: Vec<StreamSample<u64>> = output
+            .lines()
+            .map(|line| {
+                let sample: StreamSample<String> = line.parse().unwrap();
+                StreamSample::new(sample.field, sample.epoch, sample.value.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(parsed, samples);
+    }
+}